@@ -0,0 +1,460 @@
+use grammers_client::{Client, InvocationError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tauri::{Emitter, State};
+use tokio::sync::Mutex as AsyncMutex;
+use uuid::Uuid;
+
+use crate::backups::AutoBackupConfig;
+use crate::connection::{self, UploadQueue};
+use crate::crypto;
+use crate::db::{self, Database, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::{self, rpc};
+use crate::missing::LocalCopies;
+use crate::oplog::DeviceSyncConfig;
+use crate::shared::{DriveRole, SharedDrives};
+use crate::storage::StorageConfig;
+use crate::AppState;
+
+pub const SESSION_FILENAME: &str = "telegram.session";
+pub const METADATA_FILENAME: &str = "metadata.json";
+const REGISTRY_FILENAME: &str = "accounts.json";
+const ACCOUNTS_DIR: &str = "accounts";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountProfile {
+    pub id: String,
+    pub label: String,
+    pub created_at: i64,
+    #[serde(default)]
+    pub user_id: Option<i64>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AccountRegistry {
+    accounts: Vec<AccountProfile>,
+    active_id: Option<String>,
+}
+
+/// Runtime state of one profile: its own Telegram client and metadata store.
+pub struct Account {
+    pub id: String,
+    pub dir: PathBuf,
+    pub client: Arc<AsyncMutex<Option<Client>>>,
+    pub personal_db: Arc<Database>,
+    pub storage: Mutex<StorageConfig>,
+    pub shared: SharedDrives,
+    pub device_sync: Mutex<DeviceSyncConfig>,
+    pub device_sync_lock: AsyncMutex<()>,
+    pub local_copies: LocalCopies,
+    pub auto_backup: Mutex<AutoBackupConfig>,
+    pub upload_queue: UploadQueue,
+}
+
+impl Account {
+    fn open(id: &str, dir: PathBuf) -> Self {
+        // Each store reports its own save errors, so a missing dir isn't fatal here
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            eprintln!("Failed to create account dir {:?}: {}", dir, e);
+        }
+        let db = Arc::new(Database::new(&dir.to_string_lossy()));
+        let storage = Mutex::new(StorageConfig::load(&dir));
+        let device_sync = DeviceSyncConfig::load(&dir);
+        db.set_device_id(&device_sync.device_id);
+        db.set_journaling(device_sync.enabled);
+        let shared = SharedDrives::load(&dir, &device_sync.device_id);
+        let local_copies = LocalCopies::load(&dir);
+        let auto_backup = Mutex::new(AutoBackupConfig::load(&dir));
+        let upload_queue = UploadQueue::load(&dir);
+        Account {
+            id: id.to_string(),
+            dir,
+            client: Arc::new(AsyncMutex::new(None)), // Lazy init
+            personal_db: db,
+            storage,
+            shared,
+            device_sync: Mutex::new(device_sync),
+            device_sync_lock: AsyncMutex::new(()),
+            local_copies,
+            auto_backup,
+            upload_queue,
+        }
+    }
+
+    /// Metadata store of whatever drive is open: a shared drive or the personal one.
+    pub fn db(&self) -> Arc<Database> {
+        match self.shared.active() {
+            Some(drive) => drive.db.clone(),
+            None => self.personal_db.clone(),
+        }
+    }
+
+    /// Where new uploads and backups go for the open drive.
+    pub fn storage_target(&self) -> StoragePeer {
+        match self.shared.active() {
+            Some(drive) => drive.info().peer,
+            None => self.storage.lock().unwrap().target.clone(),
+        }
+    }
+
+    /// Every chat personal uploads can live in: Saved Messages plus whatever the
+    /// storage config points at, replicas included.
+    pub fn personal_peers(&self) -> Vec<StoragePeer> {
+        let config = self.storage.lock().unwrap().clone();
+        let mut peers = vec![StoragePeer::SavedMessages];
+        let configured = [Some(config.target), config.channel, config.replica_target];
+        for peer in configured.into_iter().flatten() {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+
+    /// Key for file captions written to `target`. Shared drive members don't have
+    /// our passphrase, so captions there stay readable.
+    pub fn caption_vault(&self, target: &StoragePeer) -> Option<Arc<crypto::Vault>> {
+        let shared = self.shared.list().iter().any(|d| d.peer == *target);
+        crypto::vault().filter(|_| !shared)
+    }
+
+    /// Read-only members of a shared drive may browse and download, nothing else.
+    pub fn ensure_writable(&self) -> Result<(), AppError> {
+        match self.shared.active() {
+            Some(drive) if drive.info().role == DriveRole::ReadOnly => {
+                Err("This shared drive is read-only for you".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The personal store and every shared drive's.
+    pub fn databases(&self) -> Vec<Arc<Database>> {
+        let mut dbs = vec![self.personal_db.clone()];
+        for info in self.shared.list() {
+            if let Some(drive) = self.shared.get(&info.id) {
+                dbs.push(drive.db.clone());
+            }
+        }
+        dbs
+    }
+
+    pub fn session_path(&self) -> PathBuf {
+        self.dir.join(SESSION_FILENAME)
+    }
+}
+
+pub struct AccountManager {
+    root: PathBuf,
+    registry: Mutex<AccountRegistry>,
+    loaded: Mutex<HashMap<String, Arc<Account>>>,
+}
+
+// A profile not yet in the registry. Its directory is made when it's first opened.
+fn new_profile(label: &str) -> AccountProfile {
+    AccountProfile {
+        id: Uuid::new_v4().to_string(),
+        label: label.to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+        user_id: None,
+        display_name: None,
+    }
+}
+
+impl AccountManager {
+    /// Loads the profile registry. A registry that exists but can't be read is an
+    /// error: starting over would adopt a new "Default" and hide every profile.
+    pub fn new(app_dir: &Path) -> Result<Self, AppError> {
+        let registry_path = app_dir.join(REGISTRY_FILENAME);
+        let registry = if registry_path.exists() {
+            let file = File::open(&registry_path).map_err(AppError::storage)?;
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+                AppError::Storage(format!("{} is damaged: {}", registry_path.display(), e))
+            })?
+        } else {
+            AccountRegistry::default()
+        };
+
+        let manager = AccountManager {
+            root: app_dir.to_path_buf(),
+            registry: Mutex::new(registry),
+            loaded: Mutex::new(HashMap::new()),
+        };
+
+        let needs_default = manager.registry.lock().unwrap().accounts.is_empty();
+        if needs_default {
+            // First run with profiles: adopt the single-account files from older versions
+            let profile = manager.add("Default")?;
+            let dir = manager.account_dir(&profile.id);
+            std::fs::create_dir_all(&dir).map_err(AppError::storage)?;
+            for name in [SESSION_FILENAME, METADATA_FILENAME] {
+                let legacy = app_dir.join(name);
+                if legacy.exists() {
+                    if let Err(e) = std::fs::rename(&legacy, dir.join(name)) {
+                        eprintln!("Failed to migrate {}: {}", name, e);
+                    }
+                }
+            }
+            manager.switch(&profile.id)?;
+        }
+
+        Ok(manager)
+    }
+
+    // Written next to it and renamed, so a crash can't leave a truncated registry
+    fn save(&self) -> Result<(), AppError> {
+        let path = self.root.join(REGISTRY_FILENAME);
+        let tmp = path.with_extension("json.tmp");
+        let file = File::create(&tmp).map_err(AppError::storage)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &*self.registry.lock().unwrap())
+            .map_err(AppError::storage)?;
+        writer
+            .into_inner()
+            .map_err(|e| AppError::storage(e.error()))?
+            .sync_all()
+            .map_err(AppError::storage)?;
+        std::fs::rename(&tmp, &path).map_err(AppError::storage)
+    }
+
+    fn account_dir(&self, id: &str) -> PathBuf {
+        self.root.join(ACCOUNTS_DIR).join(id)
+    }
+
+    pub fn list(&self) -> (Vec<AccountProfile>, Option<String>) {
+        let registry = self.registry.lock().unwrap();
+        (registry.accounts.clone(), registry.active_id.clone())
+    }
+
+    pub fn active_id(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .active_id
+            .clone()
+            .or_else(|| registry.accounts.first().map(|a| a.id.clone()))
+            .expect("no account profiles")
+    }
+
+    /// Resolves the active profile once, so a command keeps working against the
+    /// same account even if the user switches halfway through it.
+    pub fn active(&self) -> Arc<Account> {
+        let id = self.active_id();
+        self.get(&id).expect("active account missing from registry")
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Account>> {
+        if !self
+            .registry
+            .lock()
+            .unwrap()
+            .accounts
+            .iter()
+            .any(|a| a.id == id)
+        {
+            return None;
+        }
+        let mut loaded = self.loaded.lock().unwrap();
+        let account = loaded
+            .entry(id.to_string())
+            .or_insert_with(|| Arc::new(Account::open(id, self.account_dir(id))))
+            .clone();
+        Some(account)
+    }
+
+    /// Accounts opened so far in this session.
+    pub fn loaded(&self) -> Vec<Arc<Account>> {
+        self.loaded.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Every profile, opening the ones not loaded yet.
+    pub fn all(&self) -> Vec<Arc<Account>> {
+        let (profiles, _) = self.list();
+        profiles.iter().filter_map(|p| self.get(&p.id)).collect()
+    }
+
+    pub fn add(&self, label: &str) -> Result<AccountProfile, AppError> {
+        let profile = new_profile(label);
+        self.registry.lock().unwrap().accounts.push(profile.clone());
+        if let Err(e) = self.save() {
+            // Not on disk, so not in the list either
+            let mut registry = self.registry.lock().unwrap();
+            registry.accounts.retain(|a| a.id != profile.id);
+            return Err(e);
+        }
+        Ok(profile)
+    }
+
+    pub fn switch(&self, id: &str) -> Result<(), AppError> {
+        let mut registry = self.registry.lock().unwrap();
+        if !registry.accounts.iter().any(|a| a.id == id) {
+            return Err("Account not found".into());
+        }
+        registry.active_id = Some(id.to_string());
+        drop(registry);
+        self.save()
+    }
+
+    /// Removes the profile and its directory. Returns the runtime account if it was
+    /// loaded so the caller can shut its client down.
+    pub fn remove(&self, id: &str) -> Result<Option<Arc<Account>>, AppError> {
+        let mut registry = self.registry.lock().unwrap();
+        let len_before = registry.accounts.len();
+        registry.accounts.retain(|a| a.id != id);
+        if registry.accounts.len() == len_before {
+            return Err("Account not found".into());
+        }
+        // Always keep one profile around so the login screen has somewhere to go.
+        // Added under the same lock, so active() never sees an empty list.
+        if registry.accounts.is_empty() {
+            registry.accounts.push(new_profile("Default"));
+        }
+        let was_active = registry.active_id.as_deref() == Some(id);
+        if was_active {
+            registry.active_id = registry.accounts.first().map(|a| a.id.clone());
+        }
+        drop(registry);
+        self.save()?;
+
+        let account = self.loaded.lock().unwrap().remove(id);
+        let dir = self.account_dir(id);
+        if dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                eprintln!("Failed to remove account dir {:?}: {}", dir, e);
+            }
+        }
+
+        Ok(account)
+    }
+
+    pub fn set_identity(&self, id: &str, user_id: i64, display_name: &str) -> Result<(), AppError> {
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(profile) = registry.accounts.iter_mut().find(|a| a.id == id) {
+            profile.user_id = Some(user_id);
+            profile.display_name = Some(display_name.to_string());
+        }
+        drop(registry);
+        self.save()
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountList {
+    pub accounts: Vec<AccountProfile>,
+    pub active_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AccountSearchResult {
+    pub account_id: String,
+    pub label: String,
+    pub folders: Vec<db::Folder>,
+    pub files: Vec<db::FileMetadata>,
+}
+
+#[tauri::command]
+pub(crate) async fn list_accounts(state: State<'_, AppState>) -> Result<AccountList, AppError> {
+    let (accounts, active_id) = state.accounts.list();
+    Ok(AccountList {
+        accounts,
+        active_id,
+    })
+}
+
+/// Logs the session out on Telegram's side, connecting first if this run never
+/// did. Fails if Telegram doesn't confirm, so the session file is only dropped
+/// once the server no longer honours it.
+pub(crate) async fn end_session(state: &AppState, account: &Account) -> Result<(), AppError> {
+    let mut client_guard = account.client.lock().await;
+    let client = match client_guard.as_ref() {
+        Some(client) => client.clone(),
+        None if account.session_path().exists() => {
+            connection::connect(&state.app_lock, &state.proxy.get(), account).await?
+        }
+        None => return Ok(()),
+    };
+    match rpc!("auth.logOut", client.sign_out()) {
+        Ok(_) => {}
+        // Never logged in, or already ended elsewhere
+        Err(InvocationError::Rpc(e)) if e.code == 401 => {}
+        Err(e) => return Err(e.into()),
+    }
+    gateway::global().forget_me(&client);
+    *client_guard = None;
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) async fn add_account(
+    label: String,
+    state: State<'_, AppState>,
+) -> Result<AccountProfile, AppError> {
+    println!("Adding account profile: {}", label);
+    let profile = state.accounts.add(&label)?;
+    // New profiles start logged out, so the frontend goes straight to login
+    state.accounts.switch(&profile.id)?;
+    *state.phone_token.lock().unwrap() = None;
+    *state.password_token.lock().unwrap() = None;
+    let _ = state.app_handle.emit("account-switched", &profile.id);
+    Ok(profile)
+}
+
+#[tauri::command]
+pub(crate) async fn switch_account(id: String, state: State<'_, AppState>) -> Result<(), AppError> {
+    println!("Switching to account: {}", id);
+    state.accounts.switch(&id)?;
+    *state.phone_token.lock().unwrap() = None;
+    *state.password_token.lock().unwrap() = None;
+    let _ = state.app_handle.emit("account-switched", &id);
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) async fn remove_account(id: String, state: State<'_, AppState>) -> Result<(), AppError> {
+    println!("Removing account: {}", id);
+    let was_active = state.accounts.active_id() == id;
+    // Log out first, same as logout, so the session doesn't outlive its files
+    let account = state.accounts.get(&id).ok_or("Account not found")?;
+    end_session(&state, &account).await?;
+    state.accounts.remove(&id)?;
+    if was_active {
+        *state.phone_token.lock().unwrap() = None;
+        *state.password_token.lock().unwrap() = None;
+        let _ = state
+            .app_handle
+            .emit("account-switched", state.accounts.active_id());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) async fn search_all_accounts(
+    query: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<AccountSearchResult>, AppError> {
+    let (profiles, _) = state.accounts.list();
+    let mut results = Vec::new();
+
+    for profile in profiles {
+        if let Some(account) = state.accounts.get(&profile.id) {
+            let (folders, files) = account.personal_db.search_items(&query);
+            if folders.is_empty() && files.is_empty() {
+                continue;
+            }
+            results.push(AccountSearchResult {
+                account_id: profile.id,
+                label: profile.label,
+                folders,
+                files,
+            });
+        }
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,240 @@
+use grammers_session::Session;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+use crate::crypto::{self, Encryption, Vault};
+use crate::error::AppError;
+use crate::AppState;
+
+const PASSCODE_FILENAME: &str = "passcode.json";
+const SETTINGS_FILENAME: &str = "app_lock.json";
+const MIN_PASSCODE_LEN: usize = 4;
+const CHECK_SECS: u64 = 30;
+
+// The lock screen has to be able to talk to us
+const ALLOWED_WHILE_LOCKED: &[&str] = &["get_app_lock_status", "unlock_app"];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AppLockSettings {
+    // 0 turns auto-lock off
+    pub auto_lock_minutes: u64,
+}
+
+impl Default for AppLockSettings {
+    fn default() -> Self {
+        AppLockSettings {
+            auto_lock_minutes: 10,
+        }
+    }
+}
+
+/// Passcode lock for the whole app. While locked every command except the lock
+/// screen's is rejected, and the key for the session files is dropped.
+pub struct AppLock {
+    dir: PathBuf,
+    passcode: Encryption,
+    vault: Mutex<Option<Arc<Vault>>>,
+    locked: AtomicBool,
+    last_activity: AtomicI64,
+    settings: Mutex<AppLockSettings>,
+}
+
+impl AppLock {
+    pub fn new(app_dir: &Path) -> Self {
+        let passcode = Encryption::with_file(app_dir, PASSCODE_FILENAME, MIN_PASSCODE_LEN);
+        let settings = File::open(app_dir.join(SETTINGS_FILENAME))
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default();
+        AppLock {
+            dir: app_dir.to_path_buf(),
+            // A passcode means every start begins at the lock screen
+            locked: AtomicBool::new(passcode.is_enabled()),
+            passcode,
+            vault: Mutex::new(None),
+            last_activity: AtomicI64::new(chrono::Utc::now().timestamp()),
+            settings: Mutex::new(settings),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    /// Gate for every command. Allowed commands also count as activity.
+    pub fn check(&self, command: &str) -> bool {
+        if self.is_locked() && !ALLOWED_WHILE_LOCKED.contains(&command) {
+            return false;
+        }
+        self.last_activity
+            .store(chrono::Utc::now().timestamp(), Ordering::SeqCst);
+        true
+    }
+
+    fn lock(&self) {
+        if self.passcode.is_enabled() {
+            self.locked.store(true, Ordering::SeqCst);
+            *self.vault.lock().unwrap() = None;
+        }
+    }
+
+    fn save_settings(&self, settings: &AppLockSettings) -> Result<(), AppError> {
+        let file = File::create(self.dir.join(SETTINGS_FILENAME)).map_err(AppError::storage)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, settings).map_err(AppError::storage)
+    }
+
+    /// Reads a session file, decrypting it if a passcode is set. A missing file
+    /// gives a fresh session; nothing is written until login completes.
+    pub fn load_session(&self, path: &Path) -> Result<Session, AppError> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Ok(Session::new()),
+        };
+        let bytes = if crypto::is_encrypted(&bytes) {
+            let vault = self
+                .vault
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| AppError::Locked("App is locked".to_string()))?;
+            vault.decrypt(&bytes)?
+        } else {
+            bytes
+        };
+        Session::load(&bytes)
+            .map_err(|e| AppError::Storage(format!("Damaged session file: {:?}", e)))
+    }
+
+    /// Writes a session file, encrypted if a passcode is set. While locked there
+    /// is no key, so the write is refused rather than done in plaintext.
+    pub fn save_session(&self, path: &Path, session: &Session) -> Result<(), AppError> {
+        let data = session.save();
+        let data = match self.vault.lock().unwrap().as_ref() {
+            Some(vault) => vault.encrypt(&data),
+            None if self.passcode.is_enabled() => {
+                return Err(AppError::Locked("App is locked".to_string()))
+            }
+            None => data,
+        };
+        std::fs::write(path, data)
+            .map_err(|e| AppError::Storage(format!("Failed to write to {:?}: {}", path, e)))
+    }
+}
+
+pub fn spawn_auto_lock_loop(app_handle: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(CHECK_SECS)).await;
+            let state = app_handle.state::<AppState>();
+            let lock = &state.app_lock;
+            let minutes = lock.settings.lock().unwrap().auto_lock_minutes;
+            if minutes == 0 || lock.is_locked() || !lock.passcode.is_enabled() {
+                continue;
+            }
+            let idle = chrono::Utc::now().timestamp() - lock.last_activity.load(Ordering::SeqCst);
+            if idle >= (minutes * 60) as i64 {
+                println!("Locking after {} minutes without activity", minutes);
+                lock.lock();
+                let _ = app_handle.emit("app-locked", ());
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct AppLockStatus {
+    pub enabled: bool,
+    pub locked: bool,
+    pub auto_lock_minutes: u64,
+}
+
+#[tauri::command]
+pub(crate) fn get_app_lock_status(state: State<AppState>) -> Result<AppLockStatus, AppError> {
+    let lock = &state.app_lock;
+    Ok(AppLockStatus {
+        enabled: lock.passcode.is_enabled(),
+        locked: lock.is_locked(),
+        auto_lock_minutes: lock.settings.lock().unwrap().auto_lock_minutes,
+    })
+}
+
+#[tauri::command]
+pub(crate) fn unlock_app(passcode: String, state: State<AppState>) -> Result<(), AppError> {
+    let lock = &state.app_lock;
+    if !lock.passcode.is_enabled() {
+        return Ok(());
+    }
+    let vault = lock.passcode.open(&passcode)?;
+    *lock.vault.lock().unwrap() = Some(Arc::new(vault));
+    lock.locked.store(false, Ordering::SeqCst);
+    lock.last_activity
+        .store(chrono::Utc::now().timestamp(), Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn lock_app(state: State<AppState>) -> Result<(), AppError> {
+    if !state.app_lock.passcode.is_enabled() {
+        return Err("No passcode is set".into());
+    }
+    state.app_lock.lock();
+    Ok(())
+}
+
+/// Sets, changes (both given) or removes (`new_passcode` None) the passcode. Every
+/// account's session file is re-written to match.
+#[tauri::command]
+pub(crate) fn set_passcode(
+    current_passcode: Option<String>,
+    new_passcode: Option<String>,
+    state: State<AppState>,
+) -> Result<(), AppError> {
+    let lock = &state.app_lock;
+    if lock.passcode.is_enabled() {
+        let current = current_passcode.ok_or("Enter the current passcode")?;
+        let vault = lock.passcode.open(&current)?;
+        *lock.vault.lock().unwrap() = Some(Arc::new(vault));
+    }
+
+    // Everything is read with the old key before the keyring changes
+    let mut sessions = Vec::new();
+    for account in state.accounts.all() {
+        let path = account.session_path();
+        if path.exists() {
+            let session = lock.load_session(&path)?;
+            sessions.push((path, session));
+        }
+    }
+
+    let vault = match new_passcode {
+        Some(passcode) => Some(Arc::new(lock.passcode.create(&passcode)?)),
+        None => {
+            if lock.passcode.is_enabled() {
+                lock.passcode.remove()?;
+            }
+            None
+        }
+    };
+    *lock.vault.lock().unwrap() = vault;
+    for (path, session) in &sessions {
+        lock.save_session(path, session)?;
+    }
+    println!("Passcode updated, {} session(s) re-written", sessions.len());
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn set_auto_lock(minutes: u64, state: State<AppState>) -> Result<(), AppError> {
+    let mut settings = state.app_lock.settings.lock().unwrap().clone();
+    settings.auto_lock_minutes = minutes;
+    state.app_lock.save_settings(&settings)?;
+    *state.app_lock.settings.lock().unwrap() = settings;
+    Ok(())
+}
@@ -0,0 +1,563 @@
+use grammers_client::types::{Downloadable, InputMessage, Media, Message};
+use grammers_client::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use tauri::State;
+
+use crate::accounts::Account;
+use crate::db::{Database, MergePlan, MergePreview, Replica, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::{crypto, storage, AppState, BACKUP_TAG};
+
+const AUTO_BACKUP_FILENAME: &str = "auto_backup.json";
+
+/// One backup message. Backups made before the index existed only carry a
+/// timestamp, so counts and hash are optional.
+#[derive(Serialize, Clone)]
+pub struct BackupEntry {
+    pub peer: StoragePeer,
+    pub message_id: i32,
+    pub timestamp: i64,
+    pub files: Option<usize>,
+    pub folders: Option<usize>,
+    pub hash: Option<String>,
+    pub size: i64,
+}
+
+fn caption(timestamp: i64, files: usize, folders: usize, hash: &str) -> String {
+    format!(
+        "{}\nTimestamp: {}\nFiles: {}\nFolders: {}\nHash: {}",
+        BACKUP_TAG, timestamp, files, folders, hash
+    )
+}
+
+fn parse_backup(peer: &StoragePeer, msg: &Message) -> Option<BackupEntry> {
+    let text = msg.text();
+    if !text.contains(BACKUP_TAG) {
+        return None;
+    }
+    let size = match msg.media()? {
+        Media::Document(doc) => doc.size(),
+        _ => return None,
+    };
+    let mut entry = BackupEntry {
+        peer: peer.clone(),
+        message_id: msg.id(),
+        timestamp: msg.date().timestamp(),
+        files: None,
+        folders: None,
+        hash: None,
+        size,
+    };
+    for line in text.lines() {
+        match line.split_once(": ") {
+            Some(("Timestamp", v)) => entry.timestamp = v.parse().unwrap_or(entry.timestamp),
+            Some(("Files", v)) => entry.files = v.parse().ok(),
+            Some(("Folders", v)) => entry.folders = v.parse().ok(),
+            Some(("Hash", v)) => entry.hash = Some(v.to_string()),
+            _ => {}
+        }
+    }
+    Some(entry)
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// Backups go to the storage target. Older ones may still sit in Saved Messages.
+fn peers_for(target: StoragePeer) -> Vec<StoragePeer> {
+    let mut peers = vec![target];
+    if peers[0] != StoragePeer::SavedMessages {
+        peers.push(StoragePeer::SavedMessages);
+    }
+    peers
+}
+
+// Where the open drive's backups live. A shared drive's never go to Saved Messages.
+fn backup_peers(account: &Account) -> Vec<StoragePeer> {
+    match account.shared.active() {
+        Some(_) => vec![account.storage_target()],
+        None => peers_for(account.storage_target()),
+    }
+}
+
+/// Every backup found in `peers`, newest first. `limit` caps the messages read
+/// per chat; 0 reads them all.
+pub async fn find_backups(
+    client: &Client,
+    peers: &[StoragePeer],
+    limit: usize,
+) -> Result<Vec<BackupEntry>, AppError> {
+    let mut backups = Vec::new();
+    for peer in peers {
+        let chat = storage::resolve_peer(client, peer).await?;
+        let mut messages = client.search_messages(chat).query(BACKUP_TAG);
+        if limit > 0 {
+            messages = messages.limit(limit);
+        }
+        while let Some(msg) = rpc!("messages.search", messages.next())? {
+            if let Some(entry) = parse_backup(peer, &msg) {
+                backups.push(entry);
+            }
+        }
+    }
+    backups.sort_by_key(|b| std::cmp::Reverse(b.timestamp));
+    Ok(backups)
+}
+
+/// Deletes all but the newest `keep` backups. Returns how many were removed.
+pub async fn prune_backups(
+    client: &Client,
+    peers: &[StoragePeer],
+    keep: usize,
+) -> Result<usize, AppError> {
+    // Every backup, or old ones past a search limit would never go
+    let backups = find_backups(client, peers, 0).await?;
+    let mut pruned = 0;
+    for old in backups.iter().skip(keep) {
+        let chat = storage::resolve_peer(client, &old.peer).await?;
+        rpc!(
+            "messages.deleteMessages",
+            client.delete_messages(chat, &[old.message_id])
+        )?;
+        pruned += 1;
+    }
+    Ok(pruned)
+}
+
+/// Uploads `db`'s store to `target` with its counts and hash, encrypted when a
+/// passphrase is set. Returns None without uploading when the hash equals `unless_hash`.
+pub async fn create_backup(
+    client: &Client,
+    db: &Database,
+    target: StoragePeer,
+    unless_hash: Option<&str>,
+) -> Result<Option<BackupEntry>, AppError> {
+    if db.is_locked() {
+        return Err(AppError::Locked("Metadata is locked".to_string()));
+    }
+    // Hashed before encryption, which would make every upload look different
+    let plain = db.export_snapshot()?;
+    let hash = hash_bytes(&plain);
+    if unless_hash == Some(hash.as_str()) {
+        return Ok(None);
+    }
+    let (files, folders) = db.counts();
+    let timestamp = chrono::Utc::now().timestamp();
+
+    let (bytes, name) = match crypto::vault() {
+        Some(vault) => (vault.encrypt(&plain), "metadata.json.enc"),
+        None => (plain, "metadata.json"),
+    };
+    let size = bytes.len();
+    let uploaded = rpc!(
+        "upload.saveFilePart",
+        client.upload_stream(&mut bytes.as_slice(), size, name.to_string())
+    )?;
+
+    let chat = storage::resolve_peer(client, &target).await?;
+    let text = caption(timestamp, files, folders, &hash);
+    let msg = rpc!(
+        "messages.sendMedia",
+        client.send_message(chat, InputMessage::text(&text).document(uploaded.clone()))
+    )?;
+
+    Ok(Some(BackupEntry {
+        peer: target,
+        message_id: msg.id(),
+        timestamp,
+        files: Some(files),
+        folders: Some(folders),
+        hash: Some(hash),
+        size: size as i64,
+    }))
+}
+
+// A failed cleanup doesn't make the new backup any less good
+async fn apply_retention(client: &Client, peers: &[StoragePeer], keep: usize) {
+    match prune_backups(client, peers, keep).await {
+        Ok(0) => {}
+        Ok(pruned) => println!("Removed {} old backups", pruned),
+        Err(e) => eprintln!("Backup retention failed: {}", e),
+    }
+}
+
+/// Automatic backups of the personal drive, in auto_backup.json.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AutoBackupConfig {
+    pub enabled: bool,
+    // Back up after this many changes...
+    pub every_mutations: u64,
+    // ...or when the last backup is this old
+    pub every_hours: u64,
+    pub last_backup: Option<i64>,
+    pub last_hash: Option<String>,
+    pub last_error: Option<String>,
+    // Mutation count and time of the last check, this session only
+    #[serde(skip)]
+    mutations_at_check: u64,
+    #[serde(skip)]
+    last_check: i64,
+}
+
+impl Default for AutoBackupConfig {
+    fn default() -> Self {
+        AutoBackupConfig {
+            enabled: true,
+            every_mutations: 50,
+            every_hours: 24,
+            last_backup: None,
+            last_hash: None,
+            last_error: None,
+            mutations_at_check: 0,
+            last_check: 0,
+        }
+    }
+}
+
+impl AutoBackupConfig {
+    pub fn load(dir: &Path) -> Self {
+        File::open(dir.join(AUTO_BACKUP_FILENAME))
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), AppError> {
+        let file = File::create(dir.join(AUTO_BACKUP_FILENAME)).map_err(AppError::storage)?;
+        serde_json::to_writer(BufWriter::new(file), self).map_err(AppError::storage)
+    }
+
+    // After a backup the upload is what matters; a schedule that didn't save
+    // only means the next check comes early
+    fn save_or_log(&self, dir: &Path) {
+        if let Err(e) = self.save(dir) {
+            eprintln!("Failed to save backup schedule: {}", e);
+        }
+    }
+
+    fn is_due(&self, mutations: u64, now: i64) -> bool {
+        let changed = mutations.saturating_sub(self.mutations_at_check);
+        let since = now - self.last_backup.unwrap_or(0).max(self.last_check);
+        changed >= self.every_mutations || since >= (self.every_hours * 3600) as i64
+    }
+
+    fn record(&mut self, result: &Result<Option<BackupEntry>, AppError>, mutations: u64, now: i64) {
+        self.mutations_at_check = mutations;
+        self.last_check = now;
+        match result {
+            Ok(Some(entry)) => {
+                self.last_backup = Some(entry.timestamp);
+                self.last_hash = entry.hash.clone();
+                self.last_error = None;
+            }
+            Ok(None) => self.last_error = None,
+            Err(e) => self.last_error = Some(e.to_string()),
+        }
+    }
+}
+
+/// One automatic backup check of the personal drive, run by the maintenance
+/// scheduler. Backs up when enough has changed or enough time has passed, or
+/// always when `force`d, skipping the upload when the content is unchanged.
+pub async fn run_auto_backup(
+    client: &Client,
+    account: &Account,
+    keep: usize,
+    force: bool,
+) -> Result<String, AppError> {
+    let config = account.auto_backup.lock().unwrap().clone();
+    let mutations = account.personal_db.mutation_count();
+    let now = chrono::Utc::now().timestamp();
+    if !force && !config.enabled {
+        return Ok("Automatic backups are off".to_string());
+    }
+    if !force && !config.is_due(mutations, now) {
+        return Ok("Not due yet".to_string());
+    }
+
+    let target = account.storage.lock().unwrap().target.clone();
+    let result = create_backup(
+        client,
+        &account.personal_db,
+        target.clone(),
+        config.last_hash.as_deref(),
+    )
+    .await;
+    let outcome = match &result {
+        Ok(Some(entry)) => {
+            println!("Automatic backup done at {}", entry.timestamp);
+            apply_retention(client, &peers_for(target), keep).await;
+            Ok(format!("Backed up at {}", entry.timestamp))
+        }
+        Ok(None) => {
+            println!("Automatic backup skipped, nothing changed");
+            Ok("Nothing changed since the last backup".to_string())
+        }
+        Err(e) => {
+            eprintln!("Automatic backup failed: {}", e);
+            Err(e.clone())
+        }
+    };
+
+    let mut config = account.auto_backup.lock().unwrap();
+    config.record(&result, mutations, now);
+    config.save_or_log(&account.dir);
+    outcome
+}
+
+#[tauri::command]
+pub(crate) async fn backup_metadata(state: State<'_, AppState>) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    println!("Starting metadata backup...");
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let db = account.db();
+    let mutations = db.mutation_count();
+    let result = create_backup(&client, &db, account.storage_target(), None).await;
+    // A manual backup of the personal drive resets the automatic schedule
+    if account.shared.active().is_none() {
+        let mut config = account.auto_backup.lock().unwrap();
+        config.record(&result, mutations, chrono::Utc::now().timestamp());
+        config.save_or_log(&account.dir);
+    }
+    let entry = result?.ok_or("Nothing to back up")?;
+    println!("Backup uploaded successfully.");
+
+    apply_retention(
+        &client,
+        &backup_peers(&account),
+        state.settings.get().keep_backups,
+    )
+    .await;
+    Ok(format!("Backup successful! Timestamp: {}", entry.timestamp))
+}
+
+#[tauri::command]
+pub(crate) async fn get_backup_status(
+    state: State<'_, AppState>,
+) -> Result<AutoBackupConfig, AppError> {
+    let account = state.accounts.active();
+    let config = account.auto_backup.lock().unwrap().clone();
+    Ok(config)
+}
+
+#[tauri::command]
+pub(crate) async fn set_auto_backup(
+    enabled: bool,
+    every_mutations: u64,
+    every_hours: u64,
+    state: State<'_, AppState>,
+) -> Result<AutoBackupConfig, AppError> {
+    if every_mutations == 0 || every_hours == 0 {
+        return Err("Backup intervals must be at least 1".into());
+    }
+    let account = state.accounts.active();
+    let mut config = account.auto_backup.lock().unwrap();
+    let mut updated = config.clone();
+    updated.enabled = enabled;
+    updated.every_mutations = every_mutations;
+    updated.every_hours = every_hours;
+    updated.save(&account.dir)?;
+    *config = updated.clone();
+    Ok(updated)
+}
+
+#[tauri::command]
+pub(crate) async fn list_backups(state: State<'_, AppState>) -> Result<Vec<BackupEntry>, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+    let limit = state.settings.get().backup_search_limit;
+    find_backups(&client, &backup_peers(&account), limit).await
+}
+
+/// Downloads and decrypts a backup and checks it before anything local is touched:
+/// the hash must match the one recorded at backup time and the JSON must load.
+pub async fn download_backup(
+    client: &Client,
+    peer: &StoragePeer,
+    message_id: i32,
+) -> Result<(BackupEntry, Vec<u8>), AppError> {
+    let messages = storage::fetch_messages(client, peer, &[message_id]).await?;
+    let msg = messages
+        .into_iter()
+        .next()
+        .flatten()
+        .ok_or("Backup message not found")?;
+    let entry = parse_backup(peer, &msg).ok_or("Message is not a metadata backup")?;
+    let media = msg.media().ok_or("No media in backup message")?;
+
+    let mut bytes = Vec::new();
+    let mut download = client.iter_download(&Downloadable::Media(media));
+    while let Some(chunk) = rpc!("upload.getFile", download.next())? {
+        bytes.extend_from_slice(&chunk);
+    }
+    if crypto::is_encrypted(&bytes) {
+        bytes = crypto::vault()
+            .ok_or_else(|| AppError::Locked("Unlock first to restore an encrypted backup".into()))?
+            .decrypt(&bytes)?;
+    }
+
+    if let Some(expected) = &entry.hash {
+        if hash_bytes(&bytes) != *expected {
+            return Err(AppError::Storage(
+                "Backup is corrupted: hash does not match".to_string(),
+            ));
+        }
+    }
+    Database::validate_snapshot(&bytes)?;
+    Ok((entry, bytes))
+}
+
+// The chosen backup, or the newest one when none is given
+async fn pick_backup(
+    client: &Client,
+    account: &Account,
+    peer: Option<StoragePeer>,
+    message_id: Option<i32>,
+    limit: usize,
+) -> Result<(StoragePeer, i32), AppError> {
+    match (peer, message_id) {
+        (Some(peer), Some(message_id)) => Ok((peer, message_id)),
+        _ => {
+            let newest = find_backups(client, &backup_peers(account), limit)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or("No backup found in storage.")?;
+            Ok((newest.peer, newest.message_id))
+        }
+    }
+}
+
+// Backup entries with no copy left would only be dead links, so they are left
+// out of the merge. Entries that lost some copies keep the live ones.
+async fn plan_merge_restore(
+    client: &Client,
+    db: &Database,
+    bytes: &[u8],
+) -> Result<MergePlan, AppError> {
+    let mut plan = db.plan_merge(bytes)?;
+    let mut by_peer: HashMap<StoragePeer, Vec<i32>> = HashMap::new();
+    for l in plan.files.iter().flat_map(|f| f.locations()) {
+        by_peer.entry(l.peer).or_default().push(l.message_id);
+    }
+    let mut alive = HashSet::new();
+    for (peer, ids) in by_peer {
+        for chunk in ids.chunks(100) {
+            let messages = storage::fetch_messages(client, &peer, chunk).await?;
+            for (message_id, msg) in chunk.iter().zip(messages.iter()) {
+                if msg.as_ref().and_then(|m| m.media()).is_some() {
+                    alive.insert(Replica {
+                        peer: peer.clone(),
+                        message_id: *message_id,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut gone = Vec::new();
+    for file in &mut plan.files {
+        let mut live = file.locations();
+        live.retain(|l| alive.contains(l));
+        if live.is_empty() {
+            gone.push(file.id.clone());
+            continue;
+        }
+        let primary = live.remove(0);
+        file.peer = primary.peer;
+        file.message_id = primary.message_id;
+        file.replicas = live;
+    }
+    plan.drop_files(&gone);
+    Ok(plan)
+}
+
+/// What `restore_metadata` in merge mode would change, without changing it.
+#[tauri::command]
+pub(crate) async fn preview_restore(
+    peer: Option<StoragePeer>,
+    message_id: Option<i32>,
+    state: State<'_, AppState>,
+) -> Result<MergePreview, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let (peer, message_id) = pick_backup(
+        &client,
+        &account,
+        peer,
+        message_id,
+        state.settings.get().backup_search_limit,
+    )
+    .await?;
+    let (_, bytes) = download_backup(&client, &peer, message_id).await?;
+    let plan = plan_merge_restore(&client, &account.db(), &bytes).await?;
+    Ok(plan.preview)
+}
+
+/// Restores the chosen backup, or the newest one when none is given. By default
+/// the backup is merged into the current index (see `Database::plan_merge`);
+/// with `merge: false` it replaces it and the current metadata is kept next to
+/// it as metadata.json.old.
+#[tauri::command]
+pub(crate) async fn restore_metadata(
+    peer: Option<StoragePeer>,
+    message_id: Option<i32>,
+    merge: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    println!("Restoring metadata from backup...");
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let (peer, message_id) = pick_backup(
+        &client,
+        &account,
+        peer,
+        message_id,
+        state.settings.get().backup_search_limit,
+    )
+    .await?;
+    let (entry, bytes) = download_backup(&client, &peer, message_id).await?;
+    let db = account.db();
+
+    if merge.unwrap_or(true) {
+        let plan = plan_merge_restore(&client, &db, &bytes).await?;
+        let summary = format!(
+            "Backup merged: {} added, {} updated, {} skipped because their messages are gone.",
+            plan.preview.added.len(),
+            plan.preview.updated.len(),
+            plan.preview.dropped.len()
+        );
+        db.apply_merge(plan)?;
+        println!("Merged backup from {}", entry.timestamp);
+        return Ok(summary);
+    }
+
+    let metadata_path = db.path().to_path_buf();
+    if metadata_path.exists() {
+        let _ = tokio::fs::copy(&metadata_path, metadata_path.with_extension("json.old")).await;
+    }
+    db.replace_with_snapshot(&bytes)?;
+
+    println!("Restored backup from {}", entry.timestamp);
+    Ok("Backup restored successfully. Your dashboard will refresh.".to_string())
+}
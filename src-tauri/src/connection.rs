@@ -0,0 +1,411 @@
+use grammers_client::{Client, Config, InitParams};
+use grammers_tl_types as tl;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio::sync::Notify;
+
+use crate::accounts::Account;
+use crate::applock::AppLock;
+use crate::error::{is_network_error, AppError};
+use crate::gateway::rpc;
+use crate::proxy::ProxyConfig;
+use crate::{deletion, AppState};
+
+const UPLOAD_QUEUE_FILENAME: &str = "upload_queue.json";
+// How often a healthy connection is checked
+const CHECK_SECS: u64 = 20;
+const PING_TIMEOUT_SECS: u64 = 10;
+const MAX_BACKOFF_SECS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Online,
+    Connecting { attempt: u32 },
+    Offline { attempt: u32, retry_in_secs: u64 },
+}
+
+/// Connection state of the active account, kept up to date by the supervisor.
+pub struct Connection {
+    state: Mutex<ConnectionState>,
+    wake: Notify,
+    // Set when the client must be rebuilt even though it still works, e.g. a new proxy
+    force_reconnect: AtomicBool,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Connection {
+            state: Mutex::new(ConnectionState::Online),
+            wake: Notify::new(),
+            force_reconnect: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Connection {
+    pub fn state(&self) -> ConnectionState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.state() != ConnectionState::Online
+    }
+
+    fn set(&self, app_handle: &AppHandle, state: ConnectionState) {
+        let mut current = self.state.lock().unwrap();
+        if *current != state {
+            *current = state.clone();
+            let _ = app_handle.emit("connection-state", state);
+        }
+    }
+
+    /// Makes the supervisor check (or retry) right away instead of at its next tick.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Replaces the active client with a fresh one on its next check.
+    pub fn reconnect(&self) {
+        self.force_reconnect.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+}
+
+/// Read at compile time for releases and from .env for local dev.
+pub fn api_credentials() -> Option<(i32, String)> {
+    dotenv::dotenv().ok();
+    let api_id = option_env!("TELEGRAM_API_ID")
+        .map(|s| s.to_string())
+        .or_else(|| std::env::var("TELEGRAM_API_ID").ok())
+        .and_then(|s| s.parse::<i32>().ok())
+        .unwrap_or(0);
+    let api_hash = option_env!("TELEGRAM_API_HASH")
+        .map(|s| s.to_string())
+        .or_else(|| std::env::var("TELEGRAM_API_HASH").ok())
+        .unwrap_or_default();
+    if api_id == 0 || api_hash.is_empty() {
+        return None;
+    }
+    Some((api_id, api_hash))
+}
+
+/// Opens a client on the account's saved session, through the proxy if one is set.
+pub async fn connect(
+    app_lock: &AppLock,
+    proxy: &ProxyConfig,
+    account: &Account,
+) -> Result<Client, AppError> {
+    let (api_id, api_hash) = api_credentials().ok_or("API Credentials missing in .env")?;
+    let session = app_lock.load_session(&account.session_path())?;
+    let mut params = InitParams {
+        device_model: "Paperfold Desktop".to_string(),
+        app_version: "0.1.0".to_string(),
+        system_version: "macOS".to_string(),
+        ..Default::default()
+    };
+    proxy.apply(&mut params)?;
+    let config = Config {
+        session,
+        api_id,
+        api_hash,
+        params,
+    };
+    Ok(Client::connect(config).await?)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedUpload {
+    pub id: String,
+    pub path: String,
+    pub folder_id: Option<String>,
+    // Shared drive the upload was started in; None for the personal drive
+    #[serde(default)]
+    pub drive_id: Option<String>,
+    pub queued_at: i64,
+}
+
+/// Uploads started while offline. They run once the supervisor reconnects.
+pub struct UploadQueue {
+    path: PathBuf,
+    items: Mutex<Vec<QueuedUpload>>,
+}
+
+impl UploadQueue {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(UPLOAD_QUEUE_FILENAME);
+        let items = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default();
+        UploadQueue {
+            path,
+            items: Mutex::new(items),
+        }
+    }
+
+    fn save(&self, items: &[QueuedUpload]) {
+        let result = File::create(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| {
+                serde_json::to_writer(BufWriter::new(f), items).map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            eprintln!("Failed to save upload queue: {}", e);
+        }
+    }
+
+    pub fn push(&self, path: &str, folder_id: Option<String>, drive_id: Option<String>) {
+        let mut items = self.items.lock().unwrap();
+        items.push(QueuedUpload {
+            id: uuid::Uuid::new_v4().to_string(),
+            path: path.to_string(),
+            folder_id,
+            drive_id,
+            queued_at: chrono::Utc::now().timestamp(),
+        });
+        self.save(&items);
+    }
+
+    pub fn remove(&self, id: &str) -> bool {
+        let mut items = self.items.lock().unwrap();
+        let before = items.len();
+        items.retain(|u| u.id != id);
+        let removed = items.len() != before;
+        if removed {
+            self.save(&items);
+        }
+        removed
+    }
+
+    pub fn list(&self) -> Vec<QueuedUpload> {
+        self.items.lock().unwrap().clone()
+    }
+}
+
+/// Queues an upload for later and returns the error the command should report.
+pub fn queue_upload(account: &Account, path: &str, folder_id: Option<String>) -> AppError {
+    let drive_id = account.shared.active().map(|d| d.info().id);
+    account.upload_queue.push(path, folder_id, drive_id);
+    println!("Offline, queued upload of {}", path);
+    AppError::Offline(
+        "You are offline. The upload is queued and starts when the connection is back.".to_string(),
+    )
+}
+
+// Any answer from Telegram, even an error, means the connection works
+async fn is_alive(client: &Client) -> bool {
+    let ping = async {
+        rpc!(
+            "updates.getState",
+            client.invoke(&tl::functions::updates::GetState {})
+        )
+    };
+    match tokio::time::timeout(Duration::from_secs(PING_TIMEOUT_SECS), ping).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => !is_network_error(&e),
+        Err(_) => false,
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct QueuedUploadDone {
+    id: String,
+    path: String,
+    error: Option<String>,
+}
+
+/// Runs what piled up while offline: Telegram-side deletions and queued uploads.
+async fn drain(app_handle: &AppHandle, account: &Arc<Account>, client: &Client) {
+    let run = deletion::process_account(client, account).await;
+    if run.deleted > 0 {
+        println!("Deleted {} queued messages after reconnecting", run.deleted);
+    }
+
+    for item in account.upload_queue.list() {
+        let (db, target) = match &item.drive_id {
+            None => (
+                account.personal_db.clone(),
+                account.storage.lock().unwrap().target.clone(),
+            ),
+            Some(id) => match account.shared.get(id) {
+                Some(drive) => (drive.db.clone(), drive.info().peer),
+                None => {
+                    eprintln!("Dropping queued upload {}: drive is gone", item.path);
+                    account.upload_queue.remove(&item.id);
+                    continue;
+                }
+            },
+        };
+
+        println!("Uploading queued {}", item.path);
+        let result = crate::upload_file_core(
+            app_handle.clone(),
+            account,
+            client.clone(),
+            &db,
+            target,
+            item.path.clone(),
+            item.folder_id.clone(),
+        )
+        .await;
+        let error = match result {
+            Ok(_) => None,
+            // Lost the connection again; the rest waits for the next reconnect
+            Err(AppError::Offline(_)) => break,
+            Err(e) => {
+                eprintln!("Queued upload of {} failed: {}", item.path, e);
+                Some(e.to_string())
+            }
+        };
+        account.upload_queue.remove(&item.id);
+        let _ = app_handle.emit(
+            "queued-upload-done",
+            QueuedUploadDone {
+                id: item.id,
+                path: item.path,
+                error,
+            },
+        );
+    }
+}
+
+/// Watches the active account's connection. A dead client is replaced with a fresh
+/// one on the saved session, retrying with exponential backoff.
+pub fn spawn_supervisor(app_handle: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut attempt: u32 = 0;
+
+        loop {
+            let state = app_handle.state::<AppState>();
+            let account = state.accounts.active();
+            let client = account.client.lock().await.clone();
+
+            let forced = state
+                .connection
+                .force_reconnect
+                .swap(false, Ordering::SeqCst);
+            // No client is only our business when check_auth started us offline
+            let alive = !forced
+                && match &client {
+                    Some(client) => is_alive(client).await,
+                    None => !state.connection.is_offline() || !account.session_path().exists(),
+                };
+            if alive {
+                if state.connection.is_offline() {
+                    println!("Connection is back");
+                    state.connection.set(&app_handle, ConnectionState::Online);
+                }
+                attempt = 0;
+                let _ = tokio::time::timeout(
+                    Duration::from_secs(CHECK_SECS),
+                    state.connection.wake.notified(),
+                )
+                .await;
+                continue;
+            }
+
+            attempt += 1;
+            if forced {
+                println!("Reconnecting with new connection settings");
+            } else {
+                println!("Connection lost, reconnecting (attempt {})", attempt);
+            }
+            state
+                .connection
+                .set(&app_handle, ConnectionState::Connecting { attempt });
+
+            // Keep whatever the old client learned about the session
+            if let Some(old) = &client {
+                if let Err(e) = state
+                    .app_lock
+                    .save_session(&account.session_path(), old.session())
+                {
+                    eprintln!("Failed to save session before reconnecting: {}", e);
+                }
+            }
+
+            match connect(&state.app_lock, &state.proxy.get(), &account).await {
+                Ok(new_client) => {
+                    let mut guard = account.client.lock().await;
+                    // A logout while we were connecting wins
+                    if guard.is_none() && client.is_some() {
+                        state.connection.set(&app_handle, ConnectionState::Online);
+                        attempt = 0;
+                        continue;
+                    }
+                    *guard = Some(new_client.clone());
+                    drop(guard);
+                    println!("Reconnected after {} attempts", attempt);
+                    state.connection.set(&app_handle, ConnectionState::Online);
+                    attempt = 0;
+
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        drain(&app_handle, &account, &new_client).await;
+                    });
+                }
+                Err(e) => {
+                    let retry_in_secs = (1u64 << attempt.min(6)).min(MAX_BACKOFF_SECS);
+                    eprintln!("Reconnect failed: {}. Retrying in {}s", e, retry_in_secs);
+                    state.connection.set(
+                        &app_handle,
+                        ConnectionState::Offline {
+                            attempt,
+                            retry_in_secs,
+                        },
+                    );
+                    let _ = tokio::time::timeout(
+                        Duration::from_secs(retry_in_secs),
+                        state.connection.wake.notified(),
+                    )
+                    .await;
+                }
+            }
+        }
+    });
+}
+
+/// Marks the app offline without a client, e.g. when the saved session could not
+/// connect at startup. The supervisor takes it from there.
+pub fn start_offline(app_handle: &AppHandle, connection: &Connection) {
+    connection.set(
+        app_handle,
+        ConnectionState::Offline {
+            attempt: 0,
+            retry_in_secs: 0,
+        },
+    );
+    connection.wake();
+}
+
+#[tauri::command]
+pub(crate) fn get_connection_state(state: State<AppState>) -> Result<ConnectionState, AppError> {
+    Ok(state.connection.state())
+}
+
+/// Skips the rest of the backoff and tries to reconnect now.
+#[tauri::command]
+pub(crate) fn reconnect_now(state: State<AppState>) -> Result<(), AppError> {
+    state.connection.wake();
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn list_queued_uploads(state: State<AppState>) -> Result<Vec<QueuedUpload>, AppError> {
+    Ok(state.accounts.active().upload_queue.list())
+}
+
+#[tauri::command]
+pub(crate) fn cancel_queued_upload(id: String, state: State<AppState>) -> Result<(), AppError> {
+    if !state.accounts.active().upload_queue.remove(&id) {
+        return Err(AppError::not_found(&id));
+    }
+    Ok(())
+}
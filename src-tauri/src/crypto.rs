@@ -0,0 +1,347 @@
+use base64::{engine::general_purpose, Engine as _};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tauri::State;
+
+use crate::error::AppError;
+use crate::AppState;
+
+const KEYRING_FILENAME: &str = "encryption.json";
+// Prefix of every encrypted blob, followed by iterations, salt and nonce
+const MAGIC: &[u8] = b"PFENC1";
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 4 + SALT_LEN + NONCE_LEN;
+const ITERATIONS: u32 = 600_000;
+const MIN_PASSPHRASE_LEN: usize = 8;
+// Known plaintext stored encrypted in the keyring to check a passphrase
+const VERIFIER: &[u8] = b"paperfold";
+
+// The key of the unlocked session, shared by every metadata store
+static VAULT: Mutex<Option<Arc<Vault>>> = Mutex::new(None);
+
+pub fn vault() -> Option<Arc<Vault>> {
+    VAULT.lock().unwrap().clone()
+}
+
+fn set_vault(vault: Option<Arc<Vault>>) {
+    *VAULT.lock().unwrap() = vault;
+}
+
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> LessSafeKey {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).unwrap())
+}
+
+/// AES-256-GCM with a PBKDF2 key. Each blob carries its own salt, so one written
+/// with another salt (a backup from another install with the same passphrase)
+/// can still be opened: the key for it is derived on demand and cached. Blobs
+/// from before a passphrase change are opened with the retired passphrases.
+pub struct Vault {
+    passphrase: String,
+    salt: [u8; SALT_LEN],
+    key: LessSafeKey,
+    // Earlier passphrases, newest first
+    retired: Vec<String>,
+    other_keys: Mutex<HashMap<Vec<u8>, Arc<LessSafeKey>>>,
+}
+
+impl Vault {
+    fn new(passphrase: &str, salt: [u8; SALT_LEN]) -> Self {
+        Vault {
+            passphrase: passphrase.to_string(),
+            salt,
+            key: derive_key(passphrase, &salt, ITERATIONS),
+            retired: Vec::new(),
+            other_keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn encrypt(&self, plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).unwrap();
+
+        let mut sealed = plain.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .unwrap();
+
+        let mut blob = Vec::with_capacity(HEADER_LEN + sealed.len());
+        blob.extend_from_slice(MAGIC);
+        blob.extend_from_slice(&ITERATIONS.to_be_bytes());
+        blob.extend_from_slice(&self.salt);
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&sealed);
+        blob
+    }
+
+    pub fn decrypt(&self, blob: &[u8]) -> Result<Vec<u8>, String> {
+        if !is_encrypted(blob) || blob.len() < HEADER_LEN {
+            return Err("Not an encrypted Paperfold file".to_string());
+        }
+        let (header, sealed) = blob.split_at(HEADER_LEN);
+        let iterations =
+            u32::from_be_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+        let salt = &header[MAGIC.len() + 4..MAGIC.len() + 4 + SALT_LEN];
+        let nonce: [u8; NONCE_LEN] = header[HEADER_LEN - NONCE_LEN..].try_into().unwrap();
+
+        let passphrases = std::iter::once(&self.passphrase).chain(&self.retired);
+        for (i, passphrase) in passphrases.enumerate() {
+            let other;
+            let key = if i == 0 && salt == self.salt && iterations == ITERATIONS {
+                &self.key
+            } else {
+                let mut cache = self.other_keys.lock().unwrap();
+                let mut id = vec![i as u8];
+                id.extend_from_slice(salt);
+                id.extend_from_slice(&iterations.to_be_bytes());
+                other = cache
+                    .entry(id)
+                    .or_insert_with(|| Arc::new(derive_key(passphrase, salt, iterations)))
+                    .clone();
+                &*other
+            };
+
+            let mut buf = sealed.to_vec();
+            if let Ok(plain) =
+                key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut buf)
+            {
+                return Ok(plain.to_vec());
+            }
+        }
+        Err("Wrong passphrase or damaged file".to_string())
+    }
+}
+
+/// Salt and verifier of one secret. Its presence means that secret is set.
+#[derive(Serialize, Deserialize)]
+struct Keyring {
+    salt: String,     // base64
+    verifier: String, // base64 blob of VERIFIER
+    // Replaced passphrases, each a base64 blob under the current key
+    #[serde(default)]
+    retired: Vec<String>,
+}
+
+/// A secret kept as a keyring file in the app directory. The metadata passphrase
+/// lives in encryption.json, the app lock passcode in its own file.
+pub struct Encryption {
+    path: PathBuf,
+    min_len: usize,
+}
+
+impl Encryption {
+    pub fn new(app_dir: &Path) -> Self {
+        Self::with_file(app_dir, KEYRING_FILENAME, MIN_PASSPHRASE_LEN)
+    }
+
+    pub fn with_file(app_dir: &Path, filename: &str, min_len: usize) -> Self {
+        Encryption {
+            path: app_dir.join(filename),
+            min_len,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Encryption is on but nobody has entered the passphrase yet. Background work
+    /// that writes metadata waits while this is true.
+    pub fn is_locked(&self) -> bool {
+        self.is_enabled() && vault().is_none()
+    }
+
+    fn load(&self) -> Result<Keyring, String> {
+        let bytes = std::fs::read(&self.path).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+    }
+
+    /// Builds the vault for `passphrase` if it matches the keyring.
+    pub fn open(&self, passphrase: &str) -> Result<Vault, String> {
+        let keyring = self.load()?;
+        let damaged = || format!("Damaged {}", self.path.display());
+        let salt: [u8; SALT_LEN] = general_purpose::STANDARD
+            .decode(&keyring.salt)
+            .ok()
+            .and_then(|s| s.try_into().ok())
+            .ok_or_else(damaged)?;
+        let verifier = general_purpose::STANDARD
+            .decode(&keyring.verifier)
+            .map_err(|_| damaged())?;
+        let mut vault = Vault::new(passphrase, salt);
+        match vault.decrypt(&verifier) {
+            Ok(plain) if plain == VERIFIER => {}
+            _ => return Err("Wrong passphrase".to_string()),
+        }
+        for blob in &keyring.retired {
+            let old = general_purpose::STANDARD
+                .decode(blob)
+                .map_err(|_| damaged())
+                .and_then(|b| vault.decrypt(&b))?;
+            vault
+                .retired
+                .push(String::from_utf8(old).map_err(|_| damaged())?);
+        }
+        Ok(vault)
+    }
+
+    /// Fresh salt for `passphrase`, written to the keyring.
+    pub fn create(&self, passphrase: &str) -> Result<Vault, String> {
+        let vault = self.new_vault(passphrase)?;
+        self.save_keyring(&vault)?;
+        Ok(vault)
+    }
+
+    /// A vault for `passphrase` that still opens whatever `current` could. Nothing
+    /// is written; see `save_keyring`.
+    pub fn rekey(&self, current: &Vault, passphrase: &str) -> Result<Vault, String> {
+        let mut vault = self.new_vault(passphrase)?;
+        for old in std::iter::once(&current.passphrase).chain(&current.retired) {
+            if old != passphrase && !vault.retired.contains(old) {
+                vault.retired.push(old.clone());
+            }
+        }
+        Ok(vault)
+    }
+
+    fn new_vault(&self, passphrase: &str) -> Result<Vault, String> {
+        if passphrase.chars().count() < self.min_len {
+            return Err(format!("Must be at least {} characters", self.min_len));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).unwrap();
+        Ok(Vault::new(passphrase, salt))
+    }
+
+    pub fn save_keyring(&self, vault: &Vault) -> Result<(), String> {
+        let keyring = Keyring {
+            salt: general_purpose::STANDARD.encode(vault.salt),
+            verifier: general_purpose::STANDARD.encode(vault.encrypt(VERIFIER)),
+            retired: vault
+                .retired
+                .iter()
+                .map(|p| general_purpose::STANDARD.encode(vault.encrypt(p.as_bytes())))
+                .collect(),
+        };
+        // Written next to it first: losing the keyring halfway would lose everything
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&keyring).unwrap()).map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp, &self.path).map_err(|e| e.to_string())
+    }
+
+    pub fn remove(&self) -> Result<(), String> {
+        std::fs::remove_file(&self.path).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Serialize)]
+pub struct EncryptionStatus {
+    pub enabled: bool,
+    pub unlocked: bool,
+}
+
+#[tauri::command]
+pub(crate) fn get_encryption_status(state: State<AppState>) -> Result<EncryptionStatus, AppError> {
+    Ok(EncryptionStatus {
+        enabled: state.encryption.is_enabled(),
+        unlocked: vault().is_some(),
+    })
+}
+
+/// Turns encryption on and rewrites every account's metadata with the new key.
+#[tauri::command]
+pub(crate) fn enable_encryption(
+    passphrase: String,
+    state: State<AppState>,
+) -> Result<(), AppError> {
+    if state.encryption.is_enabled() {
+        return Err("Encryption is already enabled".into());
+    }
+    let vault = state.encryption.create(&passphrase)?;
+    set_vault(Some(Arc::new(vault)));
+    for account in state.accounts.all() {
+        for db in account.databases() {
+            db.rewrite()?;
+        }
+    }
+    println!("Metadata encryption enabled");
+    Ok(())
+}
+
+/// The startup step when encryption is on: nothing can be read or written before it.
+#[tauri::command]
+pub(crate) fn unlock(passphrase: String, state: State<AppState>) -> Result<(), AppError> {
+    if !state.encryption.is_enabled() {
+        return Ok(());
+    }
+    let vault = state.encryption.open(&passphrase)?;
+    set_vault(Some(Arc::new(vault)));
+    for account in state.accounts.loaded() {
+        for db in account.databases() {
+            db.unlock()?;
+        }
+    }
+    println!("Metadata unlocked");
+    Ok(())
+}
+
+/// Re-encrypts every account with a key from `new_passphrase`. Each store is
+/// written to a temp file first; the keyring and the files are only replaced once
+/// all of them are. The old passphrase stays in the keyring, wrapped by the new
+/// one, so backups made before the change can still be restored.
+#[tauri::command]
+pub(crate) fn change_passphrase(
+    current_passphrase: String,
+    new_passphrase: String,
+    state: State<AppState>,
+) -> Result<(), AppError> {
+    if !state.encryption.is_enabled() {
+        return Err("Encryption is not enabled".into());
+    }
+    let current = state.encryption.open(&current_passphrase)?;
+    // Every store has to be readable before the old key is thrown away
+    let accounts = state.accounts.all();
+    for account in &accounts {
+        for db in account.databases() {
+            if db.is_locked() {
+                return Err("Unlock before changing the passphrase".into());
+            }
+        }
+    }
+
+    let vault = state.encryption.rekey(&current, &new_passphrase)?;
+    let databases: Vec<_> = accounts.iter().flat_map(|a| a.databases()).collect();
+    let staged = databases
+        .iter()
+        .map(|db| db.stage_rewrite(&vault))
+        .collect::<Result<Vec<_>, _>>()?;
+    state.encryption.save_keyring(&vault)?;
+    set_vault(Some(Arc::new(vault)));
+    // Old files still open with the retired key, so a crash from here on loses nothing
+    for rewrite in staged {
+        rewrite.commit()?;
+    }
+    println!("Passphrase changed, metadata re-encrypted");
+    Ok(())
+}
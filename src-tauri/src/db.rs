@@ -1,10 +1,14 @@
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use uuid::Uuid;
 
+use crate::crypto;
+use crate::error::AppError;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Folder {
     pub id: String,
@@ -41,6 +45,26 @@ pub struct Folder {
     pub last_modified: i64,
 }
 
+/// Where a file's Telegram message lives. Entries written before storage targets
+/// existed have no `peer` field and deserialize as Saved Messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StoragePeer {
+    #[default]
+    SavedMessages,
+    Channel {
+        id: i64,
+        packed: String, // PackedChat hex, carries the access hash
+    },
+}
+
+/// An extra copy of a file's message in another peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Replica {
+    pub peer: StoragePeer,
+    pub message_id: i32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub id: String,
@@ -57,40 +81,603 @@ pub struct FileMetadata {
     #[serde(default)]
     pub is_starred: bool,
     pub thumbnail: Option<String>,
+    #[serde(default)]
+    pub peer: StoragePeer,
+    #[serde(default)]
+    pub hash: Option<String>, // SHA-256 of the content, hex
+    // Message was deleted outside the app; the entry stays so it can be re-uploaded
+    #[serde(default)]
+    pub missing: bool,
+    // Last local edit, compared by merge restores
+    #[serde(default)]
+    pub last_modified: i64,
+    // Copies besides `peer`/`message_id`, which stays the primary
+    #[serde(default)]
+    pub replicas: Vec<Replica>,
+}
+
+impl FileMetadata {
+    /// Every place the file is stored, primary first.
+    pub fn locations(&self) -> Vec<Replica> {
+        let mut locations = vec![Replica {
+            peer: self.peer.clone(),
+            message_id: self.message_id,
+        }];
+        locations.extend(self.replicas.iter().cloned());
+        locations
+    }
+}
+
+/// Partial update of a folder's appearance. `None` leaves a field untouched,
+/// an empty string clears it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FolderPatch {
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    pub gradient: Option<String>,
+    pub cover_image: Option<String>,
+    pub emoji: Option<String>,
+    pub pattern: Option<String>,
+    pub show_badges: Option<bool>,
+    pub tags: Option<Vec<String>>,
+    pub description: Option<String>,
+    pub view_mode: Option<String>,
+}
+
+impl FolderPatch {
+    /// The `meta.*` fields this patch sets, for versioning.
+    fn fields(&self) -> Vec<&'static str> {
+        let set = [
+            ("meta.color", self.color.is_some()),
+            ("meta.icon", self.icon.is_some()),
+            ("meta.gradient", self.gradient.is_some()),
+            ("meta.cover_image", self.cover_image.is_some()),
+            ("meta.emoji", self.emoji.is_some()),
+            ("meta.pattern", self.pattern.is_some()),
+            ("meta.show_badges", self.show_badges.is_some()),
+            ("meta.tags", self.tags.is_some()),
+            ("meta.description", self.description.is_some()),
+            ("meta.view_mode", self.view_mode.is_some()),
+        ];
+        set.into_iter()
+            .filter(|(_, s)| *s)
+            .map(|(f, _)| f)
+            .collect()
+    }
+
+    // Just the given `meta.*` fields of this patch
+    fn only(&self, fields: &[&str]) -> FolderPatch {
+        let keep = |field: &str| fields.contains(&field);
+        FolderPatch {
+            color: self.color.clone().filter(|_| keep("meta.color")),
+            icon: self.icon.clone().filter(|_| keep("meta.icon")),
+            gradient: self.gradient.clone().filter(|_| keep("meta.gradient")),
+            cover_image: self
+                .cover_image
+                .clone()
+                .filter(|_| keep("meta.cover_image")),
+            emoji: self.emoji.clone().filter(|_| keep("meta.emoji")),
+            pattern: self.pattern.clone().filter(|_| keep("meta.pattern")),
+            show_badges: self.show_badges.filter(|_| keep("meta.show_badges")),
+            tags: self.tags.clone().filter(|_| keep("meta.tags")),
+            description: self
+                .description
+                .clone()
+                .filter(|_| keep("meta.description")),
+            view_mode: self.view_mode.clone().filter(|_| keep("meta.view_mode")),
+        }
+    }
+
+    fn apply_to(&self, folder: &mut Folder) {
+        if let Some(c) = &self.color {
+            folder.color = if c.is_empty() { None } else { Some(c.clone()) };
+        }
+        if let Some(i) = &self.icon {
+            folder.icon = if i.is_empty() { None } else { Some(i.clone()) };
+        }
+        if let Some(g) = &self.gradient {
+            folder.gradient = if g.is_empty() { None } else { Some(g.clone()) };
+        }
+        if let Some(c) = &self.cover_image {
+            folder.cover_image = if c.is_empty() { None } else { Some(c.clone()) };
+        }
+        if let Some(e) = &self.emoji {
+            folder.emoji = if e.is_empty() { None } else { Some(e.clone()) };
+        }
+        if let Some(p) = &self.pattern {
+            folder.pattern = if p.is_empty() { None } else { Some(p.clone()) };
+        }
+        if let Some(s) = self.show_badges {
+            folder.show_badges = s;
+        }
+        if let Some(t) = &self.tags {
+            folder.tags = Some(t.clone());
+        }
+        if let Some(d) = &self.description {
+            folder.description = Some(d.clone());
+        }
+        if let Some(v) = &self.view_mode {
+            folder.view_mode = Some(v.clone());
+        }
+    }
+}
+
+/// A single change to the store. Events carry resulting values rather than
+/// toggles, so replaying the same sequence on another machine gives the same state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DriveEvent {
+    FolderCreated {
+        folder: Folder,
+    },
+    FolderRenamed {
+        id: String,
+        name: String,
+    },
+    FolderUpdated {
+        id: String,
+        patch: FolderPatch,
+        last_modified: i64,
+    },
+    FolderDeleted {
+        id: String,
+    },
+    Moved {
+        id: String,
+        is_folder: bool,
+        parent_id: Option<String>,
+    },
+    FileAdded {
+        file: FileMetadata,
+    },
+    FileRenamed {
+        id: String,
+        name: String,
+    },
+    FileRelocated {
+        id: String,
+        peer: StoragePeer,
+        message_id: i32,
+    },
+    ReplicasChanged {
+        id: String,
+        replicas: Vec<Replica>,
+    },
+    FileDeleted {
+        id: String,
+    },
+    Trashed {
+        id: String,
+        is_folder: bool,
+        at: i64,
+    },
+    Restored {
+        id: String,
+        is_folder: bool,
+    },
+    Starred {
+        id: String,
+        is_folder: bool,
+        starred: bool,
+    },
+}
+
+// Every field an item can have changed independently. A create writes all of them.
+// Folder appearance is versioned per field, since a FolderUpdated patch only
+// carries the fields that changed.
+const ALL_FIELDS: &[&str] = &[
+    "name",
+    "parent",
+    "trash",
+    "star",
+    "meta.color",
+    "meta.icon",
+    "meta.gradient",
+    "meta.cover_image",
+    "meta.emoji",
+    "meta.pattern",
+    "meta.show_badges",
+    "meta.tags",
+    "meta.description",
+    "meta.view_mode",
+    "location",
+    "replicas",
+];
+
+impl DriveEvent {
+    /// The item an event touches and which of its fields it writes.
+    fn target(&self) -> (&str, Vec<&'static str>) {
+        let (id, fields): (&str, &[&'static str]) = match self {
+            DriveEvent::FolderCreated { folder } => (&folder.id, ALL_FIELDS),
+            DriveEvent::FileAdded { file } => (&file.id, ALL_FIELDS),
+            DriveEvent::FolderRenamed { id, .. } | DriveEvent::FileRenamed { id, .. } => {
+                (id, &["name"])
+            }
+            DriveEvent::FolderUpdated { id, patch, .. } => return (id, patch.fields()),
+            DriveEvent::Moved { id, .. } => (id, &["parent"]),
+            DriveEvent::FileRelocated { id, .. } => (id, &["location"]),
+            DriveEvent::ReplicasChanged { id, .. } => (id, &["replicas"]),
+            DriveEvent::Trashed { id, .. } | DriveEvent::Restored { id, .. } => (id, &["trash"]),
+            DriveEvent::Starred { id, .. } => (id, &["star"]),
+            DriveEvent::FolderDeleted { id } | DriveEvent::FileDeleted { id } => (id, &["deleted"]),
+        };
+        (id, fields.to_vec())
+    }
+}
+
+/// Lamport timestamp. Ties on the clock are broken by device id, so every
+/// replica orders any two operations the same way.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct OpStamp {
+    pub clock: u64,
+    pub device_id: String,
+}
+
+/// A recorded change, stamped by the device that made it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Operation {
+    pub id: String,
+    pub device_id: String,
+    pub clock: u64,
+    pub event: DriveEvent,
+}
+
+impl Operation {
+    pub fn stamp(&self) -> OpStamp {
+        OpStamp {
+            clock: self.clock,
+            device_id: self.device_id.clone(),
+        }
+    }
+}
+
+/// A file whose message is being deleted from Telegram. The entry stays in the
+/// store (hidden from listings) until Telegram confirms, so a failed delete can
+/// be retried instead of leaving the message behind.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PendingDeletion {
+    pub file_id: String,
+    pub name: String,
+    pub peer: StoragePeer,
+    pub message_id: i32,
+    pub queued_at: i64,
+    // Copies that go with the primary message
+    #[serde(default)]
+    pub replicas: Vec<Replica>,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct MergeItem {
+    pub id: String,
+    pub name: String,
+    pub is_folder: bool,
+}
+
+/// What a merge restore would do. Only `added` and `updated` change anything;
+/// local-only items are always kept.
+#[derive(Debug, Serialize, Default)]
+pub struct MergePreview {
+    pub added: Vec<MergeItem>,
+    pub updated: Vec<MergeItem>,
+    pub kept_local: usize,
+    // Backup entries left out because their message no longer exists
+    pub dropped: Vec<MergeItem>,
+}
+
+/// The backup items that win a merge, plus the preview describing them.
+pub struct MergePlan {
+    pub folders: Vec<Folder>,
+    pub files: Vec<FileMetadata>,
+    pub preview: MergePreview,
+}
+
+impl MergePlan {
+    /// Leaves out files whose Telegram message is gone.
+    pub fn drop_files(&mut self, ids: &[String]) {
+        let (gone, kept): (Vec<FileMetadata>, Vec<FileMetadata>) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(|f| ids.contains(&f.id));
+        self.files = kept;
+        for file in gone {
+            self.preview.added.retain(|i| i.id != file.id);
+            let updated = self.preview.updated.len();
+            self.preview.updated.retain(|i| i.id != file.id);
+            // The local entry it would have replaced stays after all
+            self.preview.kept_local += updated - self.preview.updated.len();
+            self.preview.dropped.push(MergeItem {
+                id: file.id,
+                name: file.name,
+                is_folder: false,
+            });
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct DataStore {
     folders: Vec<Folder>,
     files: Vec<FileMetadata>,
+    // Recorded changes not yet published (only while journaling is on)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    outbox: Vec<Operation>,
+    // Logical clock of this replica
+    #[serde(default)]
+    clock: u64,
+    // Newest stamp written to each "<item id>:<field>", used to resolve conflicts
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    versions: HashMap<String, OpStamp>,
+    // Permanent deletions waiting for Telegram to confirm
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pending_deletions: Vec<PendingDeletion>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pending_folders: Vec<String>,
+}
+
+// What a backup holds. Reads back as a DataStore with empty bookkeeping.
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    folders: &'a [Folder],
+    files: &'a [FileMetadata],
+}
+
+impl DataStore {
+    fn is_pending(&self, id: &str) -> bool {
+        self.pending_deletions.iter().any(|p| p.file_id == id)
+            || self.pending_folders.iter().any(|f| f == id)
+    }
+}
+
+/// A store written under a new key, waiting to replace the file.
+pub struct StagedRewrite<'a> {
+    _store: MutexGuard<'a, DataStore>,
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> Result<(), AppError> {
+    let mut file = std::fs::File::create(path).map_err(AppError::storage)?;
+    file.write_all(bytes).map_err(AppError::storage)?;
+    file.sync_all().map_err(AppError::storage)
+}
+
+impl StagedRewrite<'_> {
+    pub fn commit(self) -> Result<(), AppError> {
+        std::fs::rename(&self.tmp, &self.path).map_err(AppError::storage)
+    }
+}
+
+// An abandoned rewrite leaves the file as it was
+impl Drop for StagedRewrite<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.tmp);
+    }
 }
 
 pub struct Database {
     db_path: PathBuf,
     store: Mutex<DataStore>,
+    journaling: AtomicBool,
+    device_id: Mutex<String>,
+    // Local edits since startup, for "back up every N changes"
+    mutations: AtomicU64,
+    // Encrypted on disk and not unlocked yet: the store is empty and never saved
+    locked: AtomicBool,
+}
+
+// Decrypts with the unlocked key if the file is encrypted
+fn parse_store(bytes: &[u8]) -> Result<DataStore, AppError> {
+    let plain = if crypto::is_encrypted(bytes) {
+        crypto::vault()
+            .ok_or_else(|| AppError::Locked("Metadata is locked".to_string()))?
+            .decrypt(bytes)
+            .map_err(AppError::Storage)?
+    } else {
+        bytes.to_vec()
+    };
+    serde_json::from_slice(&plain).map_err(AppError::storage)
 }
 
 impl Database {
     pub fn new(app_dir: &str) -> Self {
         let db_path = std::path::Path::new(app_dir).join("metadata.json");
-        let store = if db_path.exists() {
-            let file = File::open(&db_path).unwrap();
-            let reader = BufReader::new(file);
-            serde_json::from_reader(reader).unwrap_or_default()
-        } else {
-            DataStore::default()
+        let (store, locked) = match std::fs::read(&db_path) {
+            Ok(bytes) if crypto::is_encrypted(&bytes) => match parse_store(&bytes) {
+                Ok(store) => (store, false),
+                Err(e) => {
+                    eprintln!("Metadata at {:?} stays locked: {}", db_path, e);
+                    (DataStore::default(), true)
+                }
+            },
+            Ok(bytes) => (serde_json::from_slice(&bytes).unwrap_or_default(), false),
+            Err(_) => (DataStore::default(), false),
         };
 
         Database {
             db_path,
             store: Mutex::new(store),
+            journaling: AtomicBool::new(false),
+            device_id: Mutex::new(String::new()),
+            mutations: AtomicU64::new(0),
+            locked: AtomicBool::new(locked),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    /// Reads the file again now that a key is available. A plaintext file is
+    /// encrypted on the spot.
+    pub fn unlock(&self) -> Result<(), AppError> {
+        if let Ok(bytes) = std::fs::read(&self.db_path) {
+            let loaded = parse_store(&bytes)?;
+            *self.lock() = loaded;
         }
+        self.locked.store(false, Ordering::SeqCst);
+        self.save()
+    }
+
+    /// Writes the store again, e.g. under a new key.
+    pub fn rewrite(&self) -> Result<(), AppError> {
+        self.save()
+    }
+
+    /// Writes the store under `vault` next to the file, for a key change. The
+    /// store stays locked until the rewrite is committed or dropped.
+    pub fn stage_rewrite(&self, vault: &crypto::Vault) -> Result<StagedRewrite<'_>, AppError> {
+        if self.is_locked() {
+            return Err(AppError::Locked("Metadata is locked".to_string()));
+        }
+        let store = self.lock();
+        let bytes = serde_json::to_vec(&*store).map_err(AppError::storage)?;
+        let tmp = self.db_path.with_extension("json.tmp");
+        write_synced(&tmp, &vault.encrypt(&bytes))?;
+        Ok(StagedRewrite {
+            _store: store,
+            tmp,
+            path: self.db_path.clone(),
+        })
+    }
+
+    /// The tree as plain JSON, for backups. Sync bookkeeping (clock, versions,
+    /// outbox, pending deletions) belongs to this replica and stays out.
+    pub fn export_snapshot(&self) -> Result<Vec<u8>, AppError> {
+        let store = self.lock();
+        let snapshot = Snapshot {
+            folders: &store.folders,
+            files: &store.files,
+        };
+        serde_json::to_vec(&snapshot).map_err(AppError::storage)
+    }
+
+    /// Replaces the tree with a plain JSON snapshot, e.g. a restored backup. This
+    /// replica's clock and sync bookkeeping are kept.
+    pub fn replace_with_snapshot(&self, bytes: &[u8]) -> Result<(), AppError> {
+        let backup: DataStore = serde_json::from_slice(bytes)
+            .map_err(|e| format!("Backup is not valid metadata: {}", e))?;
+        let mut store = self.lock();
+        store.folders = backup.folders;
+        store.files = backup.files;
+        // Older backups carry a clock; it must never go backwards
+        store.clock = store.clock.max(backup.clock);
+        drop(store);
+        self.save()
     }
 
-    fn save(&self) {
-        let file = File::create(&self.db_path).unwrap();
-        let writer = BufWriter::new(file);
-        serde_json::to_writer(writer, &*self.store.lock().unwrap()).unwrap();
+    pub fn path(&self) -> &Path {
+        &self.db_path
+    }
+
+    /// When enabled, every mutation is stamped and queued as an `Operation` in the outbox.
+    pub fn set_journaling(&self, enabled: bool) {
+        self.journaling.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn set_device_id(&self, device_id: &str) {
+        *self
+            .device_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = device_id.to_string();
+    }
+
+    // Every local edit goes through here: it stamps the item's last_modified and
+    // journals the event
+    fn record(&self, store: &mut DataStore, event: DriveEvent) {
+        let now = chrono::Utc::now().timestamp();
+        let (id, _) = event.target();
+        if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
+            folder.last_modified = now;
+        } else if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
+            file.last_modified = now;
+        }
+        self.mutations.fetch_add(1, Ordering::SeqCst);
+        self.journal(store, event);
+    }
+
+    fn journal(&self, store: &mut DataStore, event: DriveEvent) {
+        if !self.journaling.load(Ordering::SeqCst) {
+            return;
+        }
+        store.clock += 1;
+        let op = Operation {
+            id: Uuid::new_v4().to_string(),
+            device_id: self
+                .device_id
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+            clock: store.clock,
+            event,
+        };
+        let stamp = op.stamp();
+        let (id, fields) = op.event.target();
+        for field in fields {
+            store
+                .versions
+                .insert(format!("{}:{}", id, field), stamp.clone());
+        }
+        store.outbox.push(op);
+    }
+
+    pub fn pending_operations(&self) -> Vec<Operation> {
+        self.lock().outbox.clone()
+    }
+
+    /// Drops the first `count` outbox entries once they have been published.
+    pub fn ack_operations(&self, count: usize) -> Result<(), AppError> {
+        let mut store = self.lock();
+        let count = count.min(store.outbox.len());
+        store.outbox.drain(..count);
+        drop(store);
+        self.save()
+    }
+
+    /// Queues the whole current tree as create operations, so a replica seeing this
+    /// store for the first time gets everything, not just later changes.
+    pub fn queue_snapshot(&self) -> Result<(), AppError> {
+        let mut store = self.lock();
+        let folders = store.folders.clone();
+        let files = store.files.clone();
+        for folder in folders {
+            self.journal(&mut store, DriveEvent::FolderCreated { folder });
+        }
+        for file in files {
+            self.journal(&mut store, DriveEvent::FileAdded { file });
+        }
+        drop(store);
+        self.save()
+    }
+
+    pub fn mutation_count(&self) -> u64 {
+        self.mutations.load(Ordering::SeqCst)
+    }
+
+    fn save(&self) -> Result<(), AppError> {
+        // An empty locked store must never overwrite the encrypted file
+        if self.is_locked() {
+            return Err(AppError::Locked("Metadata is locked".to_string()));
+        }
+        // Held until the rename, so two saves never share the tmp file
+        let store = self.lock();
+        let bytes = serde_json::to_vec(&*store).map_err(AppError::storage)?;
+        let bytes = match crypto::vault() {
+            Some(vault) => vault.encrypt(&bytes),
+            None => bytes,
+        };
+        // A torn write can't be decrypted, so the old file stays until the new one is complete
+        let tmp = self.db_path.with_extension("json.tmp");
+        write_synced(&tmp, &bytes)?;
+        std::fs::rename(&tmp, &self.db_path).map_err(AppError::storage)
+    }
+
+    // A panic elsewhere can't leave the store half-written (every change is made
+    // under the lock and saved after it), so a poisoned lock is still usable
+    fn lock(&self) -> MutexGuard<'_, DataStore> {
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
     // Helper to get a unique name (e.g. "Folder (1)")
@@ -142,18 +729,15 @@ impl Database {
         }
     }
 
-    pub fn create_folder(&self, name: &str, parent_id: Option<String>) -> String {
-        let mut store = self.store.lock().unwrap();
+    pub fn create_folder(&self, name: &str, parent_id: Option<String>) -> Result<String, AppError> {
+        let mut store = self.lock();
 
         // Ensure unique name
         let final_name = self.get_unique_name(&store, parent_id.as_ref(), name, true);
 
         let id = Uuid::new_v4().to_string();
         // timestamp
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+        let now = chrono::Utc::now().timestamp();
 
         let folder = Folder {
             id: id.clone(),
@@ -176,48 +760,106 @@ impl Database {
             last_modified: now,
         };
 
-        store.folders.push(folder);
+        store.folders.push(folder.clone());
+        self.record(&mut store, DriveEvent::FolderCreated { folder });
         drop(store);
-        self.save();
-        id
+        self.save()?;
+        Ok(id)
     }
 
     pub fn list_contents(&self, folder_id: Option<String>) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         let folders = store
             .folders
             .iter()
-            .filter(|f| f.parent_id == folder_id && !f.trashed)
+            .filter(|f| f.parent_id == folder_id && !f.trashed && !store.is_pending(&f.id))
             .cloned()
             .collect();
         let files = store
             .files
             .iter()
-            .filter(|f| f.folder_id == folder_id && !f.trashed)
+            .filter(|f| f.folder_id == folder_id && !f.trashed && !store.is_pending(&f.id))
             .cloned()
             .collect();
         (folders, files)
     }
 
     pub fn list_trash(&self) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         let folders = store
             .folders
             .iter()
-            .filter(|f| f.trashed)
+            .filter(|f| f.trashed && !store.is_pending(&f.id))
+            .cloned()
+            .collect();
+        let files = store
+            .files
+            .iter()
+            .filter(|f| f.trashed && !store.is_pending(&f.id))
             .cloned()
             .collect();
-        let files = store.files.iter().filter(|f| f.trashed).cloned().collect();
         (folders, files)
     }
 
     pub fn get_file(&self, id: &str) -> Option<FileMetadata> {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         store.files.iter().find(|f| f.id == id).cloned()
     }
 
+    pub fn find_file_by_message(&self, message_id: i32) -> Option<FileMetadata> {
+        let store = self.lock();
+        store
+            .files
+            .iter()
+            .find(|f| f.message_id == message_id)
+            .cloned()
+    }
+
+    /// Folder names from the root down to `folder_id`.
+    pub fn folder_path(&self, folder_id: Option<&str>) -> Vec<String> {
+        let store = self.lock();
+        let mut path = Vec::new();
+        let mut cursor = folder_id.map(|s| s.to_string());
+        while let Some(id) = cursor {
+            match store.folders.iter().find(|f| f.id == id) {
+                Some(folder) => {
+                    path.push(folder.name.clone());
+                    cursor = folder.parent_id.clone();
+                }
+                None => break,
+            }
+            // A corrupted store could contain a cycle
+            if path.len() > store.folders.len() {
+                break;
+            }
+        }
+        path.reverse();
+        path
+    }
+
+    /// Walks `path` from the root, creating any folder that does not exist yet.
+    /// Returns the id of the last one (None for an empty path, i.e. the root).
+    pub fn ensure_folder_path(&self, path: &[String]) -> Result<Option<String>, AppError> {
+        let mut parent: Option<String> = None;
+        for name in path {
+            let existing = {
+                let store = self.lock();
+                store
+                    .folders
+                    .iter()
+                    .find(|f| f.parent_id == parent && f.name == *name && !f.trashed)
+                    .map(|f| f.id.clone())
+            };
+            parent = Some(match existing {
+                Some(id) => id,
+                None => self.create_folder(name, parent.clone())?,
+            });
+        }
+        Ok(parent)
+    }
+
     pub fn lookup_folder_name(&self, id: &str) -> Option<String> {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         store
             .folders
             .iter()
@@ -225,6 +867,7 @@ impl Database {
             .map(|f| f.name.clone())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn add_file(
         &self,
         folder_id: Option<String>,
@@ -233,22 +876,14 @@ impl Database {
         mime_type: String,
         message_id: i32,
         thumbnail: Option<String>,
-    ) -> FileMetadata {
-        let mut store = self.store.lock().unwrap();
-
-        // Ensure unique name
-        let final_name = self.get_unique_name(&store, folder_id.as_ref(), &name, false);
+        peer: StoragePeer,
+    ) -> Result<FileMetadata, AppError> {
+        let now = chrono::Utc::now().timestamp();
 
-        let id = Uuid::new_v4().to_string();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
-        let file = FileMetadata {
-            id: id.clone(),
+        self.insert_file(FileMetadata {
+            id: Uuid::new_v4().to_string(),
             folder_id,
-            name: final_name,
+            name,
             size,
             mime_type,
             message_id,
@@ -257,21 +892,33 @@ impl Database {
             trashed_at: None,
             is_starred: false,
             thumbnail,
-        };
+            peer,
+            hash: None,
+            missing: false,
+            last_modified: 0,
+            replicas: Vec::new(),
+        })
+    }
+
+    /// Adds a fully built entry, e.g. one whose id was already written into its
+    /// Telegram caption. The name is still made unique within the folder and
+    /// `last_modified` is set to now.
+    pub fn insert_file(&self, mut file: FileMetadata) -> Result<FileMetadata, AppError> {
+        let mut store = self.lock();
+        file.name = self.get_unique_name(&store, file.folder_id.as_ref(), &file.name, false);
+        file.last_modified = chrono::Utc::now().timestamp();
 
         store.files.push(file.clone());
+        self.record(&mut store, DriveEvent::FileAdded { file: file.clone() });
         drop(store);
-        self.save();
-        file
+        self.save()?;
+        Ok(file)
     }
 
     // Soft delete
-    pub fn trash_item(&self, id: &str, is_folder: bool) {
-        let mut store = self.store.lock().unwrap();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+    pub fn trash_item(&self, id: &str, is_folder: bool) -> Result<(), AppError> {
+        let mut store = self.lock();
+        let now = chrono::Utc::now().timestamp();
 
         if is_folder {
             if let Some(f) = store.folders.iter_mut().find(|f| f.id == id) {
@@ -292,12 +939,20 @@ impl Database {
                 f.trashed_at = Some(now);
             }
         }
+        self.record(
+            &mut store,
+            DriveEvent::Trashed {
+                id: id.to_string(),
+                is_folder,
+                at: now,
+            },
+        );
         drop(store);
-        self.save();
+        self.save()
     }
 
-    pub fn restore_item(&self, id: &str, is_folder: bool) {
-        let mut store = self.store.lock().unwrap();
+    pub fn restore_item(&self, id: &str, is_folder: bool) -> Result<(), AppError> {
+        let mut store = self.lock();
         if is_folder {
             if let Some(f) = store.folders.iter_mut().find(|f| f.id == id) {
                 f.trashed = false;
@@ -309,25 +964,33 @@ impl Database {
                 f.trashed_at = None;
             }
         }
+        self.record(
+            &mut store,
+            DriveEvent::Restored {
+                id: id.to_string(),
+                is_folder,
+            },
+        );
         drop(store);
-        self.save();
+        self.save()
     }
 
     // Hard delete (Permanent)
-    pub fn delete_file(&self, id: &str) -> bool {
-        let mut store = self.store.lock().unwrap();
+    pub fn delete_file(&self, id: &str) -> Result<bool, AppError> {
+        let mut store = self.lock();
         let len_before = store.files.len();
         store.files.retain(|f| f.id != id);
         let deleted = store.files.len() < len_before;
         if deleted {
+            self.record(&mut store, DriveEvent::FileDeleted { id: id.to_string() });
             drop(store);
-            self.save();
+            self.save()?;
         }
-        deleted
+        Ok(deleted)
     }
 
-    pub fn delete_folder(&self, id: &str) -> Vec<FileMetadata> {
-        let mut store = self.store.lock().unwrap();
+    pub fn delete_folder(&self, id: &str) -> Result<Vec<FileMetadata>, AppError> {
+        let mut store = self.lock();
 
         // 1. Find all files in this folder (recursive TODO later, for now flat)
         let deleted_files: Vec<FileMetadata> = store
@@ -343,38 +1006,181 @@ impl Database {
         // 3. Remove folder
         store.folders.retain(|f| f.id != id);
 
+        self.record(&mut store, DriveEvent::FolderDeleted { id: id.to_string() });
         drop(store);
-        self.save();
+        self.save()?;
 
-        deleted_files
+        Ok(deleted_files)
     }
 
-    pub fn rename_file(&self, id: &str, new_name: &str) -> bool {
-        let mut store = self.store.lock().unwrap();
+    pub fn rename_file(&self, id: &str, new_name: &str) -> Result<bool, AppError> {
+        let mut store = self.lock();
         if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
             file.name = new_name.to_string();
+            self.record(
+                &mut store,
+                DriveEvent::FileRenamed {
+                    id: id.to_string(),
+                    name: new_name.to_string(),
+                },
+            );
             drop(store); // release lock before save
-            self.save();
-            true
+            self.save()?;
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
-    pub fn rename_folder(&self, id: &str, new_name: &str) -> bool {
-        let mut store = self.store.lock().unwrap();
+    // Points a file at a new message, e.g. after it was forwarded to another peer
+    pub fn relocate_file(
+        &self,
+        id: &str,
+        peer: StoragePeer,
+        message_id: i32,
+    ) -> Result<bool, AppError> {
+        let mut store = self.lock();
+        if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
+            file.peer = peer.clone();
+            file.message_id = message_id;
+            file.missing = false;
+            self.record(
+                &mut store,
+                DriveEvent::FileRelocated {
+                    id: id.to_string(),
+                    peer,
+                    message_id,
+                },
+            );
+            drop(store);
+            self.save()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub fn set_replicas(&self, id: &str, replicas: Vec<Replica>) -> Result<bool, AppError> {
+        let mut store = self.lock();
+        match store.files.iter_mut().find(|f| f.id == id) {
+            Some(file) if file.replicas != replicas => {
+                file.replicas = replicas.clone();
+                self.record(
+                    &mut store,
+                    DriveEvent::ReplicasChanged {
+                        id: id.to_string(),
+                        replicas,
+                    },
+                );
+                drop(store);
+                self.save()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn rename_folder(&self, id: &str, new_name: &str) -> Result<bool, AppError> {
+        let mut store = self.lock();
         if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
             folder.name = new_name.to_string();
+            self.record(
+                &mut store,
+                DriveEvent::FolderRenamed {
+                    id: id.to_string(),
+                    name: new_name.to_string(),
+                },
+            );
             drop(store);
-            self.save();
-            true
+            self.save()?;
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
+    // Moves a file or folder under a new parent (None = root). Refuses to move a
+    // folder into itself or one of its descendants.
+    pub fn move_item(
+        &self,
+        id: &str,
+        is_folder: bool,
+        parent_id: Option<String>,
+    ) -> Result<bool, AppError> {
+        let mut store = self.lock();
+
+        if is_folder {
+            let mut cursor = parent_id.clone();
+            while let Some(current) = cursor {
+                if current == id {
+                    return Ok(false);
+                }
+                cursor = store
+                    .folders
+                    .iter()
+                    .find(|f| f.id == current)
+                    .and_then(|f| f.parent_id.clone());
+            }
+        }
+
+        let current_name = if is_folder {
+            store
+                .folders
+                .iter()
+                .find(|f| f.id == id)
+                .map(|f| f.name.clone())
+        } else {
+            store
+                .files
+                .iter()
+                .find(|f| f.id == id)
+                .map(|f| f.name.clone())
+        };
+        let current_name = match current_name {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+        let final_name = self.get_unique_name(&store, parent_id.as_ref(), &current_name, is_folder);
+
+        if is_folder {
+            if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
+                folder.parent_id = parent_id.clone();
+                folder.name = final_name.clone();
+            }
+        } else if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
+            file.folder_id = parent_id.clone();
+            file.name = final_name.clone();
+        }
+
+        self.record(
+            &mut store,
+            DriveEvent::Moved {
+                id: id.to_string(),
+                is_folder,
+                parent_id,
+            },
+        );
+        if final_name != current_name {
+            let event = if is_folder {
+                DriveEvent::FolderRenamed {
+                    id: id.to_string(),
+                    name: final_name,
+                }
+            } else {
+                DriveEvent::FileRenamed {
+                    id: id.to_string(),
+                    name: final_name,
+                }
+            };
+            self.record(&mut store, event);
+        }
+        drop(store);
+        self.save()?;
+        Ok(true)
+    }
+
     pub fn get_folder_stats(&self, folder_id: &str) -> (i64, i32) {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         self.calculate_stats_recursive(&store, folder_id)
     }
 
@@ -412,171 +1218,230 @@ impl Database {
         (total_size, total_count)
     }
 
-    pub fn update_folder_metadata(
-        &self,
-        id: &str,
-        color: Option<String>,
-        icon: Option<String>,
-        gradient: Option<String>,
-        cover_image: Option<String>,
-        emoji: Option<String>,
-        pattern: Option<String>,
-        show_badges: Option<bool>,
-        tags: Option<Vec<String>>,
-        description: Option<String>,
-        // view_mode update logic or separate? Let's add it.
-        view_mode: Option<String>,
-    ) -> bool {
-        let mut store = self.store.lock().unwrap();
+    pub fn update_folder_metadata(&self, id: &str, patch: FolderPatch) -> Result<bool, AppError> {
+        let mut store = self.lock();
         if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
-            if let Some(c) = color {
-                folder.color = if c.is_empty() { None } else { Some(c) };
-            }
-            if let Some(i) = icon {
-                folder.icon = if i.is_empty() { None } else { Some(i) };
-            }
-            if let Some(g) = gradient {
-                folder.gradient = if g.is_empty() { None } else { Some(g) };
-            }
-            if let Some(c) = cover_image {
-                folder.cover_image = if c.is_empty() { None } else { Some(c) };
-            }
-            if let Some(e) = emoji {
-                folder.emoji = if e.is_empty() { None } else { Some(e) };
-            }
-            if let Some(p) = pattern {
-                folder.pattern = if p.is_empty() { None } else { Some(p) };
-            }
-            if let Some(s) = show_badges {
-                folder.show_badges = s;
-            }
-            if let Some(t) = tags {
-                folder.tags = Some(t);
-            }
-            if let Some(d) = description {
-                folder.description = Some(d);
-            }
-            if let Some(v) = view_mode {
-                folder.view_mode = Some(v);
-            }
-
+            patch.apply_to(folder);
             folder.last_modified = chrono::Utc::now().timestamp();
-
+            let last_modified = folder.last_modified;
+
+            self.record(
+                &mut store,
+                DriveEvent::FolderUpdated {
+                    id: id.to_string(),
+                    patch,
+                    last_modified,
+                },
+            );
             drop(store);
-            self.save();
-            true
+            self.save()?;
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
-    pub fn cleanup_trash(&self, days: i64) -> Vec<FileMetadata> {
-        let mut store = self.store.lock().unwrap();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
-        let limit = now - (days * 24 * 60 * 60);
-
-        // Collect files to be deleted (for telegram deletion)
-        let mut deleted_files = Vec::new();
+    /// First phase of a permanent delete: tombstones the file (or every file in the
+    /// folder) without touching the entries. `complete_deletions` removes them once
+    /// Telegram confirms. Returns everything now waiting.
+    pub fn queue_deletion(
+        &self,
+        id: &str,
+        is_folder: bool,
+    ) -> Result<Vec<PendingDeletion>, AppError> {
+        let mut store = self.lock();
+        let files: Vec<FileMetadata> = if is_folder {
+            // Flat, same as delete_folder
+            if !store.pending_folders.iter().any(|f| f == id) {
+                store.pending_folders.push(id.to_string());
+            }
+            store
+                .files
+                .iter()
+                .filter(|f| f.folder_id.as_deref() == Some(id))
+                .cloned()
+                .collect()
+        } else {
+            store.files.iter().filter(|f| f.id == id).cloned().collect()
+        };
+        Self::tombstone(&mut store, files);
+        self.finish_folders(&mut store);
+        let pending = store.pending_deletions.clone();
+        drop(store);
+        self.save()?;
+        Ok(pending)
+    }
 
-        // 1. Identify valid trash items to remove
-        // Note: If we remove a folder, we need to return its files too?
-        // This is getting complex for auto-cleanup.
-        // Let's just implement individual item cleanup for now.
+    /// Queues everything that has been in the trash longer than `days` (0 empties it).
+    pub fn queue_trash_cleanup(&self, days: i64) -> Result<Vec<PendingDeletion>, AppError> {
+        let mut store = self.lock();
+        let limit = chrono::Utc::now().timestamp() - (days * 24 * 60 * 60);
 
-        // Files older than limit
-        let files_to_remove: Vec<String> = store
-            .files
+        let folders: Vec<String> = store
+            .folders
             .iter()
             .filter(|f| f.trashed && f.trashed_at.unwrap_or(0) < limit)
             .map(|f| f.id.clone())
             .collect();
-
-        for id in &files_to_remove {
-            if let Some(f) = store.files.iter().find(|f| f.id == *id) {
-                deleted_files.push(f.clone());
-            }
-        }
-        store.files.retain(|f| !files_to_remove.contains(&f.id));
-
-        // Folders older than limit
-        let folders_to_remove: Vec<String> = store
-            .folders
+        // Children of an expired folder go with it, trashed or not
+        let files: Vec<FileMetadata> = store
+            .files
             .iter()
-            .filter(|f| f.trashed && f.trashed_at.unwrap_or(0) < limit)
-            .map(|f| f.id.clone())
+            .filter(|f| {
+                let expired = f.trashed && f.trashed_at.unwrap_or(0) < limit;
+                let in_folder = f.folder_id.as_ref().is_some_and(|p| folders.contains(p));
+                expired || in_folder
+            })
+            .cloned()
             .collect();
+        for id in folders {
+            if !store.pending_folders.contains(&id) {
+                store.pending_folders.push(id);
+            }
+        }
+        Self::tombstone(&mut store, files);
+        self.finish_folders(&mut store);
+        let pending = store.pending_deletions.clone();
+        drop(store);
+        self.save()?;
+        Ok(pending)
+    }
 
-        // If folder is removed, its children must be removed too
-        // But children might not be marked trashed?
-        // For now, let's assume if you trash a folder, we don't necessarily trash children in DB
-        // but they become inaccessible.
-        // When cleaning up a folder, we should delete its children.
+    fn tombstone(store: &mut DataStore, files: Vec<FileMetadata>) {
+        let now = chrono::Utc::now().timestamp();
+        for file in files {
+            if store.pending_deletions.iter().any(|p| p.file_id == file.id) {
+                continue;
+            }
+            store.pending_deletions.push(PendingDeletion {
+                file_id: file.id,
+                name: file.name,
+                peer: file.peer,
+                message_id: file.message_id,
+                queued_at: now,
+                replicas: file.replicas,
+                attempts: 0,
+                last_error: None,
+            });
+        }
+    }
 
-        for fid in folders_to_remove.clone() {
-            let children_files: Vec<FileMetadata> = store
+    // Folders go once none of their files are still waiting on Telegram
+    fn finish_folders(&self, store: &mut DataStore) {
+        let (done, waiting): (Vec<String>, Vec<String>) =
+            std::mem::take(&mut store.pending_folders)
+                .into_iter()
+                .partition(|folder_id| {
+                    !store.files.iter().any(|f| {
+                        f.folder_id.as_deref() == Some(folder_id.as_str())
+                            && store.pending_deletions.iter().any(|p| p.file_id == f.id)
+                    })
+                });
+        store.pending_folders = waiting;
+        for id in done {
+            store
                 .files
-                .iter()
-                .filter(|f| f.folder_id.as_deref() == Some(&fid))
-                .cloned()
-                .collect();
-            deleted_files.extend(children_files);
-            store.files.retain(|f| f.folder_id.as_deref() != Some(&fid));
+                .retain(|f| f.folder_id.as_deref() != Some(id.as_str()));
+            store.folders.retain(|f| f.id != id);
+            self.record(store, DriveEvent::FolderDeleted { id });
         }
+    }
 
-        store.folders.retain(|f| !folders_to_remove.contains(&f.id));
+    /// Whether the file or folder is tombstoned and waiting for Telegram.
+    pub fn is_pending_deletion(&self, id: &str) -> bool {
+        self.lock().is_pending(id)
+    }
 
-        drop(store);
-        self.save();
+    pub fn pending_deletions(&self) -> Vec<PendingDeletion> {
+        self.lock().pending_deletions.clone()
+    }
 
-        deleted_files
+    /// Second phase: Telegram confirmed these messages are gone, so the entries go too.
+    pub fn complete_deletions(&self, file_ids: &[String]) -> Result<(), AppError> {
+        let mut store = self.lock();
+        store
+            .pending_deletions
+            .retain(|p| !file_ids.contains(&p.file_id));
+        let removed: Vec<String> = store
+            .files
+            .iter()
+            .filter(|f| file_ids.contains(&f.id))
+            .map(|f| f.id.clone())
+            .collect();
+        store.files.retain(|f| !file_ids.contains(&f.id));
+        for id in removed {
+            self.record(&mut store, DriveEvent::FileDeleted { id });
+        }
+        self.finish_folders(&mut store);
+        drop(store);
+        self.save()
     }
 
-    pub fn toggle_star(&self, id: &str, is_folder: bool) -> bool {
-        let mut store = self.store.lock().unwrap();
-        let mut found = false;
+    pub fn fail_deletions(&self, file_ids: &[String], error: &str) -> Result<(), AppError> {
+        let mut store = self.lock();
+        for pending in store
+            .pending_deletions
+            .iter_mut()
+            .filter(|p| file_ids.contains(&p.file_id))
+        {
+            pending.attempts += 1;
+            pending.last_error = Some(error.to_string());
+        }
+        drop(store);
+        self.save()
+    }
+    pub fn toggle_star(&self, id: &str, is_folder: bool) -> Result<bool, AppError> {
+        let mut store = self.lock();
+        let mut starred = None;
 
         if is_folder {
             if let Some(f) = store.folders.iter_mut().find(|f| f.id == id) {
                 f.is_starred = !f.is_starred;
-                found = true;
+                starred = Some(f.is_starred);
             }
         } else {
             if let Some(f) = store.files.iter_mut().find(|f| f.id == id) {
                 f.is_starred = !f.is_starred;
-                found = true;
+                starred = Some(f.is_starred);
             }
         }
 
-        if found {
+        let found = starred.is_some();
+        if let Some(starred) = starred {
+            // Record the resulting value, not the toggle, so replays are idempotent
+            self.record(
+                &mut store,
+                DriveEvent::Starred {
+                    id: id.to_string(),
+                    is_folder,
+                    starred,
+                },
+            );
             drop(store);
-            self.save();
+            self.save()?;
         }
-        found
+        Ok(found)
     }
 
     pub fn get_starred(&self) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         let folders = store
             .folders
             .iter()
-            .filter(|f| f.is_starred && !f.trashed)
+            .filter(|f| f.is_starred && !f.trashed && !store.is_pending(&f.id))
             .cloned()
             .collect();
         let files = store
             .files
             .iter()
-            .filter(|f| f.is_starred && !f.trashed)
+            .filter(|f| f.is_starred && !f.trashed && !store.is_pending(&f.id))
             .cloned()
             .collect();
         (folders, files)
     }
 
     pub fn search_items(&self, query: &str) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         let query_lower = query.to_lowercase();
 
         let folders = store
@@ -584,6 +1449,7 @@ impl Database {
             .iter()
             .filter(|f| {
                 !f.trashed
+                    && !store.is_pending(&f.id)
                     && (f.name.to_lowercase().contains(&query_lower)
                         || f.tags.as_ref().map_or(false, |tags| {
                             tags.iter().any(|t| t.to_lowercase().contains(&query_lower))
@@ -595,15 +1461,44 @@ impl Database {
         let files = store
             .files
             .iter()
-            .filter(|f| !f.trashed && f.name.to_lowercase().contains(&query_lower))
+            .filter(|f| {
+                !f.trashed
+                    && !store.is_pending(&f.id)
+                    && f.name.to_lowercase().contains(&query_lower)
+            })
             .cloned()
             .collect();
 
         (folders, files)
     }
 
+    /// Flags files whose message disappeared. This is local knowledge (every device
+    /// sees the deletion itself), so nothing is journaled.
+    pub fn mark_missing(&self, ids: &[String]) -> Result<(), AppError> {
+        let mut store = self.lock();
+        for file in store.files.iter_mut().filter(|f| ids.contains(&f.id)) {
+            file.missing = true;
+        }
+        drop(store);
+        self.save()
+    }
+
+    // Corrections from reconciliation; every device reads the same size from
+    // Telegram, so this is not journaled
+    pub fn set_file_size(&self, id: &str, size: i64) -> Result<bool, AppError> {
+        let mut store = self.lock();
+        if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
+            file.size = size;
+            drop(store);
+            self.save()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     pub fn get_total_usage(&self) -> i64 {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         // Sum size of all NON-TRASHED files
         store
             .files
@@ -613,37 +1508,742 @@ impl Database {
             .sum()
     }
     pub fn get_all_files(&self) -> Vec<FileMetadata> {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         store.files.clone()
     }
 
     pub fn get_all_folders(&self) -> Vec<Folder> {
-        let store = self.store.lock().unwrap();
+        let store = self.lock();
         store.folders.clone()
     }
 
-    pub fn delete_files_by_ids(&self, ids: &[String]) {
+    pub fn delete_files_by_ids(&self, ids: &[String]) -> Result<(), AppError> {
         {
-            let mut store = self.store.lock().unwrap();
+            let mut store = self.lock();
             store.files.retain(|f| !ids.contains(&f.id));
+            for id in ids {
+                self.record(&mut store, DriveEvent::FileDeleted { id: id.clone() });
+            }
         }
-        self.save();
+        self.save()
     }
 
-    pub fn reload(&self) {
-        let mut store = self.store.lock().unwrap();
-        if self.db_path.exists() {
-            if let Ok(file) = File::open(&self.db_path) {
-                let reader = BufReader::new(file);
-                if let Ok(new_store) = serde_json::from_reader(reader) {
-                    *store = new_store;
-                    println!("Database reloaded from disk.");
-                } else {
-                    eprintln!("Failed to parse metadata.json during reload.");
+    /// Merges an operation made elsewhere. Each field keeps the value with the newest
+    /// stamp and deletions are final, so replicas converge whatever order operations
+    /// arrive in. Nothing is journaled, so merging never echoes an operation back out.
+    /// Returns false if the operation was already superseded.
+    pub fn merge_operation(&self, op: &Operation) -> Result<bool, AppError> {
+        let mut store = self.lock();
+        store.clock = store.clock.max(op.clock);
+
+        let stamp = op.stamp();
+        let (id, fields) = op.event.target();
+        if store.versions.contains_key(&format!("{}:deleted", id)) {
+            return Ok(false);
+        }
+        let newer: Vec<&str> = fields
+            .iter()
+            .copied()
+            .filter(|field| {
+                let version = store.versions.get(&format!("{}:{}", id, field));
+                // Stores from before per-field meta versions kept one "meta" stamp
+                let legacy = field
+                    .strip_prefix("meta.")
+                    .and_then(|_| store.versions.get(&format!("{}:meta", id)));
+                version.max(legacy).is_none_or(|v| *v < stamp)
+            })
+            .collect();
+        if newer.is_empty() {
+            return Ok(false);
+        }
+
+        match &op.event {
+            DriveEvent::FolderCreated { folder } => {
+                match store.folders.iter_mut().find(|f| f.id == folder.id) {
+                    Some(existing) => merge_folder_fields(existing, folder, &newer),
+                    None => store.folders.push(folder.clone()),
                 }
-            } else {
+            }
+            DriveEvent::FileAdded { file } => {
+                match store.files.iter_mut().find(|f| f.id == file.id) {
+                    Some(existing) => merge_file_fields(existing, file, &newer),
+                    None => store.files.push(file.clone()),
+                }
+            }
+            event => apply_event(&mut store, event, &newer),
+        }
+
+        for field in newer {
+            store
+                .versions
+                .insert(format!("{}:{}", id, field), stamp.clone());
+        }
+        drop(store);
+        self.save()?;
+        Ok(true)
+    }
+
+    // Thumbnails are not part of shared events (too large for a message), so
+    // members fill them in locally after applying a FileAdded.
+    pub fn set_thumbnail(&self, id: &str, thumbnail: Option<String>) -> Result<(), AppError> {
+        let mut store = self.lock();
+        if let Some(f) = store.files.iter_mut().find(|f| f.id == id) {
+            f.thumbnail = thumbnail;
+            drop(store);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Compares a backup with the current store, item by item. Items only in the
+    /// backup are added, items in both take the side with the newer
+    /// `last_modified`, and items only here stay as they are.
+    pub fn plan_merge(&self, bytes: &[u8]) -> Result<MergePlan, AppError> {
+        let backup: DataStore = serde_json::from_slice(bytes)
+            .map_err(|e| format!("Backup is not valid metadata: {}", e))?;
+        let store = self.lock();
+        let mut plan = MergePlan {
+            folders: Vec::new(),
+            files: Vec::new(),
+            preview: MergePreview::default(),
+        };
+
+        for folder in backup.folders {
+            let item = MergeItem {
+                id: folder.id.clone(),
+                name: folder.name.clone(),
+                is_folder: true,
+            };
+            match store.folders.iter().find(|f| f.id == folder.id) {
+                None if store.is_pending(&folder.id) => continue,
+                None => plan.preview.added.push(item),
+                Some(local) if folder.last_modified > local.last_modified => {
+                    plan.preview.updated.push(item)
+                }
+                Some(_) => continue,
+            }
+            plan.folders.push(folder);
+        }
+        for file in backup.files {
+            let item = MergeItem {
+                id: file.id.clone(),
+                name: file.name.clone(),
+                is_folder: false,
+            };
+            match store.files.iter().find(|f| f.id == file.id) {
+                None if store.is_pending(&file.id) => continue,
+                None => plan.preview.added.push(item),
+                Some(local) if file.last_modified > local.last_modified => {
+                    plan.preview.updated.push(item)
+                }
+                Some(_) => continue,
+            }
+            plan.files.push(file);
+        }
+
+        plan.preview.kept_local =
+            store.folders.len() + store.files.len() - plan.preview.updated.len();
+        Ok(plan)
+    }
+
+    /// Writes the winning backup items into the store. Each one is journaled as a
+    /// create, which other devices merge field by field.
+    pub fn apply_merge(&self, plan: MergePlan) -> Result<(), AppError> {
+        let mut store = self.lock();
+        for folder in plan.folders {
+            match store.folders.iter_mut().find(|f| f.id == folder.id) {
+                Some(local) => *local = folder.clone(),
+                None => store.folders.push(folder.clone()),
+            }
+            self.record(&mut store, DriveEvent::FolderCreated { folder });
+        }
+        for file in plan.files {
+            match store.files.iter_mut().find(|f| f.id == file.id) {
+                Some(local) => *local = file.clone(),
+                None => store.files.push(file.clone()),
+            }
+            self.record(&mut store, DriveEvent::FileAdded { file });
+        }
+        drop(store);
+        self.save()
+    }
+
+    /// Checks that `bytes` is a metadata file this version can load, e.g. a downloaded
+    /// backup. Returns its file and folder counts.
+    pub fn validate_snapshot(bytes: &[u8]) -> Result<(usize, usize), AppError> {
+        let store: DataStore = serde_json::from_slice(bytes)
+            .map_err(|e| format!("Backup is not valid metadata: {}", e))?;
+        Ok((store.files.len(), store.folders.len()))
+    }
+
+    pub fn counts(&self) -> (usize, usize) {
+        let store = self.lock();
+        (store.files.len(), store.folders.len())
+    }
+
+    pub fn reload(&self) {
+        let bytes = match std::fs::read(&self.db_path) {
+            Ok(bytes) => bytes,
+            Err(_) => {
                 eprintln!("Failed to open metadata.json during reload.");
+                return;
+            }
+        };
+        match parse_store(&bytes) {
+            Ok(new_store) => {
+                *self.lock() = new_store;
+                println!("Database reloaded from disk.");
+            }
+            Err(e) => eprintln!("Failed to parse metadata.json during reload: {}", e),
+        }
+    }
+}
+
+// Field-level changes, limited to the `fields` that won. Creates go through the
+// merge helpers below instead.
+fn apply_event(store: &mut DataStore, event: &DriveEvent, fields: &[&str]) {
+    match event {
+        DriveEvent::FolderRenamed { id, name } => {
+            if let Some(f) = store.folders.iter_mut().find(|f| f.id == *id) {
+                f.name = name.clone();
+            }
+        }
+        DriveEvent::FolderUpdated {
+            id,
+            patch,
+            last_modified,
+        } => {
+            if let Some(f) = store.folders.iter_mut().find(|f| f.id == *id) {
+                patch.only(fields).apply_to(f);
+                f.last_modified = f.last_modified.max(*last_modified);
+            }
+        }
+        DriveEvent::Moved {
+            id,
+            is_folder,
+            parent_id,
+        } => {
+            if *is_folder {
+                if let Some(f) = store.folders.iter_mut().find(|f| f.id == *id) {
+                    f.parent_id = parent_id.clone();
+                }
+            } else if let Some(f) = store.files.iter_mut().find(|f| f.id == *id) {
+                f.folder_id = parent_id.clone();
             }
         }
+        DriveEvent::FolderDeleted { id } => {
+            store.files.retain(|f| f.folder_id.as_deref() != Some(id));
+            store.folders.retain(|f| f.id != *id);
+        }
+        DriveEvent::FileRenamed { id, name } => {
+            if let Some(f) = store.files.iter_mut().find(|f| f.id == *id) {
+                f.name = name.clone();
+            }
+        }
+        DriveEvent::FileRelocated {
+            id,
+            peer,
+            message_id,
+        } => {
+            if let Some(f) = store.files.iter_mut().find(|f| f.id == *id) {
+                f.peer = peer.clone();
+                f.message_id = *message_id;
+            }
+        }
+        DriveEvent::ReplicasChanged { id, replicas } => {
+            if let Some(f) = store.files.iter_mut().find(|f| f.id == *id) {
+                f.replicas = replicas.clone();
+            }
+        }
+        DriveEvent::FileDeleted { id } => {
+            store.files.retain(|f| f.id != *id);
+        }
+        DriveEvent::Trashed { id, is_folder, at } => {
+            if *is_folder {
+                if let Some(f) = store.folders.iter_mut().find(|f| f.id == *id) {
+                    f.trashed = true;
+                    f.trashed_at = Some(*at);
+                }
+            } else if let Some(f) = store.files.iter_mut().find(|f| f.id == *id) {
+                f.trashed = true;
+                f.trashed_at = Some(*at);
+            }
+        }
+        DriveEvent::Restored { id, is_folder } => {
+            if *is_folder {
+                if let Some(f) = store.folders.iter_mut().find(|f| f.id == *id) {
+                    f.trashed = false;
+                    f.trashed_at = None;
+                }
+            } else if let Some(f) = store.files.iter_mut().find(|f| f.id == *id) {
+                f.trashed = false;
+                f.trashed_at = None;
+            }
+        }
+        DriveEvent::Starred {
+            id,
+            is_folder,
+            starred,
+        } => {
+            if *is_folder {
+                if let Some(f) = store.folders.iter_mut().find(|f| f.id == *id) {
+                    f.is_starred = *starred;
+                }
+            } else if let Some(f) = store.files.iter_mut().find(|f| f.id == *id) {
+                f.is_starred = *starred;
+            }
+        }
+        DriveEvent::FolderCreated { .. } | DriveEvent::FileAdded { .. } => {}
+    }
+}
+
+fn merge_folder_fields(target: &mut Folder, source: &Folder, fields: &[&str]) {
+    for field in fields {
+        match *field {
+            "name" => target.name = source.name.clone(),
+            "parent" => target.parent_id = source.parent_id.clone(),
+            "trash" => {
+                target.trashed = source.trashed;
+                target.trashed_at = source.trashed_at;
+            }
+            "star" => target.is_starred = source.is_starred,
+            "meta.color" => target.color = source.color.clone(),
+            "meta.icon" => target.icon = source.icon.clone(),
+            "meta.gradient" => target.gradient = source.gradient.clone(),
+            "meta.cover_image" => target.cover_image = source.cover_image.clone(),
+            "meta.emoji" => target.emoji = source.emoji.clone(),
+            "meta.pattern" => target.pattern = source.pattern.clone(),
+            "meta.show_badges" => target.show_badges = source.show_badges,
+            "meta.tags" => target.tags = source.tags.clone(),
+            "meta.description" => target.description = source.description.clone(),
+            "meta.view_mode" => target.view_mode = source.view_mode.clone(),
+            _ => {}
+        }
+    }
+    // Highest wins, so it doesn't depend on arrival order either
+    target.last_modified = target.last_modified.max(source.last_modified);
+}
+
+fn merge_file_fields(target: &mut FileMetadata, source: &FileMetadata, fields: &[&str]) {
+    for field in fields {
+        match *field {
+            "name" => target.name = source.name.clone(),
+            "parent" => target.folder_id = source.folder_id.clone(),
+            "trash" => {
+                target.trashed = source.trashed;
+                target.trashed_at = source.trashed_at;
+            }
+            "star" => target.is_starred = source.is_starred,
+            "location" => {
+                target.peer = source.peer.clone();
+                target.message_id = source.message_id;
+            }
+            "replicas" => target.replicas = source.replicas.clone(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Empty store in its own temp dir
+    fn temp_db() -> Database {
+        let dir = std::env::temp_dir().join(format!("paperfold-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Database::new(dir.to_str().unwrap())
+    }
+
+    fn folder(id: &str, name: &str) -> Folder {
+        Folder {
+            id: id.to_string(),
+            parent_id: None,
+            name: name.to_string(),
+            created_at: 0,
+            trashed: false,
+            trashed_at: None,
+            is_starred: false,
+            color: None,
+            icon: None,
+            gradient: None,
+            cover_image: None,
+            emoji: None,
+            pattern: None,
+            show_badges: false,
+            tags: None,
+            description: None,
+            view_mode: None,
+            last_modified: 0,
+        }
+    }
+
+    fn op(device_id: &str, clock: u64, event: DriveEvent) -> Operation {
+        Operation {
+            id: Uuid::new_v4().to_string(),
+            device_id: device_id.to_string(),
+            clock,
+            event,
+        }
+    }
+
+    fn rename(device_id: &str, clock: u64, name: &str) -> Operation {
+        op(
+            device_id,
+            clock,
+            DriveEvent::FolderRenamed {
+                id: "f".to_string(),
+                name: name.to_string(),
+            },
+        )
+    }
+
+    fn created(db: &Database) {
+        let create = op(
+            "a",
+            1,
+            DriveEvent::FolderCreated {
+                folder: folder("f", "start"),
+            },
+        );
+        assert!(db.merge_operation(&create).unwrap());
+    }
+
+    fn name_of(db: &Database) -> String {
+        db.get_all_folders()[0].name.clone()
+    }
+
+    #[test]
+    fn stamps_order_by_clock_then_device() {
+        let stamp = |clock, device: &str| OpStamp {
+            clock,
+            device_id: device.to_string(),
+        };
+        assert!(stamp(1, "z") < stamp(2, "a"));
+        assert!(stamp(2, "a") < stamp(2, "b"));
+        assert_eq!(stamp(3, "a"), stamp(3, "a"));
+    }
+
+    #[test]
+    fn newer_rename_wins_in_either_order() {
+        for ops in [
+            [rename("a", 2, "old"), rename("b", 5, "new")],
+            [rename("b", 5, "new"), rename("a", 2, "old")],
+        ] {
+            let db = temp_db();
+            created(&db);
+            for op in &ops {
+                db.merge_operation(op).unwrap();
+            }
+            assert_eq!(name_of(&db), "new");
+        }
+    }
+
+    #[test]
+    fn superseded_operation_is_reported() {
+        let db = temp_db();
+        created(&db);
+        assert!(db.merge_operation(&rename("b", 5, "new")).unwrap());
+        assert!(!db.merge_operation(&rename("a", 2, "old")).unwrap());
+        // The same operation twice changes nothing the second time
+        assert!(!db.merge_operation(&rename("b", 5, "new")).unwrap());
+    }
+
+    #[test]
+    fn clock_ties_break_on_device_id() {
+        for ops in [
+            [rename("a", 4, "from a"), rename("b", 4, "from b")],
+            [rename("b", 4, "from b"), rename("a", 4, "from a")],
+        ] {
+            let db = temp_db();
+            created(&db);
+            for op in &ops {
+                db.merge_operation(op).unwrap();
+            }
+            assert_eq!(name_of(&db), "from b");
+        }
+    }
+
+    #[test]
+    fn fields_are_versioned_separately() {
+        let db = temp_db();
+        created(&db);
+        db.merge_operation(&rename("a", 5, "renamed")).unwrap();
+        let star = op(
+            "b",
+            3,
+            DriveEvent::Starred {
+                id: "f".to_string(),
+                is_folder: true,
+                starred: true,
+            },
+        );
+        // Older than the rename, but the star field was never written after it
+        assert!(db.merge_operation(&star).unwrap());
+        let merged = &db.get_all_folders()[0];
+        assert_eq!(merged.name, "renamed");
+        assert!(merged.is_starred);
+    }
+
+    #[test]
+    fn stale_create_only_fills_older_fields() {
+        let db = temp_db();
+        created(&db);
+        db.merge_operation(&rename("a", 5, "renamed")).unwrap();
+        let mut stale = folder("f", "stale");
+        stale.is_starred = true;
+        let recreate = op("b", 3, DriveEvent::FolderCreated { folder: stale });
+        assert!(db.merge_operation(&recreate).unwrap());
+        let merged = &db.get_all_folders()[0];
+        assert_eq!(merged.name, "renamed");
+        assert!(merged.is_starred);
+    }
+
+    fn update(device_id: &str, clock: u64, patch: FolderPatch) -> Operation {
+        op(
+            device_id,
+            clock,
+            DriveEvent::FolderUpdated {
+                id: "f".to_string(),
+                patch,
+                last_modified: clock as i64,
+            },
+        )
+    }
+
+    #[test]
+    fn partial_updates_merge_in_either_order() {
+        let color = update(
+            "a",
+            5,
+            FolderPatch {
+                color: Some("red".to_string()),
+                ..Default::default()
+            },
+        );
+        let icon = update(
+            "b",
+            3,
+            FolderPatch {
+                icon: Some("star".to_string()),
+                ..Default::default()
+            },
+        );
+        for ops in [[color.clone(), icon.clone()], [icon, color]] {
+            let db = temp_db();
+            created(&db);
+            for op in &ops {
+                assert!(db.merge_operation(op).unwrap());
+            }
+            let merged = &db.get_all_folders()[0];
+            assert_eq!(merged.color.as_deref(), Some("red"));
+            assert_eq!(merged.icon.as_deref(), Some("star"));
+            assert_eq!(merged.last_modified, 5);
+        }
+    }
+
+    #[test]
+    fn older_update_loses_only_overlapping_fields() {
+        let newer = update(
+            "a",
+            5,
+            FolderPatch {
+                color: Some("red".to_string()),
+                ..Default::default()
+            },
+        );
+        let older = update(
+            "b",
+            3,
+            FolderPatch {
+                color: Some("blue".to_string()),
+                emoji: Some("x".to_string()),
+                ..Default::default()
+            },
+        );
+        for ops in [[newer.clone(), older.clone()], [older, newer]] {
+            let db = temp_db();
+            created(&db);
+            for op in &ops {
+                db.merge_operation(op).unwrap();
+            }
+            let merged = &db.get_all_folders()[0];
+            assert_eq!(merged.color.as_deref(), Some("red"));
+            assert_eq!(merged.emoji.as_deref(), Some("x"));
+        }
+    }
+
+    #[test]
+    fn deletion_is_final() {
+        let db = temp_db();
+        created(&db);
+        let delete = op(
+            "a",
+            2,
+            DriveEvent::FolderDeleted {
+                id: "f".to_string(),
+            },
+        );
+        assert!(db.merge_operation(&delete).unwrap());
+        assert!(!db.merge_operation(&rename("b", 9, "too late")).unwrap());
+        assert!(db.get_all_folders().is_empty());
+    }
+
+    #[test]
+    fn merging_advances_the_clock() {
+        let db = temp_db();
+        created(&db);
+        db.merge_operation(&rename("b", 41, "new")).unwrap();
+        assert_eq!(db.lock().clock, 41);
+    }
+
+    fn file(id: &str, last_modified: i64) -> FileMetadata {
+        FileMetadata {
+            id: id.to_string(),
+            folder_id: None,
+            name: format!("{}.txt", id),
+            size: 1,
+            mime_type: "text/plain".to_string(),
+            message_id: 1,
+            created_at: 0,
+            trashed: false,
+            trashed_at: None,
+            is_starred: false,
+            thumbnail: None,
+            peer: StoragePeer::SavedMessages,
+            hash: None,
+            missing: false,
+            last_modified,
+            replicas: Vec::new(),
+        }
+    }
+
+    fn backup(folders: Vec<Folder>, files: Vec<FileMetadata>) -> Vec<u8> {
+        serde_json::to_vec(&DataStore {
+            folders,
+            files,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn ids(items: &[MergeItem]) -> Vec<&str> {
+        items.iter().map(|i| i.id.as_str()).collect()
+    }
+
+    #[test]
+    fn plan_adds_missing_and_takes_newer_items() {
+        let db = temp_db();
+        {
+            let mut store = db.lock();
+            store.files = vec![file("older", 10), file("newer", 10), file("local", 10)];
+            store.folders = vec![folder("d", "local name")];
+            store.folders[0].last_modified = 10;
+        }
+        let mut renamed = folder("d", "backup name");
+        renamed.last_modified = 5;
+        let bytes = backup(
+            vec![renamed, folder("e", "new folder")],
+            vec![file("older", 5), file("newer", 20), file("added", 1)],
+        );
+
+        let plan = db.plan_merge(&bytes).unwrap();
+        assert_eq!(ids(&plan.preview.added), ["e", "added"]);
+        assert_eq!(ids(&plan.preview.updated), ["newer"]);
+        // Everything here except the one the backup replaces
+        assert_eq!(plan.preview.kept_local, 3);
+        let files: Vec<&str> = plan.files.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(files, ["newer", "added"]);
+        assert_eq!(plan.folders.len(), 1);
+    }
+
+    #[test]
+    fn plan_skips_items_being_deleted() {
+        let db = temp_db();
+        db.lock().pending_deletions.push(PendingDeletion {
+            file_id: "gone".to_string(),
+            name: "gone.txt".to_string(),
+            peer: StoragePeer::SavedMessages,
+            message_id: 1,
+            queued_at: 0,
+            replicas: Vec::new(),
+            attempts: 0,
+            last_error: None,
+        });
+        db.lock().pending_folders.push("d".to_string());
+
+        let plan = db
+            .plan_merge(&backup(vec![folder("d", "x")], vec![file("gone", 1)]))
+            .unwrap();
+        assert!(plan.preview.added.is_empty());
+        assert!(plan.files.is_empty() && plan.folders.is_empty());
+    }
+
+    #[test]
+    fn snapshot_restore_keeps_local_bookkeeping() {
+        let db = temp_db();
+        created(&db);
+        db.merge_operation(&rename("b", 41, "new")).unwrap();
+        let snapshot = db.export_snapshot().unwrap();
+        let exported: serde_json::Value = serde_json::from_slice(&snapshot).unwrap();
+        assert!(exported.get("clock").is_none() && exported.get("versions").is_none());
+
+        db.merge_operation(&rename("b", 60, "newer")).unwrap();
+        db.replace_with_snapshot(&snapshot).unwrap();
+        assert_eq!(name_of(&db), "new");
+        let store = db.lock();
+        assert_eq!(store.clock, 60);
+        assert!(store.versions.contains_key("f:name"));
+    }
+
+    #[test]
+    fn plan_rejects_invalid_backup() {
+        assert!(temp_db().plan_merge(b"not json").is_err());
+    }
+
+    #[test]
+    fn dropped_files_leave_the_plan() {
+        let db = temp_db();
+        db.lock().files = vec![file("updated", 1)];
+        let mut plan = db
+            .plan_merge(&backup(
+                Vec::new(),
+                vec![file("updated", 2), file("added", 1)],
+            ))
+            .unwrap();
+        assert_eq!(plan.preview.kept_local, 0);
+        plan.drop_files(&["updated".to_string(), "added".to_string()]);
+        assert_eq!(plan.preview.kept_local, 1);
+        assert!(plan.files.is_empty());
+        assert!(plan.preview.added.is_empty() && plan.preview.updated.is_empty());
+        assert_eq!(ids(&plan.preview.dropped), ["updated", "added"]);
+    }
+
+    #[test]
+    fn starred_and_search_hide_pending_deletions() {
+        let db = temp_db();
+        {
+            let mut store = db.lock();
+            let mut starred = file("gone", 10);
+            starred.is_starred = true;
+            store.files = vec![starred, file("kept", 10)];
+        }
+        db.queue_deletion("gone", false).unwrap();
+
+        assert!(db.get_starred().1.is_empty());
+        let (_, found) = db.search_items(".txt");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, "kept");
+    }
+
+    #[test]
+    fn only_local_edits_count_as_mutations() {
+        let db = temp_db();
+        created(&db);
+        db.ack_operations(usize::MAX).unwrap();
+        assert_eq!(db.mutation_count(), 0);
+
+        db.create_folder("local", None).unwrap();
+        assert_eq!(db.mutation_count(), 1);
     }
 }
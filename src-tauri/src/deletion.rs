@@ -0,0 +1,131 @@
+use grammers_client::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::State;
+
+use crate::accounts::Account;
+use crate::db::{Database, PendingDeletion, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::{rpc, FloodWait};
+use crate::{storage, AppState};
+
+// Tries per batch within one run; whatever is left waits for the next run
+const ATTEMPTS: u32 = 3;
+
+#[derive(Serialize, Default)]
+pub struct DeletionRun {
+    pub deleted: usize,
+    pub pending: usize,
+}
+
+async fn delete_batch(client: &Client, peer: &StoragePeer, ids: &[i32]) -> Result<(), AppError> {
+    let chat = storage::resolve_peer(client, peer).await?;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        // Ids that are already gone are simply not counted, so any Ok means done
+        let err = match rpc!("messages.deleteMessages", client.delete_messages(chat, ids)) {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        // The gateway already sat out any FLOOD_WAIT it could, so a long one ends the run
+        if attempt >= ATTEMPTS || err.flood_wait_secs().is_some() {
+            return Err(err.into());
+        }
+        let wait = 1u64 << attempt;
+        eprintln!("Delete failed ({}), retrying in {}s", err, wait);
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+    }
+}
+
+/// Runs the queued deletions of one database against Telegram, 100 messages per
+/// request. A file is finalized once its primary and every replica are gone;
+/// otherwise it stays queued with the error.
+pub async fn process_pending(client: &Client, db: &Database) -> DeletionRun {
+    let pending = db.pending_deletions();
+    let mut by_peer: HashMap<StoragePeer, Vec<(String, i32)>> = HashMap::new();
+    for p in &pending {
+        by_peer
+            .entry(p.peer.clone())
+            .or_default()
+            .push((p.file_id.clone(), p.message_id));
+        for replica in &p.replicas {
+            by_peer
+                .entry(replica.peer.clone())
+                .or_default()
+                .push((p.file_id.clone(), replica.message_id));
+        }
+    }
+
+    let mut failed: HashMap<String, String> = HashMap::new();
+    for (peer, messages) in by_peer {
+        for chunk in messages.chunks(100) {
+            let ids: Vec<i32> = chunk.iter().map(|(_, message_id)| *message_id).collect();
+            if let Err(e) = delete_batch(client, &peer, &ids).await {
+                eprintln!("Failed to delete {} messages: {}", ids.len(), e);
+                for (file_id, _) in chunk {
+                    failed.insert(file_id.clone(), e.to_string());
+                }
+            }
+        }
+    }
+
+    let done: Vec<String> = pending
+        .iter()
+        .map(|p| p.file_id.clone())
+        .filter(|id| !failed.contains_key(id))
+        .collect();
+    if !done.is_empty() {
+        if let Err(e) = db.complete_deletions(&done) {
+            eprintln!("Deleted messages but could not save: {}", e);
+        }
+    }
+    for (file_id, error) in &failed {
+        if let Err(e) = db.fail_deletions(std::slice::from_ref(file_id), error) {
+            eprintln!("Failed to record deletion error: {}", e);
+        }
+    }
+    DeletionRun {
+        deleted: done.len(),
+        pending: failed.len(),
+    }
+}
+
+/// Personal drive plus every shared drive of the account.
+pub async fn process_account(client: &Client, account: &Account) -> DeletionRun {
+    let mut run = process_pending(client, &account.personal_db).await;
+    for info in account.shared.list() {
+        if let Some(drive) = account.shared.get(&info.id) {
+            let drive_run = process_pending(client, &drive.db).await;
+            run.deleted += drive_run.deleted;
+            run.pending += drive_run.pending;
+        }
+    }
+    if run.deleted > 0 || run.pending > 0 {
+        println!(
+            "Deletions: {} confirmed, {} still pending",
+            run.deleted, run.pending
+        );
+    }
+    run
+}
+
+#[tauri::command]
+pub(crate) async fn list_pending_deletions(
+    state: State<'_, AppState>,
+) -> Result<Vec<PendingDeletion>, AppError> {
+    let account = state.accounts.active();
+    Ok(account.db().pending_deletions())
+}
+
+#[tauri::command]
+pub(crate) async fn retry_pending_deletions(
+    state: State<'_, AppState>,
+) -> Result<DeletionRun, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+    Ok(process_account(&client, &account).await)
+}
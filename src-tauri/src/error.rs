@@ -0,0 +1,193 @@
+use grammers_client::client::auth::AuthorizationError;
+use grammers_client::InvocationError;
+use serde::Serialize;
+use std::fmt;
+
+/// Error returned by every command. It reaches the frontend as
+/// `{ code, message, ...fields }`, so the UI can branch on `code` instead of
+/// matching on message text.
+#[derive(Debug, Clone)]
+pub enum AppError {
+    /// No Telegram client for the active account yet
+    NotLoggedIn,
+    /// The code was accepted but the account has two-step verification
+    PasswordRequired,
+    /// Telegram asked us to slow down
+    FloodWait {
+        retry_after_secs: u64,
+    },
+    /// Any other error reported by Telegram
+    Telegram(String),
+    /// Telegram cannot be reached right now
+    Offline(String),
+    /// The app lock or the metadata passphrase has not been entered
+    Locked(String),
+    NotFound {
+        file_id: Option<String>,
+    },
+    /// Part of an upload or download failed
+    Transfer {
+        file_id: Option<String>,
+        part: Option<usize>,
+        message: String,
+    },
+    /// Reading or writing metadata on disk failed
+    Storage(String),
+    Other(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotLoggedIn => "NOT_LOGGED_IN",
+            AppError::PasswordRequired => "PASSWORD_REQUIRED",
+            AppError::FloodWait { .. } => "FLOOD_WAIT",
+            AppError::Telegram(_) => "TELEGRAM",
+            AppError::Offline(_) => "OFFLINE",
+            AppError::Locked(_) => "LOCKED",
+            AppError::NotFound { .. } => "NOT_FOUND",
+            AppError::Transfer { .. } => "TRANSFER_FAILED",
+            AppError::Storage(_) => "STORAGE",
+            AppError::Other(_) => "OTHER",
+        }
+    }
+
+    pub fn storage(e: impl fmt::Display) -> Self {
+        AppError::Storage(e.to_string())
+    }
+
+    pub fn offline() -> Self {
+        AppError::Offline("No connection to Telegram".to_string())
+    }
+
+    pub fn not_found(file_id: &str) -> Self {
+        AppError::NotFound {
+            file_id: Some(file_id.to_string()),
+        }
+    }
+
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            AppError::FloodWait { retry_after_secs } => Some(*retry_after_secs),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotLoggedIn => f.write_str("Not logged in"),
+            AppError::PasswordRequired => f.write_str("Two-step verification password required"),
+            AppError::FloodWait {
+                retry_after_secs: 0,
+            } => f.write_str("Too many requests. Please wait a while before trying again."),
+            AppError::FloodWait { retry_after_secs } => {
+                let secs = *retry_after_secs;
+                write!(
+                    f,
+                    "Too many requests. Please wait {}h {}m {}s.",
+                    secs / 3600,
+                    (secs % 3600) / 60,
+                    secs % 60
+                )
+            }
+            AppError::Telegram(message) => write!(f, "Telegram error: {}", message),
+            AppError::Offline(message) | AppError::Locked(message) => f.write_str(message),
+            AppError::NotFound { file_id: Some(id) } => write!(f, "Item {} not found", id),
+            AppError::NotFound { file_id: None } => f.write_str("Item not found"),
+            AppError::Transfer {
+                part: Some(part),
+                message,
+                ..
+            } => write!(f, "Part {} failed: {}", part, message),
+            AppError::Transfer { message, .. } => write!(f, "Transfer failed: {}", message),
+            AppError::Storage(message) => write!(f, "Storage error: {}", message),
+            AppError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+// Flat shape sent to the frontend
+#[derive(Serialize)]
+struct Payload<'a> {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    part: Option<usize>,
+}
+
+impl Serialize for AppError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (file_id, part) = match self {
+            AppError::NotFound { file_id } => (file_id.as_deref(), None),
+            AppError::Transfer { file_id, part, .. } => (file_id.as_deref(), *part),
+            _ => (None, None),
+        };
+        Payload {
+            code: self.code(),
+            message: self.to_string(),
+            retry_after_secs: self.retry_after_secs(),
+            file_id,
+            part,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl From<String> for AppError {
+    fn from(message: String) -> Self {
+        AppError::Other(message)
+    }
+}
+
+impl From<&str> for AppError {
+    fn from(message: &str) -> Self {
+        AppError::Other(message.to_string())
+    }
+}
+
+/// True when the request never got an answer, as opposed to Telegram refusing it.
+pub fn is_network_error(e: &InvocationError) -> bool {
+    matches!(e, InvocationError::Dropped | InvocationError::Read(_))
+}
+
+impl From<InvocationError> for AppError {
+    fn from(e: InvocationError) -> Self {
+        match e {
+            InvocationError::Rpc(rpc) if rpc.name == "FLOOD_WAIT" => AppError::FloodWait {
+                retry_after_secs: rpc.value.unwrap_or(0) as u64,
+            },
+            e if is_network_error(&e) => AppError::offline(),
+            e => AppError::Telegram(e.to_string()),
+        }
+    }
+}
+
+impl From<AuthorizationError> for AppError {
+    fn from(e: AuthorizationError) -> Self {
+        match e {
+            AuthorizationError::Invoke(e) => e.into(),
+            e => AppError::Telegram(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        // Upload and download helpers wrap the InvocationError
+        match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<InvocationError>())
+        {
+            Some(inner) if is_network_error(inner) => AppError::offline(),
+            _ => AppError::Other(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use grammers_client::client::auth::AuthorizationError;
+use grammers_client::types::User;
+use grammers_client::{Client, InvocationError, SignInError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio::time::Instant;
+
+/// Runs a Telegram call through the gateway: `rpc!("messages.getMessages",
+/// client.get_messages_by_id(chat, &ids))`. The call expression is evaluated
+/// again on each retry, so it must not move anything.
+macro_rules! rpc {
+    ($method:expr, $call:expr) => {
+        loop {
+            let gateway = $crate::gateway::global();
+            gateway.ready($method).await;
+            match $call.await {
+                Err(e) => match $crate::gateway::FloodWait::flood_wait_secs(&e) {
+                    Some(secs) if gateway.park($method, secs) => continue,
+                    _ => break Err(e),
+                },
+                ok => break ok,
+            }
+        }
+    };
+}
+pub(crate) use rpc;
+
+// Longer waits are returned to the caller instead of parking it
+const MAX_PARK_SECS: u32 = 300;
+
+// How long a successful is_authorized check is trusted
+const AUTHORIZED_TTL: Duration = Duration::from_secs(300);
+
+// Minimum gap between two calls of the same method. Anything not listed only
+// waits out FLOOD_WAITs. Iterators are not listed: most `next()` calls are served
+// from their buffer and never reach Telegram.
+const MIN_INTERVAL_MS: &[(&str, u64)] = &[
+    ("messages.sendMessage", 1000),
+    ("messages.sendMedia", 1000),
+    ("messages.forwardMessages", 1000),
+    ("messages.deleteMessages", 500),
+    ("messages.getMessages", 200),
+    ("users.getUsers", 1000),
+    ("channels.createChannel", 5000),
+    ("messages.importChatInvite", 5000),
+    ("messages.exportChatInvite", 2000),
+    ("auth.sendCode", 5000),
+];
+
+static GATEWAY: OnceLock<Gateway> = OnceLock::new();
+
+/// The one place Telegram calls go through (see `rpc!`). It spaces out calls per
+/// method and, when Telegram answers FLOOD_WAIT, parks every caller of that method
+/// until the wait is over, so long jobs slow down instead of failing.
+pub struct Gateway {
+    app_handle: Mutex<Option<AppHandle>>,
+    // Earliest time the next call of each method may start
+    next_call: Mutex<HashMap<&'static str, Instant>>,
+    // get_me by the logged-in user id
+    me: Mutex<HashMap<i64, User>>,
+    // When each logged-in user id last passed is_authorized
+    authorized: Mutex<HashMap<i64, Instant>>,
+}
+
+pub fn global() -> &'static Gateway {
+    GATEWAY.get_or_init(|| Gateway {
+        app_handle: Mutex::new(None),
+        next_call: Mutex::new(HashMap::new()),
+        me: Mutex::new(HashMap::new()),
+        authorized: Mutex::new(HashMap::new()),
+    })
+}
+
+/// Lets the gateway tell the UI when it parks callers.
+pub fn init(app_handle: AppHandle) {
+    *global().app_handle.lock().unwrap() = Some(app_handle);
+}
+
+#[derive(Clone, Serialize)]
+struct RateLimited {
+    method: &'static str,
+    retry_after_secs: u32,
+}
+
+/// Errors that may carry a FLOOD_WAIT.
+pub trait FloodWait {
+    fn flood_wait_secs(&self) -> Option<u32>;
+}
+
+impl FloodWait for InvocationError {
+    fn flood_wait_secs(&self) -> Option<u32> {
+        match self {
+            InvocationError::Rpc(rpc) if rpc.name == "FLOOD_WAIT" => Some(rpc.value.unwrap_or(0)),
+            _ => None,
+        }
+    }
+}
+
+impl FloodWait for AuthorizationError {
+    fn flood_wait_secs(&self) -> Option<u32> {
+        match self {
+            AuthorizationError::Invoke(e) => e.flood_wait_secs(),
+            _ => None,
+        }
+    }
+}
+
+impl FloodWait for SignInError {
+    fn flood_wait_secs(&self) -> Option<u32> {
+        match self {
+            SignInError::Other(e) => e.flood_wait_secs(),
+            _ => None,
+        }
+    }
+}
+
+// The upload and download helpers wrap the InvocationError in an io::Error
+impl FloodWait for std::io::Error {
+    fn flood_wait_secs(&self) -> Option<u32> {
+        self.get_ref()?
+            .downcast_ref::<InvocationError>()?
+            .flood_wait_secs()
+    }
+}
+
+impl Gateway {
+    /// Waits until `method` may be called and books the slot.
+    pub async fn ready(&self, method: &'static str) {
+        let interval = MIN_INTERVAL_MS
+            .iter()
+            .find(|(m, _)| *m == method)
+            .map(|(_, ms)| Duration::from_millis(*ms))
+            .unwrap_or_default();
+        let start = {
+            let mut next_call = self.next_call.lock().unwrap();
+            let now = Instant::now();
+            let start = next_call.get(method).copied().unwrap_or(now).max(now);
+            next_call.insert(method, start + interval);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+
+    /// Parks `method` for a FLOOD_WAIT of `secs`. Returns false when the wait is too
+    /// long to sit out, in which case the caller gets the error.
+    pub fn park(&self, method: &'static str, secs: u32) -> bool {
+        if secs > MAX_PARK_SECS {
+            eprintln!("{} is flood-limited for {}s, giving up", method, secs);
+            return false;
+        }
+        println!("{} is flood-limited, waiting {}s", method, secs);
+        // Everyone else calling this method waits as well
+        let until = Instant::now() + Duration::from_secs(secs as u64 + 1);
+        {
+            let mut next_call = self.next_call.lock().unwrap();
+            let next = next_call.entry(method).or_insert(until);
+            *next = (*next).max(until);
+        }
+        if let Some(app_handle) = self.app_handle.lock().unwrap().as_ref() {
+            let _ = app_handle.emit(
+                "rate-limited",
+                RateLimited {
+                    method,
+                    retry_after_secs: secs,
+                },
+            );
+        }
+        true
+    }
+
+    /// `client.get_me()`, fetched once per logged-in user.
+    pub async fn get_me(&self, client: &Client) -> Result<User, InvocationError> {
+        let user_id = client.session().get_user().map(|u| u.id);
+        if let Some(me) = user_id.and_then(|id| self.me.lock().unwrap().get(&id).cloned()) {
+            return Ok(me);
+        }
+        let me = rpc!("users.getUsers", client.get_me())?;
+        self.me.lock().unwrap().insert(me.id(), me.clone());
+        Ok(me)
+    }
+
+    /// `client.is_authorized()`, trusted for a few minutes once it says yes.
+    /// The scheduler asks on every tick.
+    pub async fn is_authorized(&self, client: &Client) -> Result<bool, InvocationError> {
+        let user_id = client.session().get_user().map(|u| u.id);
+        if let Some(id) = user_id {
+            let authorized = self.authorized.lock().unwrap();
+            if authorized
+                .get(&id)
+                .is_some_and(|at| at.elapsed() < AUTHORIZED_TTL)
+            {
+                return Ok(true);
+            }
+        }
+        let authorized = rpc!("updates.getState", client.is_authorized())?;
+        if let (true, Some(id)) = (authorized, user_id) {
+            self.authorized.lock().unwrap().insert(id, Instant::now());
+        }
+        Ok(authorized)
+    }
+
+    /// Drops the cached user, e.g. on logout.
+    pub fn forget_me(&self, client: &Client) {
+        if let Some(user) = client.session().get_user() {
+            self.me.lock().unwrap().remove(&user.id);
+            self.authorized.lock().unwrap().remove(&user.id);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use grammers_client::types::Message;
+use grammers_client::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tauri::{AppHandle, State};
+
+use crate::accounts::Account;
+use crate::db::StoragePeer;
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::recovery::CAPTION_TAG;
+use crate::{importer, oplog, storage, AppState, BACKUP_TAG};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GcTarget {
+    pub peer: StoragePeer,
+    pub message_id: i32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OrphanMedia {
+    pub target: GcTarget,
+    pub name: String,
+    pub size: i64,
+    pub date: i64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BackupMessage {
+    pub target: GcTarget,
+    pub date: i64,
+}
+
+#[derive(Serialize)]
+pub struct GcReport {
+    pub orphans: Vec<OrphanMedia>,
+    pub orphan_bytes: i64,
+    pub backups_kept: Vec<BackupMessage>,
+    pub backups_to_prune: Vec<BackupMessage>,
+}
+
+// Uploads younger than this are never collected. Another device's upload can sit
+// in the peer before its FileAdded reaches this one.
+const MIN_ORPHAN_AGE_SECS: i64 = 24 * 60 * 60;
+
+// Only messages Paperfold wrote itself are ever collected
+fn is_app_upload(msg: &Message) -> bool {
+    msg.media().is_some() && msg.text().starts_with(CAPTION_TAG)
+}
+
+fn is_collectable_upload(msg: &Message) -> bool {
+    let age = chrono::Utc::now().timestamp() - msg.date().timestamp();
+    is_app_upload(msg) && age >= MIN_ORPHAN_AGE_SECS
+}
+
+fn is_backup(msg: &Message) -> bool {
+    msg.media().is_some() && msg.text().contains(BACKUP_TAG)
+}
+
+// Every peer personal uploads may be in: the current target, the storage channel
+// even while Saved Messages is the target, and the replica peer
+fn referenced(account: &Account) -> HashSet<GcTarget> {
+    account
+        .personal_db
+        .get_all_files()
+        .iter()
+        .flat_map(|f| f.locations())
+        .map(|l| GcTarget {
+            peer: l.peer,
+            message_id: l.message_id,
+        })
+        .collect()
+}
+
+// Merges what the account's other devices did first, so their uploads are
+// referenced before anything is called an orphan
+async fn catch_up(
+    app_handle: &AppHandle,
+    client: &Client,
+    account: &Account,
+) -> Result<(), AppError> {
+    if account.device_sync.lock().unwrap().enabled {
+        oplog::sync_devices(client, account, app_handle).await?;
+    }
+    Ok(())
+}
+
+async fn client_for(account: &Account) -> Result<Client, AppError> {
+    let client_guard = account.client.lock().await;
+    Ok(client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone())
+}
+
+/// Lists app uploads no entry (trashed ones included) points at, and metadata
+/// backups beyond the newest `keep_backups`. Nothing is deleted here.
+pub async fn scan(
+    app_handle: &AppHandle,
+    client: &Client,
+    account: &Account,
+    keep_backups: usize,
+) -> Result<GcReport, AppError> {
+    catch_up(app_handle, client, account).await?;
+    let referenced = referenced(account);
+
+    let mut orphans = Vec::new();
+    let mut backups = Vec::new();
+
+    for peer in account.personal_peers() {
+        let chat = storage::resolve_peer(client, &peer).await?;
+        let mut messages = client.iter_messages(chat);
+        while let Some(msg) = rpc!("messages.getHistory", messages.next())? {
+            let target = GcTarget {
+                peer: peer.clone(),
+                message_id: msg.id(),
+            };
+            if is_backup(&msg) {
+                backups.push(BackupMessage {
+                    target,
+                    date: msg.date().timestamp(),
+                });
+            } else if is_collectable_upload(&msg) && !referenced.contains(&target) {
+                if let Some((name, size, _)) = importer::describe_media(&msg) {
+                    orphans.push(OrphanMedia {
+                        target,
+                        name,
+                        size,
+                        date: msg.date().timestamp(),
+                    });
+                }
+            }
+        }
+    }
+
+    backups.sort_by_key(|b| std::cmp::Reverse(b.date));
+    let backups_to_prune = backups.split_off(keep_backups.min(backups.len()));
+
+    println!(
+        "GC scan: {} orphaned uploads, {} backups to prune",
+        orphans.len(),
+        backups_to_prune.len()
+    );
+    Ok(GcReport {
+        orphan_bytes: orphans.iter().map(|o| o.size).sum(),
+        orphans,
+        backups_kept: backups,
+        backups_to_prune,
+    })
+}
+
+#[tauri::command]
+pub(crate) async fn scan_garbage(
+    keep_backups: Option<usize>,
+    state: State<'_, AppState>,
+) -> Result<GcReport, AppError> {
+    let account = state.accounts.active();
+    let client = client_for(&account).await?;
+    let keep_backups = keep_backups.unwrap_or(state.settings.get().keep_backups);
+    scan(&state.app_handle, &client, &account, keep_backups).await
+}
+
+/// Deletes the confirmed targets from a `scan_garbage` report. Each message is
+/// checked again first: it must still be unreferenced and written by the app.
+#[tauri::command]
+pub(crate) async fn collect_garbage(
+    targets: Vec<GcTarget>,
+    state: State<'_, AppState>,
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let client = client_for(&account).await?;
+    catch_up(&state.app_handle, &client, &account).await?;
+    let referenced = referenced(&account);
+
+    let mut by_peer: HashMap<StoragePeer, Vec<i32>> = HashMap::new();
+    for target in targets {
+        if !referenced.contains(&target) {
+            by_peer
+                .entry(target.peer)
+                .or_default()
+                .push(target.message_id);
+        }
+    }
+
+    let mut deleted = 0;
+    let mut skipped = 0;
+    for (peer, ids) in by_peer {
+        let chat = storage::resolve_peer(&client, &peer).await?;
+        for chunk in ids.chunks(100) {
+            let messages = rpc!(
+                "messages.getMessages",
+                client.get_messages_by_id(chat, chunk)
+            )?;
+            let confirmed: Vec<i32> = messages
+                .iter()
+                .flatten()
+                .filter(|m| is_collectable_upload(m) || is_backup(m))
+                .map(|m| m.id())
+                .collect();
+            skipped += chunk.len() - confirmed.len();
+            if confirmed.is_empty() {
+                continue;
+            }
+            deleted += rpc!(
+                "messages.deleteMessages",
+                client.delete_messages(chat, &confirmed)
+            )?;
+        }
+    }
+
+    println!("GC deleted {} messages, skipped {}", deleted, skipped);
+    Ok(format!(
+        "Deleted {} messages. {} were skipped because they changed since the scan.",
+        deleted, skipped
+    ))
+}
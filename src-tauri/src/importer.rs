@@ -0,0 +1,197 @@
+use grammers_client::types::{Media, Message};
+use serde::Serialize;
+use std::collections::HashSet;
+use tauri::{Emitter, State, Window};
+
+use crate::db::{FileMetadata, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::{self, rpc};
+use crate::recovery::CAPTION_TAG;
+use crate::{AppState, BACKUP_TAG};
+
+const IMPORT_FOLDER: &str = "Imported";
+
+#[derive(Serialize, Clone)]
+pub struct ImportItem {
+    pub message_id: i32,
+    pub name: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub date: i64,
+    pub folder_path: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub scanned: usize,
+    pub imported: usize,
+    pub skipped_tracked: usize,
+    pub skipped_backups: usize,
+    pub items: Vec<ImportItem>,
+}
+
+#[derive(Serialize, Clone)]
+struct ImportProgress {
+    scanned: usize,
+    found: usize,
+    imported: usize,
+}
+
+// Name, size and MIME type of a photo or document message; None for anything else
+pub(crate) fn describe_media(msg: &Message) -> Option<(String, i64, String)> {
+    match msg.media()? {
+        Media::Document(doc) => {
+            let mime_type = doc
+                .mime_type()
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            let name = if doc.name().is_empty() {
+                // Voice notes and some forwarded files carry no filename
+                let ext = mime_guess::get_mime_extensions_str(&mime_type)
+                    .and_then(|exts| exts.first())
+                    .unwrap_or(&"bin");
+                format!("file_{}.{}", msg.id(), ext)
+            } else {
+                doc.name().to_string()
+            };
+            Some((name, doc.size(), mime_type))
+        }
+        Media::Photo(photo) => Some((
+            format!("photo_{}.jpg", msg.date().format("%Y-%m-%d_%H-%M-%S")),
+            photo.size(),
+            "image/jpeg".to_string(),
+        )),
+        _ => None,
+    }
+}
+
+/// Pages through the whole Saved Messages history and adds every photo and
+/// document Paperfold does not track yet. `layout` is "imported" (one flat
+/// "Imported" folder) or "by_date" ("Imported/2024-05" style subfolders).
+/// With `dry_run` nothing is written and the report lists what would be added.
+#[tauri::command]
+pub(crate) async fn import_saved_messages(
+    layout: String,
+    dry_run: bool,
+    state: State<'_, AppState>,
+    window: Window,
+) -> Result<ImportReport, AppError> {
+    let account = state.accounts.active();
+    let by_date = match layout.as_str() {
+        "imported" => false,
+        "by_date" => true,
+        _ => return Err(format!("Unknown import layout: {}", layout).into()),
+    };
+
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    // Saved Messages is private, so imports always go to the personal drive
+    let db = account.personal_db.clone();
+    let tracked: HashSet<i32> = db
+        .get_all_files()
+        .iter()
+        .flat_map(|f| f.locations())
+        .filter(|l| l.peer == StoragePeer::SavedMessages)
+        .map(|l| l.message_id)
+        .collect();
+
+    println!(
+        "Importing Saved Messages (layout={}, dry_run={})",
+        layout, dry_run
+    );
+    let me = gateway::global().get_me(&client).await?;
+    let mut messages = client.iter_messages(me);
+
+    let mut report = ImportReport {
+        dry_run,
+        scanned: 0,
+        imported: 0,
+        skipped_tracked: 0,
+        skipped_backups: 0,
+        items: Vec::new(),
+    };
+
+    while let Some(msg) = rpc!("messages.getHistory", messages.next())? {
+        report.scanned += 1;
+        if report.scanned.is_multiple_of(100) {
+            let _ = window.emit(
+                "import-progress",
+                ImportProgress {
+                    scanned: report.scanned,
+                    found: report.items.len(),
+                    imported: report.imported,
+                },
+            );
+        }
+
+        let (name, size, mime_type) = match describe_media(&msg) {
+            Some(described) => described,
+            None => continue,
+        };
+        if msg.text().contains(BACKUP_TAG) {
+            report.skipped_backups += 1;
+            continue;
+        }
+        // Uploads whose entry was lost belong to rebuild_from_telegram, which keeps their folders
+        if tracked.contains(&msg.id()) || msg.text().starts_with(CAPTION_TAG) {
+            report.skipped_tracked += 1;
+            continue;
+        }
+
+        let mut folder_path = vec![IMPORT_FOLDER.to_string()];
+        if by_date {
+            folder_path.push(msg.date().format("%Y-%m").to_string());
+        }
+        let item = ImportItem {
+            message_id: msg.id(),
+            name,
+            size,
+            mime_type,
+            date: msg.date().timestamp(),
+            folder_path,
+        };
+
+        if !dry_run {
+            let folder_id = db.ensure_folder_path(&item.folder_path)?;
+            let thumbnail = crate::extract_thumbnail_base64(&client, &msg).await;
+            db.insert_file(FileMetadata {
+                id: uuid::Uuid::new_v4().to_string(),
+                folder_id,
+                name: item.name.clone(),
+                size: item.size,
+                mime_type: item.mime_type.clone(),
+                message_id: item.message_id,
+                created_at: item.date,
+                trashed: false,
+                trashed_at: None,
+                is_starred: false,
+                thumbnail,
+                peer: StoragePeer::SavedMessages,
+                hash: None,
+                missing: false,
+                last_modified: 0,
+                replicas: Vec::new(),
+            })?;
+            report.imported += 1;
+        }
+        report.items.push(item);
+    }
+
+    let _ = window.emit(
+        "import-progress",
+        ImportProgress {
+            scanned: report.scanned,
+            found: report.items.len(),
+            imported: report.imported,
+        },
+    );
+    println!(
+        "Import finished: {} found, {} imported",
+        report.items.len(),
+        report.imported
+    );
+    Ok(report)
+}
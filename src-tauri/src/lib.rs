@@ -1,48 +1,67 @@
-use grammers_client::types::{Downloadable, InputMessage, LoginToken, Media, PasswordToken};
-use grammers_client::{Client, Config, InitParams, SignInError};
+use grammers_client::types::{Downloadable, LoginToken, Media, PasswordToken};
+use grammers_client::{Client, SignInError};
 use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::{engine::general_purpose, Engine as _};
 use mime_guess;
 
-use grammers_session::Session;
 use grammers_tl_types as tl;
 use rand::Rng;
-use std::collections::{HashMap, VecDeque};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Write; // Standard Sync Write for Zip
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tauri::{Emitter, Manager, State, Window};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use zip::write::SimpleFileOptions;
 
-use tokio::sync::Mutex as AsyncMutex;
 use tokio::sync::Semaphore;
 
+pub mod accounts;
+pub mod applock;
+pub mod backups;
+pub mod connection;
+pub mod crypto;
 pub mod db;
-use db::Database;
+pub mod deletion;
+pub mod error;
+pub mod gateway;
+pub mod gc;
+pub mod importer;
+pub mod maintenance;
+pub mod missing;
+pub mod oplog;
+pub mod proxy;
+pub mod reconcile;
+pub mod recovery;
+pub mod replication;
+pub mod scrub;
+pub mod sessions;
+pub mod settings;
+pub mod shared;
+pub mod storage;
+pub mod updates;
+use accounts::AccountManager;
+use error::AppError;
+use gateway::rpc;
 
 // Secrets moved to .env
 
-const SESSION_FILENAME: &str = "telegram.session";
-
-fn get_session_path(app_handle: &tauri::AppHandle) -> std::path::PathBuf {
-    let app_dir = app_handle
-        .path()
-        .app_data_dir()
-        .expect("failed to get app data dir");
-    std::fs::create_dir_all(&app_dir).unwrap(); // Ensure dir exists
-    app_dir.join(SESSION_FILENAME)
-}
+const BACKUP_TAG: &str = "#paperfold_metadata_backup";
 
 struct AppState {
     app_handle: tauri::AppHandle,
-    client: Arc<AsyncMutex<Option<Client>>>,
+    accounts: AccountManager, // Each profile owns its client, session file and metadata store
+    encryption: crypto::Encryption,
+    app_lock: applock::AppLock,
+    connection: connection::Connection,
+    proxy: proxy::ProxySettings,
+    settings: settings::SettingsStore,
+    maintenance: maintenance::Maintenance,
     phone_token: Mutex<Option<LoginToken>>, // Changed from phone_hash string
     password_token: Mutex<Option<PasswordToken>>, // For 2FA
-    db: Arc<Database>,
 }
 
 async fn extract_thumbnail_base64(
@@ -86,10 +105,10 @@ async fn extract_thumbnail_base64(
     let temp_path = std::env::temp_dir().join(temp_name);
     let temp_path_str = temp_path.to_string_lossy().to_string();
 
-    match client
-        .download_media(&Downloadable::PhotoSize(thumb.clone()), &temp_path_str)
-        .await
-    {
+    match rpc!(
+        "upload.getFile",
+        client.download_media(&Downloadable::PhotoSize(thumb.clone()), &temp_path_str)
+    ) {
         Ok(_) => {
             if let Ok(bytes) = std::fs::read(&temp_path) {
                 let _ = std::fs::remove_file(temp_path);
@@ -106,57 +125,24 @@ async fn extract_thumbnail_base64(
 }
 
 #[tauri::command]
-async fn login_start(phone: String, state: State<'_, AppState>) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
+async fn login_start(phone: String, state: State<'_, AppState>) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let mut client_guard = account.client.lock().await;
 
     // Force fresh client for new login to prevent stale state (SRP_ID_INVALID)
     *client_guard = None;
 
-    // Load env vars
-    dotenv::dotenv().ok();
-    // Try compile-time env first (for releases), then runtime (for local dev)
-    let api_id_str = option_env!("TELEGRAM_API_ID")
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| std::env::var("TELEGRAM_API_ID").unwrap_or_else(|_| "0".to_string()));
-
-    let api_hash = option_env!("TELEGRAM_API_HASH")
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| std::env::var("TELEGRAM_API_HASH").unwrap_or_else(|_| "".to_string()));
-
-    let api_id = api_id_str.parse::<i32>().unwrap_or(0);
-
-    if api_id == 0 || api_hash.is_empty() {
-        return Err("API Credentials missing in .env".to_string());
-    }
-
     for attempt in 0..2 {
         if client_guard.is_none() {
             // Init client if not present
-            let session_path = get_session_path(&state.app_handle);
-            let session = Session::load_file_or_create(&session_path).map_err(|e| e.to_string())?;
-
-            let params = InitParams {
-                device_model: "Paperfold Desktop".to_string(),
-                app_version: "0.1.0".to_string(),
-                system_version: "macOS".to_string(),
-                ..Default::default()
-            };
-
-            let config = Config {
-                session,
-                api_id,
-                api_hash: api_hash.clone(),
-                params,
-            };
-
-            let client = Client::connect(config).await.map_err(|e| e.to_string())?;
+            let client = connection::connect(&state.app_lock, &state.proxy.get(), &account).await?;
             *client_guard = Some(client);
         }
 
         let client = client_guard.as_ref().unwrap();
 
         // 0.7.x: request_login_code(phone) only
-        match client.request_login_code(&phone).await {
+        match rpc!("auth.sendCode", client.request_login_code(&phone)) {
             Ok(token) => {
                 *state.phone_token.lock().unwrap() = Some(token);
                 *state.password_token.lock().unwrap() = None;
@@ -172,39 +158,19 @@ async fn login_start(phone: String, state: State<'_, AppState>) -> Result<String
                     *client_guard = None;
 
                     // Delete session file to force fresh auth
-                    let session_path = get_session_path(&state.app_handle);
+                    let session_path = account.session_path();
                     if session_path.exists() {
                         let _ = std::fs::remove_file(session_path);
                     }
                     continue; // Retry loop
                 }
-                if err_msg.contains("FLOOD_WAIT") {
-                    let seconds = err_msg
-                        .split("value: ")
-                        .nth(1)
-                        .map(|s| s.trim_end_matches(')').trim())
-                        .and_then(|s| s.parse::<u64>().ok())
-                        .unwrap_or(0);
-
-                    if seconds > 0 {
-                        let hours = seconds / 3600;
-                        let minutes = (seconds % 3600) / 60;
-                        let secs = seconds % 60;
-                        return Err(format!(
-                            "Too many attempts. Please wait {}h {}m {}s.",
-                            hours, minutes, secs
-                        ));
-                    }
-                    return Err(
-                        "Too many attempts. Please wait a while before trying again.".to_string(),
-                    );
-                }
-                return Err(format!("Failed to send code: {}", e));
+                // FLOOD_WAIT comes back as AppError::FloodWait with the seconds
+                return Err(e.into());
             }
         }
     }
 
-    Err("Failed after retry".to_string())
+    Err("Failed after retry".into())
 }
 
 // Stub for QR login to check API presence
@@ -214,9 +180,10 @@ async fn login_complete(
     code: String,
     password: Option<String>,
     state: State<'_, AppState>,
-) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Client not initialized")?;
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let mut client_guard = account.client.lock().await;
+    let client = client_guard.as_mut().ok_or(AppError::NotLoggedIn)?;
 
     // Check if we are in 2FA mode
     if let Some(pwd) = password {
@@ -229,28 +196,33 @@ async fn login_complete(
 
         if let Some(token) = token_opt {
             // We pass a clone to check_password
-            match client.check_password(token, &pwd).await {
+            match rpc!(
+                "auth.checkPassword",
+                client.check_password(token.clone(), &pwd)
+            ) {
                 Ok(user) => {
                     // Success! Remove from state
                     *state.password_token.lock().unwrap() = None;
-                    let data = client.session().save();
-                    let session_path = get_session_path(&state.app_handle);
-                    std::fs::write(&session_path, data)
-                        .map_err(|e| format!("Failed to write to {:?}: {}", session_path, e))?;
+                    state
+                        .app_lock
+                        .save_session(&account.session_path(), client.session())?;
+                    state
+                        .accounts
+                        .set_identity(&account.id, user.id(), user.first_name())?;
                     Ok(format!("Logged in as {}", user.first_name()))
                 }
                 Err(e) => {
                     // Failure! Token remains in state for retry
                     let err_msg = e.to_string();
                     if err_msg.contains("SRP_ID_INVALID") {
-                        Err("Session Timeout. Please go back and try again.".to_string())
+                        Err("Session Timeout. Please go back and try again.".into())
                     } else {
-                        Err(format!("Password error: {}", e))
+                        Err(format!("Password error: {}", e).into())
                     }
                 }
             }
         } else {
-            Err("No 2FA session found. Please try logging in again.".to_string())
+            Err("No 2FA session found. Please try logging in again.".into())
         }
     } else {
         // Normal Code Login
@@ -260,92 +232,78 @@ async fn login_complete(
             .unwrap()
             .take()
             .ok_or("No login session found")?;
-        match client.sign_in(&token, &code).await {
+        match rpc!("auth.signIn", client.sign_in(&token, &code)) {
             Ok(user) => {
-                let data = client.session().save();
-                let session_path = get_session_path(&state.app_handle);
-                std::fs::write(&session_path, data)
-                    .map_err(|e| format!("Failed to write to {:?}: {}", session_path, e))?;
+                state
+                    .app_lock
+                    .save_session(&account.session_path(), client.session())?;
+                state
+                    .accounts
+                    .set_identity(&account.id, user.id(), user.first_name())?;
                 Ok(format!("Logged in as {}", user.first_name()))
             }
             Err(SignInError::PasswordRequired(token)) => {
                 // Store token for 2FA step
                 *state.password_token.lock().unwrap() = Some(token);
-                Err("PASSWORD_REQUIRED".to_string())
+                Err(AppError::PasswordRequired)
             }
-            Err(e) => Err(format!("Login failed: {}", e)),
+            Err(SignInError::Other(e)) => Err(e.into()),
+            Err(e) => Err(format!("Login failed: {}", e).into()),
         }
     }
 }
 
 #[tauri::command]
-async fn check_auth(state: State<'_, AppState>) -> Result<bool, String> {
-    let mut client_guard = state.client.lock().await;
-
-    // Load env vars
-    dotenv::dotenv().ok();
-    // Try compile-time env first (for releases), then runtime (for local dev)
-    let api_id_str = option_env!("TELEGRAM_API_ID")
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| std::env::var("TELEGRAM_API_ID").unwrap_or_else(|_| "0".to_string()));
-
-    let api_hash = option_env!("TELEGRAM_API_HASH")
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| std::env::var("TELEGRAM_API_HASH").unwrap_or_else(|_| "".to_string()));
-
-    let api_id = api_id_str.parse::<i32>().unwrap_or(0);
+async fn check_auth(state: State<'_, AppState>) -> Result<bool, AppError> {
+    let account = state.accounts.active();
+    let mut client_guard = account.client.lock().await;
 
     // If client exists, check status
     if let Some(client) = client_guard.as_ref() {
-        let auth = client.is_authorized().await.map_err(|e| e.to_string())?;
-        if auth {
-            let _ = state.db.cleanup_trash(30);
-        }
+        let auth = match gateway::global().is_authorized(client).await {
+            Ok(auth) => auth,
+            // The session is known to be good, so the dashboard opens offline
+            Err(e) if error::is_network_error(&e) => {
+                state.connection.wake();
+                return Ok(true);
+            }
+            Err(e) => return Err(e.into()),
+        };
         return Ok(auth);
     }
 
     // Try load from file
-    let session_path = get_session_path(&state.app_handle);
+    let session_path = account.session_path();
     if !session_path.exists() {
         return Ok(false);
     }
 
-    if api_id == 0 || api_hash.is_empty() {
+    if connection::api_credentials().is_none() {
         return Ok(false); // Can't connect without secrets
     }
 
-    let session = Session::load_file_or_create(&session_path).map_err(|e| e.to_string())?;
-    // Config... (We need repeat config, maybe refactor later but copy-paste for safety now)
-    let params = InitParams {
-        device_model: "Paperfold Desktop".to_string(),
-        app_version: "0.1.0".to_string(),
-        system_version: "macOS".to_string(),
-        ..Default::default()
-    };
-    let config = Config {
-        session,
-        api_id,                     // Use variable
-        api_hash: api_hash.clone(), // Use variable
-        params,
+    let client = match connection::connect(&state.app_lock, &state.proxy.get(), &account).await {
+        Ok(client) => client,
+        Err(AppError::Offline(_)) => {
+            println!("No connection at startup, opening offline");
+            connection::start_offline(&state.app_handle, &state.connection);
+            return Ok(true);
+        }
+        Err(e) => return Err(e),
     };
-
-    let client = Client::connect(config).await.map_err(|e| e.to_string())?;
-    let authorized = client.is_authorized().await.map_err(|e| e.to_string())?;
-
-    if authorized {
-        let _ = state.db.cleanup_trash(30);
-    }
+    let authorized = gateway::global().is_authorized(&client).await?;
 
     *client_guard = Some(client);
     Ok(authorized)
 }
 
 #[tauri::command]
-async fn logout(state: State<'_, AppState>) -> Result<(), String> {
-    let mut client_guard = state.client.lock().await;
-    *client_guard = None;
+async fn logout(state: State<'_, AppState>) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    // Ends the session on Telegram's side too, not just on this disk
+    accounts::end_session(&state, &account).await?;
 
-    let session_path = get_session_path(&state.app_handle);
+    let session_path = account.session_path();
     if session_path.exists() {
         let _ = std::fs::remove_file(session_path);
     }
@@ -356,8 +314,9 @@ async fn logout(state: State<'_, AppState>) -> Result<(), String> {
 async fn fetch_files(
     folder_id: Option<String>,
     state: State<'_, AppState>,
-) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), String> {
-    Ok(state.db.list_contents(folder_id))
+) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), AppError> {
+    let account = state.accounts.active();
+    Ok(account.db().list_contents(folder_id))
 }
 
 #[tauri::command]
@@ -365,9 +324,11 @@ async fn create_folder(
     name: String,
     parent_id: Option<String>,
     state: State<'_, AppState>,
-) -> Result<String, String> {
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
     println!("Creating folder: name={}, parent_id={:?}", name, parent_id);
-    Ok(state.db.create_folder(&name, parent_id))
+    account.db().create_folder(&name, parent_id)
 }
 
 #[tauri::command]
@@ -376,43 +337,82 @@ async fn upload_file(
     folder_id: Option<String>,
     state: State<'_, AppState>,
     window: Window,
-) -> Result<db::FileMetadata, String> {
-    let client_guard = state.client.lock().await;
-    let client = client_guard
-        .as_ref()
-        .ok_or("Client not initialized")?
-        .clone(); // Clone client for use in spawned tasks
+) -> Result<db::FileMetadata, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    if state.connection.is_offline() {
+        return Err(connection::queue_upload(&account, &path, folder_id));
+    }
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone(); // Clone client for use in spawned tasks
+    drop(client_guard);
+
+    let result = upload_file_core(
+        window.app_handle().clone(),
+        &account,
+        client,
+        &account.db(),
+        account.storage_target(),
+        path.clone(),
+        folder_id.clone(),
+    )
+    .await;
+    match result {
+        // The connection dropped halfway: start over once it is back
+        Err(AppError::Offline(_)) => {
+            state.connection.wake();
+            Err(connection::queue_upload(&account, &path, folder_id))
+        }
+        result => result,
+    }
+}
 
+/// Uploads one file into `db` and the `target` peer. Also runs queued uploads
+/// after a reconnect, so it does not look at which drive is open.
+pub(crate) async fn upload_file_core(
+    app_handle: tauri::AppHandle,
+    account: &accounts::Account,
+    client: Client,
+    db: &db::Database,
+    target: db::StoragePeer,
+    path: String,
+    folder_id: Option<String>,
+) -> Result<db::FileMetadata, AppError> {
     let file_path = Path::new(&path);
     if !file_path.exists() {
-        return Err("File not found".to_string());
+        return Err("File not found".into());
     }
     let file_name = file_path
         .file_name()
         .ok_or("Invalid file name")?
         .to_string_lossy()
         .to_string();
-    let file_size = tokio::fs::metadata(&path)
-        .await
-        .map_err(|e| e.to_string())?
-        .len();
+    let file_size = tokio::fs::metadata(&path).await?.len();
 
-    let mut file = tokio::fs::File::open(&path)
-        .await
-        .map_err(|e| e.to_string())?;
+    let mut file = tokio::fs::File::open(&path).await?;
 
     // Generate a unique file_id
     let file_id: i64 = rand::thread_rng().gen();
 
-    let is_big = file_size > 10 * 1024 * 1024;
-    let chunk_size = 512 * 1024;
+    // Read once so a change mid-upload can't mix part sizes
+    let settings = app_handle.state::<AppState>().settings.get();
+    if file_size > settings.max_upload_bytes() {
+        return Err(format!(
+            "File is too large for {} KiB upload chunks",
+            settings.upload_chunk_kib
+        )
+        .into());
+    }
+    let is_big = file_size > settings.big_file_threshold_bytes();
+    let chunk_size = settings.upload_chunk_bytes();
     let total_parts = (file_size as usize + chunk_size - 1) / chunk_size;
 
-    let semaphore = Arc::new(Semaphore::new(16)); // Max 16 parallel uploads (Optimized for speed)
+    let semaphore = Arc::new(Semaphore::new(settings.upload_parallel_parts));
     let uploaded_bytes = Arc::new(AtomicU64::new(0));
     let mut tasks = Vec::new();
 
     let mut part_index = 0;
+    let mut hasher = Sha256::new();
 
     #[derive(Clone, serde::Serialize)]
     struct ProgressPayload {
@@ -422,11 +422,12 @@ async fn upload_file(
 
     loop {
         let mut buffer = vec![0u8; chunk_size];
-        let n = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        let n = file.read(&mut buffer).await?;
         if n == 0 {
             break;
         }
         buffer.truncate(n);
+        hasher.update(&buffer);
 
         let permit = semaphore
             .clone()
@@ -435,7 +436,7 @@ async fn upload_file(
             .map_err(|e| e.to_string())?;
         let client_clone = client.clone(); // Use client_clone for the task
         let path_clone = path.clone();
-        let window_clone = window.clone();
+        let app_handle_clone = app_handle.clone();
         let uploaded_bytes_clone = uploaded_bytes.clone(); // Use uploaded_bytes_clone for the task
         let current_part = part_index;
 
@@ -444,28 +445,37 @@ async fn upload_file(
             let part_len = part_bytes.len() as u64;
 
             let result = if is_big {
-                client_clone
-                    .invoke(&tl::functions::upload::SaveBigFilePart {
+                rpc!(
+                    "upload.saveBigFilePart",
+                    client_clone.invoke(&tl::functions::upload::SaveBigFilePart {
                         file_id,
                         file_part: current_part as i32,
                         file_total_parts: total_parts as i32,
-                        bytes: part_bytes,
+                        bytes: part_bytes.clone(),
                     })
-                    .await
+                )
             } else {
-                client_clone
-                    .invoke(&tl::functions::upload::SaveFilePart {
+                rpc!(
+                    "upload.saveFilePart",
+                    client_clone.invoke(&tl::functions::upload::SaveFilePart {
                         file_id,
                         file_part: current_part as i32,
-                        bytes: part_bytes,
+                        bytes: part_bytes.clone(),
                     })
-                    .await
+                )
             };
 
             drop(permit); // Release semaphore immediately after upload
 
             if let Err(e) = result {
-                return Err(format!("Part {} failed: {}", current_part, e));
+                if error::is_network_error(&e) {
+                    return Err(AppError::offline());
+                }
+                return Err(AppError::Transfer {
+                    file_id: None,
+                    part: Some(current_part),
+                    message: e.to_string(),
+                });
             }
 
             // Update progress
@@ -476,7 +486,7 @@ async fn upload_file(
             let progress = (current_total as f64 / file_size as f64 * 100.0).min(100.0);
 
             // Emit event (maybe debounce this if it's too spammy, but for now every chunk is fine)
-            let _ = window_clone.emit(
+            let _ = app_handle_clone.emit(
                 "upload-progress",
                 ProgressPayload {
                     path: path_clone,
@@ -495,7 +505,7 @@ async fn upload_file(
     for task in tasks {
         match task.await {
             Ok(result) => result?, // Propagate task error
-            Err(e) => return Err(format!("Task join error: {}", e)),
+            Err(e) => return Err(format!("Task join error: {}", e).into()),
         }
     }
 
@@ -536,20 +546,35 @@ async fn upload_file(
             nosound_video: false,
         });
 
-    // Send to "me" (Saved Messages) using InputPeerSelf - no access hash needed!
-    let input_peer = tl::enums::InputPeer::PeerSelf;
+    // The caption lets rebuild_from_telegram recover this entry if metadata.json is lost
+    let entry_id = uuid::Uuid::new_v4().to_string();
+    let hash = format!("{:x}", hasher.finalize());
+    let caption = recovery::FileCaption {
+        version: 1,
+        id: entry_id.clone(),
+        path: db.folder_path(folder_id.as_deref()),
+        name: file_name.clone(),
+        size: file_size as i64,
+        hash: Some(hash.clone()),
+    };
+
+    // Send to the configured storage target (Saved Messages or the private storage channel)
+    let target_chat = storage::resolve_peer(&client, &target).await?;
+    let input_peer = target_chat.to_input_peer();
 
     let random_id: i64 = rand::thread_rng().gen();
 
-    let updates = client
-        .invoke(&tl::functions::messages::SendMedia {
+    // Same random_id on a retry, so Telegram can drop a duplicate
+    let updates = rpc!(
+        "messages.sendMedia",
+        client.invoke(&tl::functions::messages::SendMedia {
             silent: false,
             background: false,
             clear_draft: false,
-            peer: input_peer,
+            peer: input_peer.clone(),
             reply_to: None,
-            media: input_media,
-            message: "".to_string(),
+            media: input_media.clone(),
+            message: caption.encode(account.caption_vault(&target).as_deref()),
             random_id,
             reply_markup: None,
             entities: None,
@@ -561,8 +586,7 @@ async fn upload_file(
             quick_reply_shortcut: None,
             effect: None,
         })
-        .await
-        .map_err(|e| format!("SendMedia error: {}", e))?;
+    )?;
 
     let msg_id = match updates {
         tl::enums::Updates::Updates(u) => u
@@ -574,6 +598,11 @@ async fn upload_file(
                     tl::enums::Message::Message(msg) => Some(msg.id),
                     _ => None,
                 },
+                // Channels report the new post through a separate update type
+                tl::enums::Update::NewChannelMessage(m) => match &m.message {
+                    tl::enums::Message::Message(msg) => Some(msg.id),
+                    _ => None,
+                },
                 _ => None,
             })
             .unwrap_or(0),
@@ -588,38 +617,64 @@ async fn upload_file(
 
     let mut thumbnail = None;
     if msg_id != 0 {
-        if let Ok(chat) = client.get_me().await {
-            if let Ok(messages) = client.get_messages_by_id(&chat, &[msg_id]).await {
-                if let Some(Some(msg)) = messages.first() {
-                    thumbnail = extract_thumbnail_base64(&client, msg).await;
-                }
+        if let Ok(messages) = rpc!(
+            "messages.getMessages",
+            client.get_messages_by_id(target_chat, &[msg_id])
+        ) {
+            if let Some(Some(msg)) = messages.first() {
+                thumbnail = extract_thumbnail_base64(&client, msg).await;
             }
         }
     }
 
-    let metadata = state.db.add_file(
+    let metadata = db.insert_file(db::FileMetadata {
+        id: entry_id,
         folder_id,
-        file_name,
-        file_size as i64,
+        name: file_name,
+        size: file_size as i64,
         mime_type,
-        msg_id,
+        message_id: msg_id,
+        created_at: chrono::Utc::now().timestamp(),
+        trashed: false,
+        trashed_at: None,
+        is_starred: false,
         thumbnail,
-    );
-
+        peer: target,
+        hash: Some(hash),
+        missing: false,
+        last_modified: 0,
+        replicas: Vec::new(),
+    })?;
+    account.local_copies.remember(&metadata.id, &path);
+
+    // Shared drives keep a single copy in the drive's peer
+    if std::ptr::eq(db, account.personal_db.as_ref()) {
+        replication::replicate_upload(&client, account, &metadata).await?;
+        return db
+            .get_file(&metadata.id)
+            .ok_or_else(|| AppError::not_found(&metadata.id));
+    }
     Ok(metadata)
 }
 
+// Previews live in their own temp folder so maintenance can evict them
+pub(crate) fn preview_dir() -> PathBuf {
+    std::env::temp_dir().join("paperfold-previews")
+}
+
 #[tauri::command]
 async fn preview_file(
     state: State<'_, AppState>,
     file_id: i32,
     file_name: String,
-) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Client not initialized")?;
-
-    // Download to temp dir
-    let temp_dir = std::env::temp_dir();
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let mut client_guard = account.client.lock().await;
+    let client = client_guard.as_mut().ok_or(AppError::NotLoggedIn)?;
+
+    // Download to the preview cache
+    let temp_dir = preview_dir();
+    std::fs::create_dir_all(&temp_dir).map_err(AppError::storage)?;
     let target_path = temp_dir.join(&file_name);
     let target_path_str = target_path.to_string_lossy().to_string();
 
@@ -628,36 +683,37 @@ async fn preview_file(
         return Ok(target_path_str);
     }
 
-    let chat = client.get_me().await.map_err(|e| e.to_string())?;
-    let messages = client
-        .get_messages_by_id(&chat, &[file_id])
-        .await
-        .map_err(|e| e.to_string())?;
-
-    // Handle Vec<Option<Message>>
-
-    // Handle Vec<Option<Message>>
-    let message_opt = messages.first().ok_or("Message not found")?;
-    let message = message_opt.as_ref().ok_or("Message is empty/deleted")?;
+    // The frontend passes the message id; the metadata tells us where the copies are
+    let locations = match account.db().find_file_by_message(file_id) {
+        Some(file) => file.locations(),
+        None => vec![db::Replica {
+            peer: db::StoragePeer::SavedMessages,
+            message_id: file_id,
+        }],
+    };
+    let message = replication::find_copy(client, &locations)
+        .await?
+        .ok_or("Message is empty/deleted")?;
 
     if let Some(media) = message.media() {
         if matches!(media, Media::Photo(_) | Media::Document(_)) {
             let downloadable = Downloadable::Media(media);
-            client
-                .download_media(&downloadable, target_path_str.as_str())
-                .await
-                .map_err(|e| e.to_string())?;
+            rpc!(
+                "upload.getFile",
+                client.download_media(&downloadable, target_path_str.as_str())
+            )?;
             Ok(target_path_str)
         } else {
-            Err("Unsupported media type for preview".to_string())
+            Err("Unsupported media type for preview".into())
         }
     } else {
-        Err("No media found".to_string())
+        Err("No media found".into())
     }
 }
 
 #[derive(serde::Serialize)]
 struct UserProfile {
+    account_id: String,
     id: i64,
     first_name: String,
     last_name: Option<String>,
@@ -666,12 +722,14 @@ struct UserProfile {
 }
 
 #[tauri::command]
-async fn get_current_user(state: State<'_, AppState>) -> Result<UserProfile, String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Client not initialized")?;
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
+async fn get_current_user(state: State<'_, AppState>) -> Result<UserProfile, AppError> {
+    let account = state.accounts.active();
+    let mut client_guard = account.client.lock().await;
+    let client = client_guard.as_mut().ok_or(AppError::NotLoggedIn)?;
+    let me = gateway::global().get_me(client).await?;
 
     Ok(UserProfile {
+        account_id: account.id.clone(),
         id: me.id(),
         first_name: me.first_name().to_string(),
         last_name: me.last_name().map(|s| s.to_string()),
@@ -681,14 +739,18 @@ async fn get_current_user(state: State<'_, AppState>) -> Result<UserProfile, Str
 }
 
 #[tauri::command]
-fn trash_item(state: State<AppState>, id: String, is_folder: bool) -> Result<(), String> {
-    state.db.trash_item(&id, is_folder);
+fn trash_item(state: State<AppState>, id: String, is_folder: bool) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    account.db().trash_item(&id, is_folder)?;
     Ok(())
 }
 
 #[tauri::command]
-fn restore_item(state: State<AppState>, id: String, is_folder: bool) -> Result<(), String> {
-    state.db.restore_item(&id, is_folder);
+fn restore_item(state: State<AppState>, id: String, is_folder: bool) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    account.db().restore_item(&id, is_folder)?;
     Ok(())
 }
 
@@ -697,82 +759,59 @@ async fn delete_item_permanently(
     state: State<'_, AppState>,
     id: String,
     is_folder: bool,
-) -> Result<(), String> {
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
     println!("Deleting item permanently: {} (folder: {})", id, is_folder);
 
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?;
-
-    let mut messages_to_delete = Vec::new();
-
-    if is_folder {
-        // Get all files in the folder to be deleted
-        let files = state.db.delete_folder(&id);
-        for f in files {
-            messages_to_delete.push(f.message_id);
-        }
-    } else {
-        // Get file to get message id
-        if let Some(file) = state.db.get_file(&id) {
-            messages_to_delete.push(file.message_id);
-            state.db.delete_file(&id);
-        }
-    }
-
-    if !messages_to_delete.is_empty() {
-        // Fetch chat (me) to delete messages
-        match client.get_me().await {
-            Ok(chat) => {
-                if let Err(e) = client.delete_messages(&chat, &messages_to_delete).await {
-                    eprintln!("Failed to delete messages from Telegram: {}", e);
-                } else {
-                    println!("Deleted messages from Telegram");
-                }
-            }
-            Err(e) => eprintln!("Failed to get_me for deletion: {}", e),
-        }
-    }
-
-    Ok(())
+    // Entries only go away once Telegram confirms the messages are gone
+    account.db().queue_deletion(&id, is_folder)?;
+    finish_deletions(&account, &state.connection).await
 }
 
 #[tauri::command]
-async fn empty_trash(state: State<'_, AppState>) -> Result<(), String> {
+async fn empty_trash(state: State<'_, AppState>) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
     println!("Emptying trash...");
 
     // 0 days means delete everything in trash
-    let files = state.db.cleanup_trash(0);
-    let mut messages_to_delete = Vec::new();
+    account.db().queue_trash_cleanup(0)?;
+    finish_deletions(&account, &state.connection).await
+}
 
-    for f in files {
-        messages_to_delete.push(f.message_id);
+async fn finish_deletions(
+    account: &accounts::Account,
+    connection: &connection::Connection,
+) -> Result<(), AppError> {
+    if connection.is_offline() {
+        println!("Offline, the deletion stays queued until the connection is back");
+        return Ok(());
     }
+    let client_guard = account.client.lock().await;
+    let client = client_guard
+        .as_ref()
+        .ok_or("Not logged in. The deletion is queued and will run after login.")?
+        .clone();
+    drop(client_guard);
 
-    if !messages_to_delete.is_empty() {
-        let mut client_guard = state.client.lock().await;
-        if let Some(client) = client_guard.as_mut() {
-            match client.get_me().await {
-                Ok(chat) => {
-                    if let Err(e) = client.delete_messages(&chat, &messages_to_delete).await {
-                        eprintln!("Failed to delete messages from Telegram: {}", e);
-                    } else {
-                        println!(
-                            "Deleted {} messages from Telegram",
-                            messages_to_delete.len()
-                        );
-                    }
-                }
-                Err(e) => eprintln!("Failed to get_me for deletion: {}", e),
-            }
-        }
+    let run = deletion::process_account(&client, account).await;
+    if run.pending > 0 {
+        return Err(format!(
+            "{} messages could not be deleted from Telegram yet. They are queued for retry.",
+            run.pending
+        )
+        .into());
     }
-
     Ok(())
 }
 
 #[tauri::command]
-fn fetch_trash(state: State<AppState>) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), String> {
-    Ok(state.db.list_trash())
+fn fetch_trash(
+    state: State<AppState>,
+) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), AppError> {
+    let account = state.accounts.active();
+    Ok(account.db().list_trash())
 }
 
 #[tauri::command]
@@ -780,9 +819,11 @@ async fn delete_item(
     id: String,
     is_folder: bool,
     state: State<'_, AppState>,
-) -> Result<(), String> {
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
     // Soft delete now
-    state.db.trash_item(&id, is_folder);
+    account.db().trash_item(&id, is_folder)?;
     Ok(())
 }
 
@@ -792,39 +833,34 @@ async fn download_file_core(
     save_path: String,
     state: State<'_, AppState>,
     window: Window,
-) -> Result<String, String> {
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
     println!("Downloading file: id={}, save_path={}", file_id, save_path);
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Client not initialized")?;
+    let mut client_guard = account.client.lock().await;
+    let client = client_guard.as_mut().ok_or(AppError::NotLoggedIn)?;
 
-    let file_meta = state.db.get_file(&file_id).ok_or("File not found")?;
-    let message_id = file_meta.message_id;
+    let file_meta = account
+        .db()
+        .get_file(&file_id)
+        .ok_or_else(|| AppError::not_found(&file_id))?;
     let total_size = file_meta.size;
-    let chat = client.get_me().await.map_err(|e| e.to_string())?;
 
-    let messages = client
-        .get_messages_by_id(&chat, &[message_id])
-        .await
-        .map_err(|e| e.to_string())?;
-
-    let message_opt = messages.first().ok_or("Message list empty")?;
-    let message = match message_opt {
-        Some(m) => m,
-        None => return Err("Message not found".to_string()),
-    };
+    // Falls back to a replica when the primary copy is gone
+    let message = replication::find_copy(client, &file_meta.locations())
+        .await?
+        .ok_or_else(|| AppError::not_found(&file_id))?;
 
     if let Some(media) = message.media() {
         let downloadable = match media {
             Media::Photo(p) => Downloadable::Media(Media::Photo(p)),
             Media::Document(d) => Downloadable::Media(Media::Document(d)),
-            _ => return Err("Unsupported media type".to_string()),
+            _ => return Err("Unsupported media type".into()),
         };
 
-        let mut file_out = tokio::fs::File::create(&save_path)
-            .await
-            .map_err(|e| e.to_string())?;
+        let mut file_out = tokio::fs::File::create(&save_path).await?;
         let mut stream = client.iter_download(&downloadable);
         let mut downloaded_size: i64 = 0;
+        let mut part = 0;
 
         #[derive(Clone, serde::Serialize)]
         struct DownloadProgress {
@@ -832,11 +868,18 @@ async fn download_file_core(
             progress: u32,
         }
 
-        while let Some(chunk) = stream.next().await.map_err(|e| e.to_string())? {
-            file_out
-                .write_all(&chunk)
-                .await
-                .map_err(|e| e.to_string())?;
+        while let Some(chunk) =
+            rpc!("upload.getFile", stream.next()).map_err(|e| match AppError::from(e) {
+                AppError::Telegram(message) => AppError::Transfer {
+                    file_id: Some(file_id.clone()),
+                    part: Some(part),
+                    message,
+                },
+                e => e,
+            })?
+        {
+            part += 1;
+            file_out.write_all(&chunk).await?;
             downloaded_size += chunk.len() as i64;
 
             if total_size > 0 {
@@ -859,10 +902,11 @@ async fn download_file_core(
                 progress: 100,
             },
         );
+        account.local_copies.remember(&file_id, &save_path);
 
         Ok("Download complete".to_string())
     } else {
-        Err("No media found in message".to_string())
+        Err("No media found in message".into())
     }
 }
 
@@ -872,36 +916,60 @@ async fn rename_item(
     is_folder: bool,
     new_name: String,
     state: State<'_, AppState>,
-) -> Result<(), String> {
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
     println!(
         "Renaming item: id={}, is_folder={}, new_name={}",
         id, is_folder, new_name
     );
     if is_folder {
-        if state.db.rename_folder(&id, &new_name) {
+        if account.db().rename_folder(&id, &new_name)? {
             Ok(())
         } else {
-            Err("Folder not found".to_string())
+            Err(AppError::not_found(&id))
         }
     } else {
-        if state.db.rename_file(&id, &new_name) {
+        if account.db().rename_file(&id, &new_name)? {
             Ok(())
         } else {
-            Err("File not found".to_string())
+            Err(AppError::not_found(&id))
         }
     }
 }
 
+#[tauri::command]
+async fn move_item(
+    id: String,
+    is_folder: bool,
+    parent_id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    println!(
+        "Moving item: id={}, is_folder={}, parent_id={:?}",
+        id, is_folder, parent_id
+    );
+    if account.db().move_item(&id, is_folder, parent_id)? {
+        Ok(())
+    } else {
+        Err("Item not found or cannot be moved there".into())
+    }
+}
+
 #[tauri::command]
 async fn toggle_star(
     id: String,
     is_folder: bool,
     state: State<'_, AppState>,
-) -> Result<(), String> {
-    if state.db.toggle_star(&id, is_folder) {
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    if account.db().toggle_star(&id, is_folder)? {
         Ok(())
     } else {
-        Err("Item not found".to_string())
+        Err(AppError::not_found(&id))
     }
 }
 
@@ -922,16 +990,16 @@ pub struct EnrichedFile {
 #[tauri::command]
 async fn fetch_starred(
     state: State<'_, AppState>,
-) -> Result<(Vec<EnrichedFolder>, Vec<EnrichedFile>), String> {
-    let (folders, files) = state.db.get_starred();
+) -> Result<(Vec<EnrichedFolder>, Vec<EnrichedFile>), AppError> {
+    let account = state.accounts.active();
+    let db = account.db();
+    let (folders, files) = db.get_starred();
 
     let enriched_folders = folders
         .into_iter()
         .map(|f| {
             let path_display = if let Some(fid) = &f.parent_id {
-                state
-                    .db
-                    .lookup_folder_name(fid)
+                db.lookup_folder_name(fid)
                     .map(|name| format!("In: {}", name))
             } else {
                 Some("In: My Drive".to_string())
@@ -947,9 +1015,7 @@ async fn fetch_starred(
         .into_iter()
         .map(|f| {
             let path_display = if let Some(fid) = &f.folder_id {
-                state
-                    .db
-                    .lookup_folder_name(fid)
+                db.lookup_folder_name(fid)
                     .map(|name| format!("In: {}", name))
             } else {
                 Some("In: My Drive".to_string())
@@ -968,13 +1034,15 @@ async fn fetch_starred(
 async fn search_items(
     query: String,
     state: State<'_, AppState>,
-) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), String> {
-    Ok(state.db.search_items(&query))
+) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), AppError> {
+    let account = state.accounts.active();
+    Ok(account.db().search_items(&query))
 }
 
 #[tauri::command]
-async fn get_storage_usage(state: State<'_, AppState>) -> Result<String, String> {
-    let bytes = state.db.get_total_usage();
+async fn get_storage_usage(state: State<'_, AppState>) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let bytes = account.db().get_total_usage();
 
     const KB: f64 = 1024.0;
     const MB: f64 = KB * 1024.0;
@@ -993,8 +1061,9 @@ async fn get_storage_usage(state: State<'_, AppState>) -> Result<String, String>
 }
 
 #[tauri::command]
-async fn get_folder_stats(state: State<'_, AppState>, id: String) -> Result<(i64, i32), String> {
-    Ok(state.db.get_folder_stats(&id))
+async fn get_folder_stats(state: State<'_, AppState>, id: String) -> Result<(i64, i32), AppError> {
+    let account = state.accounts.active();
+    Ok(account.db().get_folder_stats(&id))
 }
 
 #[tauri::command]
@@ -1011,13 +1080,14 @@ async fn update_folder_metadata(
     tags: Option<Vec<String>>,
     description: Option<String>,
     view_mode: Option<String>,
-) -> Result<(), String> {
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
     println!(
         "Updating folder metadata: id={}, color={:?}, icon={:?}, gradient={:?}, emoji={:?}",
         id, color, icon, gradient, emoji
     );
-    let success = state.db.update_folder_metadata(
-        &id,
+    let patch = db::FolderPatch {
         color,
         icon,
         gradient,
@@ -1028,127 +1098,27 @@ async fn update_folder_metadata(
         tags,
         description,
         view_mode,
-    );
+    };
+    let success = account.db().update_folder_metadata(&id, patch)?;
     if success {
         println!("Update successful for id={}", id);
         Ok(())
     } else {
         println!("Update failed: Folder not found for id={}", id);
-        Err("Folder not found".to_string())
-    }
-}
-
-#[tauri::command]
-async fn backup_metadata(state: State<'_, AppState>) -> Result<String, String> {
-    println!("Starting metadata backup...");
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
-
-    // 1. Get metadata path
-    let app_dir = state
-        .app_handle
-        .path()
-        .app_data_dir()
-        .map_err(|e| e.to_string())?;
-    let metadata_path = app_dir.join("metadata.json");
-
-    if !metadata_path.exists() {
-        return Err("No metadata file found to backup".to_string());
-    }
-
-    // 2. Upload file
-    // For small files like metadata.json, we can use a simpler upload or just re-use the manual logic.
-    // Since client.upload_file is convenient but we want to tag it...
-    // Let's use the file upload helper if available or standard part upload.
-    // We'll trust client.upload_file for simplicity if available, else manual.
-    // grammers-client has `upload_file` which returns an UploadedFile.
-
-    let uploaded_file = client
-        .upload_file(&metadata_path)
-        .await
-        .map_err(|e| e.to_string())?;
-
-    // 3. Send to "Saved Messages" (Me)
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
-
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let caption = format!("#paperfold_metadata_backup\nTimestamp: {}", timestamp);
-
-    client
-        .send_message(&me, InputMessage::text(&caption).file(uploaded_file))
-        .await
-        .map_err(|e| e.to_string())?;
-
-    println!("Backup uploaded successfully.");
-    Ok(format!("Backup successful! Timestamp: {}", timestamp))
-}
-
-#[tauri::command]
-async fn restore_metadata(state: State<'_, AppState>) -> Result<String, String> {
-    println!("Restoring metadata from backup...");
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
-
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
-
-    // 1. Search for latest backup
-    let mut messages = client.iter_messages(&me).limit(50); // Check last 50 messages
-
-    let mut backup_msg = None;
-
-    while let Some(msg) = messages.next().await.map_err(|e| e.to_string())? {
-        if msg.text().contains("#paperfold_metadata_backup") {
-            backup_msg = Some(msg);
-            break;
-        }
-    }
-
-    if let Some(msg) = backup_msg {
-        // 2. Download it
-        let app_dir = state
-            .app_handle
-            .path()
-            .app_data_dir()
-            .map_err(|e| e.to_string())?;
-        let metadata_path = app_dir.join("metadata.json");
-
-        // Backup current one locally
-        if metadata_path.exists() {
-            let backup_local = app_dir.join("metadata.json.old");
-            let _ = tokio::fs::rename(&metadata_path, &backup_local).await;
-        }
-
-        // Fix: Use media() instead of message for download
-        let media = msg.media().ok_or("No media in backup message")?;
-        let downloadable = Downloadable::Media(media);
-
-        let _ = client
-            .download_media(&downloadable, &metadata_path)
-            .await
-            .map_err(|e| e.to_string())?;
-
-        // 3. Reload DB (Hot Reload)
-        state.db.reload();
-
-        Ok("Backup restored successfully. Your dashboard will refresh.".to_string())
-    } else {
-        Err("No backup found in Saved Messages.".to_string())
+        Err(AppError::not_found(&id))
     }
 }
 
 #[tauri::command]
-async fn sync_files(state: State<'_, AppState>) -> Result<String, String> {
+async fn sync_files(state: State<'_, AppState>) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
     println!("Syncing files with Telegram...");
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
-
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
+    let mut client_guard = account.client.lock().await;
+    let client = client_guard.as_mut().ok_or(AppError::NotLoggedIn)?.clone();
 
     // 1. Get all local files
-    let all_files = state.db.get_all_files();
+    let all_files = account.db().get_all_files();
     if all_files.is_empty() {
         return Ok("No files to sync.".to_string());
     }
@@ -1156,54 +1126,82 @@ async fn sync_files(state: State<'_, AppState>) -> Result<String, String> {
     println!("Checking {} files...", all_files.len());
 
     // 2. Batch check availability
-    let mut missing_ids = Vec::new();
-    let batch_size = 50;
+    let mut dead = HashSet::new();
+    let batch_size = state.settings.get().message_batch_size;
 
-    for chunk in all_files.chunks(batch_size) {
-        let message_ids: Vec<i32> = chunk.iter().map(|f| f.message_id).collect();
-
-        // get_messages_by_id returns specific messages.
-        // If a message is deleted, it might return None or an empty message depending on API.
-        // grammers: returns Vec<Option<Message>> usually, or filtered list?
-        // Let's check the return type docs/usage.
-        // In preview_file we used get_messages_by_id and it returned a list.
-        // If I request IDs [1, 2, 3] and 2 is deleted, does it return [Some, None, Some] or [Msg1, Msg3]?
-        // grammers-client `get_messages_by_id` returns `Result<Vec<Option<Message>>>`.
-
-        let messages = client
-            .get_messages_by_id(&me, &message_ids)
-            .await
-            .map_err(|e| e.to_string())?;
+    // Copies can live in different peers (Saved Messages, storage channel, replica peer)
+    let mut by_peer: HashMap<db::StoragePeer, Vec<i32>> = HashMap::new();
+    for l in all_files.iter().flat_map(|f| f.locations()) {
+        by_peer.entry(l.peer).or_default().push(l.message_id);
+    }
 
-        // We iterate input IDs and result messages in parallel?
-        // Docs say: "The returned list will have the same length as the input IDs."
-
-        for (i, msg_opt) in messages.iter().enumerate() {
-            let is_missing = match msg_opt {
-                Some(msg) => {
-                    // Check if empty or media missing?
-                    // Safe to assume if it exists it's fine, unless "Empty" type.
-                    // For now, if Some(msg), check if it has media if we expect it.
-                    // But just existence logic:
-                    msg.media().is_none() // If no media, handle as "content deleted" for our file app?
-                                          // Actually, text messages don't have media. Our files MUST have media.
-                                          // So if media is missing, it's effectively a broken link for us.
+    for (peer, ids) in by_peer {
+        let chat = storage::resolve_peer(&client, &peer).await?;
+
+        for chunk in ids.chunks(batch_size) {
+            let message_ids = chunk.to_vec();
+
+            // get_messages_by_id returns specific messages.
+            // If a message is deleted, it might return None or an empty message depending on API.
+            // grammers: returns Vec<Option<Message>> usually, or filtered list?
+            // Let's check the return type docs/usage.
+            // In preview_file we used get_messages_by_id and it returned a list.
+            // If I request IDs [1, 2, 3] and 2 is deleted, does it return [Some, None, Some] or [Msg1, Msg3]?
+            // grammers-client `get_messages_by_id` returns `Result<Vec<Option<Message>>>`.
+
+            let messages = rpc!(
+                "messages.getMessages",
+                client.get_messages_by_id(chat, &message_ids)
+            )?;
+
+            // We iterate input IDs and result messages in parallel?
+            // Docs say: "The returned list will have the same length as the input IDs."
+
+            for (i, msg_opt) in messages.iter().enumerate() {
+                let is_missing = match msg_opt {
+                    Some(msg) => {
+                        // Check if empty or media missing?
+                        // Safe to assume if it exists it's fine, unless "Empty" type.
+                        // For now, if Some(msg), check if it has media if we expect it.
+                        // But just existence logic:
+                        msg.media().is_none() // If no media, handle as "content deleted" for our file app?
+                                              // Actually, text messages don't have media. Our files MUST have media.
+                                              // So if media is missing, it's effectively a broken link for us.
+                    }
+                    None => true,
+                };
+
+                if is_missing {
+                    dead.insert(db::Replica {
+                        peer: peer.clone(),
+                        message_id: chunk[i],
+                    });
                 }
-                None => true,
-            };
-
-            if is_missing {
-                missing_ids.push(chunk[i].id.clone());
             }
         }
     }
 
+    // A file is only missing once every copy is gone; otherwise a replica takes over
+    let db = account.db();
+    let mut missing_ids = Vec::new();
+    for file in &all_files {
+        let gone = |l: &db::Replica| dead.contains(l);
+        if file.locations().iter().any(gone) && !missing::drop_locations(&db, file, gone) {
+            missing_ids.push(file.id.clone());
+        }
+    }
+
     let removed_count = missing_ids.len();
     if removed_count > 0 {
-        println!("Found {} missing files. Removing...", removed_count);
-        state.db.delete_files_by_ids(&missing_ids);
+        // Trash rather than delete: a bad response must not wipe the index.
+        // reconcile_files gives the detailed picture.
+        println!("Found {} missing files. Moving to trash...", removed_count);
+        db.mark_missing(&missing_ids)?;
+        for id in &missing_ids {
+            db.trash_item(id, false)?;
+        }
         Ok(format!(
-            "Sync complete. Removed {} deleted files.",
+            "Sync complete. Moved {} missing files to trash.",
             removed_count
         ))
     } else {
@@ -1217,15 +1215,16 @@ async fn download_folder(
     folder_id: String,
     base_path: String,
     window: Window,
-) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let mut client_guard = account.client.lock().await;
+    let client = client_guard.as_mut().ok_or(AppError::NotLoggedIn)?.clone();
 
     // Drop guard so we can await async calls
     drop(client_guard);
 
-    let all_files = state.db.get_all_files();
-    let all_folders = state.db.get_all_folders();
+    let all_files = account.db().get_all_files();
+    let all_folders = account.db().get_all_folders();
 
     // Build Maps for O(1) lookup
     let mut file_map: HashMap<String, Vec<db::FileMetadata>> = HashMap::new();
@@ -1256,21 +1255,15 @@ async fn download_folder(
 
     while let Some((curr_id, curr_path)) = queue.pop_front() {
         // 1. Create directory
-        tokio::fs::create_dir_all(&curr_path)
-            .await
-            .map_err(|e| e.to_string())?;
+        tokio::fs::create_dir_all(&curr_path).await?;
 
         // 2. Download files in this folder
         if let Some(files) = file_map.get(&curr_id) {
             for f in files {
-                // Fetch valid message/media
-                let chat = client.get_me().await.map_err(|e| e.to_string())?;
-                let messages = client
-                    .get_messages_by_id(&chat, &[f.message_id])
-                    .await
-                    .map_err(|e| e.to_string())?;
-
-                if let Some(Some(msg)) = messages.first() {
+                // Fetch valid message/media, from a replica if need be
+                let copy = replication::find_copy(&client, &f.locations()).await?;
+
+                if let Some(msg) = copy.as_ref() {
                     if let Some(media) = msg.media() {
                         let downloadable = match media {
                             Media::Photo(p) => Downloadable::Media(Media::Photo(p)),
@@ -1291,10 +1284,10 @@ async fn download_folder(
                             }),
                         );
 
-                        match client
-                            .download_media(&downloadable, part_path.clone())
-                            .await
-                        {
+                        match rpc!(
+                            "upload.getFile",
+                            client.download_media(&downloadable, part_path.clone())
+                        ) {
                             Ok(_) => {
                                 // Rename part to final
                                 let _ = std::fs::rename(&part_path, &final_path);
@@ -1302,7 +1295,7 @@ async fn download_folder(
                             Err(e) => {
                                 // Clean up part file if failed
                                 let _ = std::fs::remove_file(&part_path);
-                                return Err(e.to_string());
+                                return Err(e.to_string().into());
                             }
                         }
                     }
@@ -1326,13 +1319,14 @@ async fn download_all(
     target_dir: String,
     state: State<'_, AppState>,
     window: Window,
-) -> Result<(), String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    let mut client_guard = account.client.lock().await;
+    let client = client_guard.as_mut().ok_or(AppError::NotLoggedIn)?.clone();
     drop(client_guard);
 
-    let all_files = state.db.get_all_files();
-    let all_folders = state.db.get_all_folders();
+    let all_files = account.db().get_all_files();
+    let all_folders = account.db().get_all_folders();
 
     // Mapping ID -> Path
     let mut folder_map = HashMap::new();
@@ -1345,7 +1339,7 @@ async fn download_all(
         name: String,
         relative_path: std::path::PathBuf,
         size: i64,
-        message_id: i32,
+        locations: Vec<db::Replica>,
     }
 
     let mut entries = Vec::new();
@@ -1382,23 +1376,23 @@ async fn download_all(
 
             entries.push(FileEntry {
                 // id: file.id,
+                locations: file.locations(),
                 name: file.name,
                 relative_path: path,
                 size: file.size,
-                message_id: file.message_id,
             });
         }
     }
 
-    // Packet Logic (Limit 1.9GB)
-    const LIMIT: u64 = 1_900_000_000;
+    // Packet Logic (zip part size from settings)
+    let limit = state.settings.get().zip_part_limit_bytes();
 
     let mut packets: Vec<Vec<FileEntry>> = Vec::new();
     let mut current_packet = Vec::new();
     let mut current_size = 0;
 
     for entry in entries {
-        if current_size + (entry.size as u64) > LIMIT && !current_packet.is_empty() {
+        if current_size + (entry.size as u64) > limit && !current_packet.is_empty() {
             packets.push(current_packet);
             current_packet = Vec::new();
             current_size = 0;
@@ -1421,7 +1415,7 @@ async fn download_all(
         let final_path = Path::new(&target_dir).join(&zip_name);
         let part_path = Path::new(&target_dir).join(format!("{}.part", zip_name));
 
-        let file = std::fs::File::create(&part_path).map_err(|e| e.to_string())?;
+        let file = std::fs::File::create(&part_path)?;
         let mut zip = zip::ZipWriter::new(file);
         let options = SimpleFileOptions::default()
             .compression_method(zip::CompressionMethod::Deflated)
@@ -1446,29 +1440,27 @@ async fn download_all(
             let temp_path_str = temp_path.to_string_lossy().to_string();
 
             // Fetch message/media
-            if let Ok(chat) = client.get_me().await {
-                if let Ok(messages) = client.get_messages_by_id(&chat, &[entry.message_id]).await {
-                    if let Some(Some(msg)) = messages.first() {
-                        if let Some(media) = msg.media() {
-                            let downloadable = match media {
-                                Media::Photo(p) => Downloadable::Media(Media::Photo(p)),
-                                Media::Document(d) => Downloadable::Media(Media::Document(d)),
-                                _ => continue,
-                            };
-
-                            if client
-                                .download_media(&downloadable, &temp_path_str)
-                                .await
-                                .is_ok()
-                            {
-                                if let Ok(content) = std::fs::read(&temp_path) {
-                                    let path_str =
-                                        entry.relative_path.to_string_lossy().to_string();
-                                    let _ = zip.start_file(path_str, options);
-                                    let _ = zip.write_all(&content);
-                                }
-                                let _ = std::fs::remove_file(&temp_path);
+            if let Ok(copy) = replication::find_copy(&client, &entry.locations).await {
+                if let Some(msg) = copy.as_ref() {
+                    if let Some(media) = msg.media() {
+                        let downloadable = match media {
+                            Media::Photo(p) => Downloadable::Media(Media::Photo(p)),
+                            Media::Document(d) => Downloadable::Media(Media::Document(d)),
+                            _ => continue,
+                        };
+
+                        if rpc!(
+                            "upload.getFile",
+                            client.download_media(&downloadable, &temp_path_str)
+                        )
+                        .is_ok()
+                        {
+                            if let Ok(content) = std::fs::read(&temp_path) {
+                                let path_str = entry.relative_path.to_string_lossy().to_string();
+                                let _ = zip.start_file(path_str, options);
+                                let _ = zip.write_all(&content);
                             }
+                            let _ = std::fs::remove_file(&temp_path);
                         }
                     }
                 }
@@ -1477,7 +1469,7 @@ async fn download_all(
         let _ = zip.finish().map_err(|e| e.to_string())?;
 
         // Rename part to final
-        std::fs::rename(&part_path, &final_path).map_err(|e| e.to_string())?;
+        std::fs::rename(&part_path, &final_path)?;
     }
 
     Ok(())
@@ -1485,21 +1477,124 @@ async fn download_all(
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    // Typed so the lock check below can wrap it
+    let handler: fn(tauri::ipc::Invoke<tauri::Wry>) -> bool = tauri::generate_handler![
+        login_start,
+        login_complete,
+        check_auth,
+        logout,
+        fetch_files,
+        create_folder,
+        upload_file,
+        download_file_core,
+        delete_item,
+        delete_item_permanently,
+        trash_item,
+        restore_item,
+        fetch_trash,
+        empty_trash,
+        rename_item,
+        move_item,
+        update_folder_metadata,
+        get_folder_stats,
+        get_storage_usage,
+        preview_file,
+        get_current_user,
+        toggle_star,
+        fetch_starred,
+        search_items,
+        backups::backup_metadata,
+        backups::restore_metadata,
+        backups::list_backups,
+        backups::preview_restore,
+        backups::get_backup_status,
+        backups::set_auto_backup,
+        crypto::get_encryption_status,
+        crypto::enable_encryption,
+        crypto::unlock,
+        crypto::change_passphrase,
+        sync_files,
+        download_folder,
+        download_all,
+        storage::get_storage_target,
+        storage::set_storage_target,
+        storage::migrate_to_storage_target,
+        accounts::list_accounts,
+        accounts::add_account,
+        accounts::switch_account,
+        accounts::remove_account,
+        accounts::search_all_accounts,
+        shared::list_shared_drives,
+        shared::create_shared_drive,
+        shared::join_shared_drive,
+        shared::export_shared_drive_invite,
+        shared::open_shared_drive,
+        shared::sync_shared_drive,
+        shared::leave_shared_drive,
+        oplog::get_device_sync_status,
+        oplog::enable_device_sync,
+        oplog::disable_device_sync,
+        oplog::sync_now,
+        recovery::rebuild_from_telegram,
+        importer::import_saved_messages,
+        storage::set_inbox_settings,
+        missing::list_missing_files,
+        missing::reupload_missing_file,
+        reconcile::reconcile_files,
+        reconcile::apply_reconciliation,
+        gc::scan_garbage,
+        gc::collect_garbage,
+        deletion::list_pending_deletions,
+        deletion::retry_pending_deletions,
+        applock::get_app_lock_status,
+        applock::unlock_app,
+        applock::lock_app,
+        applock::set_passcode,
+        applock::set_auto_lock,
+        connection::get_connection_state,
+        connection::reconnect_now,
+        connection::list_queued_uploads,
+        connection::cancel_queued_upload,
+        proxy::get_proxy_settings,
+        proxy::set_proxy_settings,
+        proxy::test_proxy,
+        sessions::list_sessions,
+        sessions::terminate_sessions,
+        settings::get_settings,
+        settings::update_settings,
+        maintenance::get_maintenance_status,
+        maintenance::run_maintenance_job,
+        scrub::get_health_report,
+        replication::set_replication,
+        replication::repair_replicas
+    ];
+
     tauri::Builder::default()
         .setup(|app| {
             let _app_handle = app.handle();
             let app_dir = app.path().app_data_dir().unwrap();
             std::fs::create_dir_all(&app_dir).unwrap();
 
-            let db = Arc::new(Database::new(app_dir.to_str().unwrap()));
-
             app.manage(AppState {
                 app_handle: app.handle().clone(),
-                client: Arc::new(AsyncMutex::new(None)), // Lazy init
+                accounts: AccountManager::new(&app_dir)?,
+                encryption: crypto::Encryption::new(&app_dir),
+                app_lock: applock::AppLock::new(&app_dir),
+                connection: connection::Connection::default(),
+                proxy: proxy::ProxySettings::load(&app_dir),
+                settings: settings::SettingsStore::load(&app_dir),
+                maintenance: maintenance::Maintenance::load(&app_dir),
                 phone_token: Mutex::new(None),
                 password_token: Mutex::new(None),
-                db,
             });
+            gateway::init(app.handle().clone());
+
+            shared::spawn_sync_loop(app.handle().clone());
+            oplog::spawn_sync_loop(app.handle().clone());
+            updates::spawn_update_listener(app.handle().clone());
+            maintenance::spawn_scheduler(app.handle().clone());
+            applock::spawn_auto_lock_loop(app.handle().clone());
+            connection::spawn_supervisor(app.handle().clone());
 
             Ok(())
         })
@@ -1507,36 +1602,21 @@ pub fn run() {
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_process::init())
         .plugin(tauri_plugin_fs::init())
-        .invoke_handler(tauri::generate_handler![
-            login_start,
-            login_complete,
-            check_auth,
-            logout,
-            fetch_files,
-            create_folder,
-            upload_file,
-            download_file_core,
-            delete_item,
-            delete_item_permanently,
-            trash_item,
-            restore_item,
-            fetch_trash,
-            empty_trash,
-            rename_item,
-            update_folder_metadata,
-            get_folder_stats,
-            get_storage_usage,
-            preview_file,
-            get_current_user,
-            toggle_star,
-            fetch_starred,
-            search_items,
-            backup_metadata,
-            restore_metadata,
-            sync_files,
-            download_folder,
-            download_all
-        ])
+        .invoke_handler(move |invoke: tauri::ipc::Invoke<tauri::Wry>| {
+            // Nothing but the lock screen gets through while the app is locked
+            let webview = invoke.message.webview();
+            let allowed = webview
+                .state::<AppState>()
+                .app_lock
+                .check(invoke.message.command());
+            if !allowed {
+                invoke
+                    .resolver
+                    .reject(AppError::Locked("App is locked".to_string()));
+                return true;
+            }
+            handler(invoke)
+        })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,382 @@
+use grammers_client::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+use crate::accounts::Account;
+use crate::error::AppError;
+use crate::gateway;
+use crate::settings::Settings;
+use crate::{backups, deletion, gc, scrub, AppState};
+
+const MAINTENANCE_FILENAME: &str = "maintenance.json";
+// How often the scheduler looks for due jobs
+const TICK_SECS: u64 = 30;
+// Jobs that never ran wait this long after startup, so login goes first
+const STARTUP_DELAY_SECS: i64 = 60;
+// Errors kept per job
+const ERROR_HISTORY: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Job {
+    TrashExpiry,
+    Backup,
+    OrphanGc,
+    Scrub,
+    PreviewCache,
+}
+
+impl Job {
+    pub const ALL: [Job; 5] = [
+        Job::TrashExpiry,
+        Job::Backup,
+        Job::OrphanGc,
+        Job::Scrub,
+        Job::PreviewCache,
+    ];
+
+    fn interval_minutes(self, settings: &Settings) -> u64 {
+        match self {
+            Job::TrashExpiry => settings.trash_expiry_interval_minutes,
+            Job::Backup => settings.backup_interval_minutes,
+            Job::OrphanGc => settings.gc_interval_minutes,
+            Job::Scrub => settings.scrub_interval_minutes,
+            Job::PreviewCache => settings.preview_cache_interval_minutes,
+        }
+    }
+
+    // Everything but the local cache talks to Telegram
+    fn needs_client(self) -> bool {
+        self != Job::PreviewCache
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobError {
+    pub at: i64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct JobRecord {
+    pub last_run: Option<i64>,
+    pub last_duration_ms: Option<u64>,
+    // Summary of the last successful run
+    pub last_result: Option<String>,
+    // Set when the last run failed, cleared by the next success
+    pub last_error: Option<String>,
+    // Newest first
+    pub errors: Vec<JobError>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobStatus {
+    pub job: Job,
+    pub interval_minutes: u64,
+    pub running: bool,
+    pub next_run: i64,
+    #[serde(flatten)]
+    pub record: JobRecord,
+}
+
+/// Run history of the maintenance jobs, in maintenance.json. App-wide; each run
+/// works on whichever account is active at the time.
+pub struct Maintenance {
+    path: PathBuf,
+    records: Mutex<HashMap<Job, JobRecord>>,
+    running: Mutex<HashSet<Job>>,
+    started_at: i64,
+}
+
+// Clears the running flag however the job ends
+struct RunGuard<'a> {
+    maintenance: &'a Maintenance,
+    job: Job,
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        self.maintenance.running.lock().unwrap().remove(&self.job);
+    }
+}
+
+impl Maintenance {
+    pub fn load(app_dir: &Path) -> Self {
+        let path = app_dir.join(MAINTENANCE_FILENAME);
+        let records = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default();
+        Maintenance {
+            path,
+            records: Mutex::new(records),
+            running: Mutex::new(HashSet::new()),
+            started_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    pub fn status(&self, job: Job, settings: &Settings) -> JobStatus {
+        let record = self
+            .records
+            .lock()
+            .unwrap()
+            .get(&job)
+            .cloned()
+            .unwrap_or_default();
+        let interval_minutes = job.interval_minutes(settings);
+        let next_run = match record.last_run {
+            Some(last) => last + (interval_minutes * 60) as i64,
+            None => self.started_at + STARTUP_DELAY_SECS,
+        };
+        JobStatus {
+            job,
+            interval_minutes,
+            running: self.running.lock().unwrap().contains(&job),
+            next_run,
+            record,
+        }
+    }
+
+    fn start(&self, job: Job) -> Option<RunGuard<'_>> {
+        if !self.running.lock().unwrap().insert(job) {
+            return None;
+        }
+        Some(RunGuard {
+            maintenance: self,
+            job,
+        })
+    }
+
+    fn record(&self, job: Job, at: i64, duration: Duration, result: Result<String, AppError>) {
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(job).or_default();
+        record.last_run = Some(at);
+        record.last_duration_ms = Some(duration.as_millis() as u64);
+        match result {
+            Ok(summary) => {
+                record.last_result = Some(summary);
+                record.last_error = None;
+            }
+            Err(e) => {
+                let message = e.to_string();
+                record.last_error = Some(message.clone());
+                record.errors.insert(0, JobError { at, message });
+                record.errors.truncate(ERROR_HISTORY);
+            }
+        }
+        let saved = File::create(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| {
+                serde_json::to_writer(BufWriter::new(f), &*records).map_err(|e| e.to_string())
+            });
+        if let Err(e) = saved {
+            eprintln!("Failed to save maintenance history: {}", e);
+        }
+    }
+}
+
+// A logged-in client for the active account, or why the job can't run now
+async fn ready_client(state: &AppState, account: &Account) -> Result<Client, AppError> {
+    if state.encryption.is_locked() {
+        return Err(AppError::Locked("Unlock the app first".to_string()));
+    }
+    if state.connection.is_offline() {
+        return Err(AppError::offline());
+    }
+    let client = account
+        .client
+        .lock()
+        .await
+        .as_ref()
+        .ok_or(AppError::NotLoggedIn)?
+        .clone();
+    if !gateway::global()
+        .is_authorized(&client)
+        .await
+        .unwrap_or(false)
+    {
+        return Err(AppError::NotLoggedIn);
+    }
+    Ok(client)
+}
+
+// Deletes previews past the age limit, then the oldest until under the size limit
+fn evict_previews(dir: &Path, max_age_days: u64, max_bytes: u64) -> Result<String, AppError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok("Cache is empty".to_string())
+        }
+        Err(e) => {
+            return Err(AppError::storage(format!(
+                "Cannot read preview cache: {}",
+                e
+            )))
+        }
+    };
+    let mut files: Vec<(PathBuf, u64, SystemTime)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok().filter(|m| m.is_file())?;
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            Some((entry.path(), meta.len(), modified))
+        })
+        .collect();
+    files.sort_by_key(|(_, _, modified)| *modified);
+
+    let max_age = Duration::from_secs(max_age_days * 24 * 3600);
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+    let mut removed = 0;
+    let mut freed = 0;
+    for (path, size, modified) in &files {
+        let expired = modified.elapsed().map(|age| age > max_age).unwrap_or(false);
+        if !expired && total <= max_bytes {
+            continue;
+        }
+        // A preview that is still open may refuse to go; it gets another chance next run
+        match std::fs::remove_file(path) {
+            Ok(()) => {
+                total -= size;
+                removed += 1;
+                freed += size;
+            }
+            Err(e) => eprintln!("Failed to evict preview {}: {}", path.display(), e),
+        }
+    }
+    Ok(format!(
+        "Removed {} previews ({} MB), {} MB kept",
+        removed,
+        freed / 1_000_000,
+        total / 1_000_000
+    ))
+}
+
+async fn execute(state: &AppState, job: Job, manual: bool) -> Result<String, AppError> {
+    let settings = state.settings.get();
+    if !job.needs_client() {
+        return evict_previews(
+            &crate::preview_dir(),
+            settings.preview_cache_max_age_days,
+            settings.preview_cache_max_mb * 1_000_000,
+        );
+    }
+
+    let account = state.accounts.active();
+    let client = ready_client(state, &account).await?;
+    match job {
+        Job::TrashExpiry => {
+            account
+                .personal_db
+                .queue_trash_cleanup(settings.trash_retention_days as i64)?;
+            let run = deletion::process_account(&client, &account).await;
+            Ok(format!(
+                "{} deleted, {} still pending",
+                run.deleted, run.pending
+            ))
+        }
+        Job::Backup => {
+            backups::run_auto_backup(&client, &account, settings.keep_backups, manual).await
+        }
+        // Only reports; deleting stays a user decision in the GC screen
+        Job::OrphanGc => {
+            let report =
+                gc::scan(&state.app_handle, &client, &account, settings.keep_backups).await?;
+            Ok(format!(
+                "{} orphaned uploads ({} MB), {} backups to prune",
+                report.orphans.len(),
+                report.orphan_bytes / 1_000_000,
+                report.backups_to_prune.len()
+            ))
+        }
+        Job::Scrub => {
+            let report = scrub::run(&state.app_handle, &client, &account, &settings).await?;
+            Ok(format!(
+                "{} checked, {} failed, {} hashes verified, {} copies recreated",
+                report.checked, report.failed, report.hash_verified, report.repair.recreated
+            ))
+        }
+        Job::PreviewCache => unreachable!(),
+    }
+}
+
+/// Runs `job` now and records the outcome. Scheduled runs that can't start
+/// (locked, offline, logged out) are dropped without a record and retried on
+/// the next tick; manual ones report why.
+pub async fn run_job(
+    app_handle: &AppHandle,
+    job: Job,
+    manual: bool,
+) -> Result<JobStatus, AppError> {
+    let state = app_handle.state::<AppState>();
+    let Some(_guard) = state.maintenance.start(job) else {
+        return Err(format!("{:?} is already running", job).into());
+    };
+
+    if !manual && job.needs_client() {
+        let account = state.accounts.active();
+        ready_client(&state, &account).await?;
+    }
+
+    println!("Maintenance: running {:?}", job);
+    let at = chrono::Utc::now().timestamp();
+    let started = Instant::now();
+    let result = execute(&state, job, manual).await;
+    if let Err(e) = &result {
+        eprintln!("Maintenance: {:?} failed: {}", job, e);
+    }
+    state.maintenance.record(job, at, started.elapsed(), result);
+
+    drop(_guard);
+    let status = state.maintenance.status(job, &state.settings.get());
+    let _ = app_handle.emit("maintenance-job", &status);
+    Ok(status)
+}
+
+/// Starts every job whose next run has come. Jobs run side by side; a job that
+/// is still going is not started twice.
+pub fn spawn_scheduler(app_handle: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(TICK_SECS)).await;
+            let state = app_handle.state::<AppState>();
+            let settings = state.settings.get();
+            let now = chrono::Utc::now().timestamp();
+            for job in Job::ALL {
+                let status = state.maintenance.status(job, &settings);
+                if status.running || status.next_run > now {
+                    continue;
+                }
+                let app_handle = app_handle.clone();
+                tauri::async_runtime::spawn(async move {
+                    // Skipped runs are expected here, e.g. before login
+                    let _ = run_job(&app_handle, job, false).await;
+                });
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub(crate) fn get_maintenance_status(state: State<AppState>) -> Result<Vec<JobStatus>, AppError> {
+    let settings = state.settings.get();
+    Ok(Job::ALL
+        .iter()
+        .map(|job| state.maintenance.status(*job, &settings))
+        .collect())
+}
+
+/// Runs a job right away, whatever its schedule. A backup run this way is made
+/// even when not due.
+#[tauri::command]
+pub(crate) async fn run_maintenance_job(
+    job: Job,
+    app_handle: AppHandle,
+) -> Result<JobStatus, AppError> {
+    run_job(&app_handle, job, true).await
+}
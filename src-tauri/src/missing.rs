@@ -0,0 +1,252 @@
+use grammers_client::types::{InputMessage, MessageDeletion};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Emitter, State};
+
+use crate::accounts::Account;
+use crate::db::{Database, FileMetadata, Replica, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::recovery::FileCaption;
+use crate::{storage, AppState};
+
+const LOCAL_COPIES_FILENAME: &str = "local_copies.json";
+
+/// Where files were uploaded from or downloaded to on this machine. Kept out of
+/// metadata.json because the paths mean nothing on other devices.
+pub struct LocalCopies {
+    path: PathBuf,
+    paths: Mutex<HashMap<String, String>>,
+}
+
+impl LocalCopies {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(LOCAL_COPIES_FILENAME);
+        let paths = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default();
+        LocalCopies {
+            path,
+            paths: Mutex::new(paths),
+        }
+    }
+
+    // Called after the transfer already worked, so a failed write is only logged
+    pub fn remember(&self, file_id: &str, local_path: &str) {
+        let mut paths = self.paths.lock().unwrap();
+        paths.insert(file_id.to_string(), local_path.to_string());
+        let result = File::create(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| {
+                serde_json::to_writer(BufWriter::new(f), &*paths).map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            eprintln!("Failed to save local copies: {}", e);
+        }
+    }
+
+    /// The remembered path, if the file is still there.
+    pub fn find(&self, file_id: &str) -> Option<String> {
+        let paths = self.paths.lock().unwrap();
+        paths
+            .get(file_id)
+            .filter(|p| Path::new(p).exists())
+            .cloned()
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct MissingFile {
+    pub file: FileMetadata,
+    pub local_copy: Option<String>,
+}
+
+/// Stops `file` pointing at the locations `gone` matches, promoting a replica if
+/// the primary is one of them. Returns false, changing nothing, when no copy is left.
+pub(crate) fn drop_locations(
+    db: &Database,
+    file: &FileMetadata,
+    gone: impl Fn(&Replica) -> bool,
+) -> bool {
+    let mut left: Vec<Replica> = file.locations().into_iter().filter(|l| !gone(l)).collect();
+    if left.is_empty() {
+        return false;
+    }
+    let primary = left.remove(0);
+    if primary.peer != file.peer || primary.message_id != file.message_id {
+        println!(
+            "Primary copy of {} was deleted, promoting a replica",
+            file.name
+        );
+        if let Err(e) = db.relocate_file(&file.id, primary.peer, primary.message_id) {
+            eprintln!("Failed to promote replica: {}", e);
+        }
+    }
+    if let Err(e) = db.set_replicas(&file.id, left) {
+        eprintln!("Failed to drop deleted replica: {}", e);
+    }
+    true
+}
+
+// Files that lost their last copy are flagged and returned. Files with a copy
+// left just stop pointing at the deleted one.
+fn mark_deleted(
+    db: &Database,
+    matches_peer: impl Fn(&StoragePeer) -> bool,
+    message_ids: &[i32],
+) -> Vec<FileMetadata> {
+    let gone = |l: &Replica| matches_peer(&l.peer) && message_ids.contains(&l.message_id);
+    let mut affected = Vec::new();
+    for file in db.get_all_files().into_iter().filter(|f| !f.missing) {
+        if file.locations().iter().any(gone) && !drop_locations(db, &file, gone) {
+            affected.push(file);
+        }
+    }
+    if !affected.is_empty() {
+        let ids: Vec<String> = affected.iter().map(|f| f.id.clone()).collect();
+        if let Err(e) = db.mark_missing(&ids) {
+            eprintln!("Failed to flag missing files: {}", e);
+        }
+    }
+    affected
+}
+
+/// Reacts to messages deleted by another client. Private-chat deletions carry no
+/// chat id, but message ids are unique per account there, so a Saved Messages
+/// entry with the same id is the one that went away.
+pub fn handle_deletion(app_handle: &AppHandle, account: &Account, deletion: &MessageDeletion) {
+    let ids = deletion.messages();
+    let mut affected = match deletion.channel_id() {
+        None => mark_deleted(
+            &account.personal_db,
+            |peer| *peer == StoragePeer::SavedMessages,
+            ids,
+        ),
+        Some(channel_id) => {
+            let in_channel = |peer: &StoragePeer| match peer {
+                StoragePeer::Channel { id, .. } => *id == channel_id,
+                StoragePeer::SavedMessages => false,
+            };
+            let mut affected = mark_deleted(&account.personal_db, in_channel, ids);
+            for info in account.shared.list() {
+                if let Some(drive) = account.shared.get(&info.id) {
+                    affected.extend(mark_deleted(&drive.db, in_channel, ids));
+                }
+            }
+            affected
+        }
+    };
+    if affected.is_empty() {
+        return;
+    }
+
+    println!("{} files were deleted outside the app", affected.len());
+    let missing: Vec<MissingFile> = affected
+        .drain(..)
+        .map(|mut file| {
+            file.missing = true;
+            MissingFile {
+                local_copy: account.local_copies.find(&file.id),
+                file,
+            }
+        })
+        .collect();
+    let _ = app_handle.emit("files-missing", &missing);
+}
+
+// Makes sure `path` holds the same content as the entry before it replaces the
+// lost copy. Entries from before hashing only get the size check.
+async fn check_local_copy(path: &str, file: &FileMetadata) -> Result<(), AppError> {
+    let size = tokio::fs::metadata(path).await?.len();
+    if size != file.size as u64 {
+        return Err(format!(
+            "{} is {} bytes but {} was {} bytes",
+            path, size, file.name, file.size
+        )
+        .into());
+    }
+    let Some(expected) = file.hash.clone() else {
+        return Ok(());
+    };
+    let local = path.to_string();
+    let hash = tokio::task::spawn_blocking(move || -> Result<String, AppError> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(&local)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(|e| format!("Hashing failed: {}", e))??;
+    if hash != expected {
+        return Err(format!("{} has different content than {}", path, file.name).into());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) async fn list_missing_files(
+    state: State<'_, AppState>,
+) -> Result<Vec<MissingFile>, AppError> {
+    let account = state.accounts.active();
+    Ok(account
+        .db()
+        .get_all_files()
+        .into_iter()
+        .filter(|f| f.missing)
+        .map(|file| MissingFile {
+            local_copy: account.local_copies.find(&file.id),
+            file,
+        })
+        .collect())
+}
+
+/// Uploads the file again from `path`, or from the remembered local copy, into the
+/// current storage target and points the existing entry at the new message.
+#[tauri::command]
+pub(crate) async fn reupload_missing_file(
+    file_id: String,
+    path: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<FileMetadata, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    let db = account.db();
+    let file = db.get_file(&file_id).ok_or("File not found")?;
+    let path = path
+        .or_else(|| account.local_copies.find(&file_id))
+        .ok_or("No local copy known for this file")?;
+    check_local_copy(&path, &file).await?;
+
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    println!("Re-uploading {} from {}", file.name, path);
+    let uploaded = rpc!("upload.saveFilePart", client.upload_file(&path))?;
+
+    let caption = FileCaption {
+        version: 1,
+        id: file.id.clone(),
+        path: db.folder_path(file.folder_id.as_deref()),
+        name: file.name.clone(),
+        size: file.size,
+        hash: file.hash.clone(),
+    };
+    let target = account.storage_target();
+    let chat = storage::resolve_peer(&client, &target).await?;
+    let text = caption.encode(account.caption_vault(&target).as_deref());
+    let msg = rpc!(
+        "messages.sendMedia",
+        client.send_message(chat, InputMessage::text(&text).document(uploaded.clone()))
+    )?;
+
+    db.relocate_file(&file.id, target, msg.id())?;
+    account.local_copies.remember(&file.id, &path);
+    db.get_file(&file.id)
+        .ok_or_else(|| AppError::not_found(&file.id))
+}
@@ -0,0 +1,437 @@
+use grammers_client::types::{Downloadable, InputMessage, Media, Message, PackedChat};
+use grammers_client::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::Duration;
+use tauri::{Emitter, Manager, State};
+use uuid::Uuid;
+
+use crate::crypto;
+use crate::db::{Database, DriveEvent, Operation, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::{self, rpc};
+use crate::{storage, AppState};
+
+const SYNC_FILENAME: &str = "device_sync.json";
+const SYNC_TAG: &str = "#paperfold_ops";
+// Posted once in Saved Messages so every device of the account finds the same channel
+const SYNC_POINTER_TAG: &str = "#paperfold_sync_channel";
+const SYNC_CHANNEL_TITLE: &str = "Paperfold Sync";
+const SYNC_INTERVAL_SECS: u64 = 20;
+// Batches longer than this go out as a small JSON attachment instead of message text
+const MAX_INLINE_LEN: usize = 3500;
+
+/// Sends everything in the outbox of `db` to `chat`, a batch per message, and
+/// acknowledges each batch as soon as it is posted.
+pub async fn publish_outbox(
+    client: &Client,
+    chat: PackedChat,
+    tag: &str,
+    db: &Database,
+) -> Result<usize, AppError> {
+    let pending = db.pending_operations();
+    let mut published = 0;
+    let mut batch: Vec<Operation> = Vec::new();
+    let mut batch_len = 0;
+
+    for mut op in pending {
+        // Thumbnails are far too large for a message; receivers fetch their own
+        if let DriveEvent::FileAdded { file } = &mut op.event {
+            file.thumbnail = None;
+        }
+        let len = serde_json::to_string(&op).map_err(AppError::storage)?.len();
+        if !batch.is_empty() && batch_len + len > MAX_INLINE_LEN {
+            post_batch(client, chat, tag, &batch).await?;
+            published += batch.len();
+            db.ack_operations(batch.len())?;
+            batch.clear();
+            batch_len = 0;
+        }
+        batch_len += len;
+        batch.push(op);
+    }
+
+    if !batch.is_empty() {
+        post_batch(client, chat, tag, &batch).await?;
+        published += batch.len();
+        db.ack_operations(batch.len())?;
+    }
+    Ok(published)
+}
+
+async fn post_batch(
+    client: &Client,
+    chat: PackedChat,
+    tag: &str,
+    batch: &[Operation],
+) -> Result<(), AppError> {
+    let json = serde_json::to_string(batch).map_err(AppError::storage)?;
+    // Device sync is encrypted like backups. Shared drive events are not: the
+    // other members don't have this passphrase.
+    let vault = crypto::vault().filter(|_| tag == SYNC_TAG);
+    let (text, uploaded) = if json.len() <= MAX_INLINE_LEN && vault.is_none() {
+        (format!("{}\n{}", tag, json), None)
+    } else {
+        let (bytes, name) = match &vault {
+            Some(vault) => (vault.encrypt(json.as_bytes()), "operations.json.enc"),
+            None => (json.into_bytes(), "operations.json"),
+        };
+        let size = bytes.len();
+        let uploaded = rpc!(
+            "upload.saveFilePart",
+            client.upload_stream(&mut bytes.as_slice(), size, name.to_string())
+        )?;
+        (tag.to_string(), Some(uploaded))
+    };
+    // InputMessage is not Clone, so it is rebuilt on every attempt
+    let message = || match &uploaded {
+        Some(uploaded) => InputMessage::text(&text).document(uploaded.clone()),
+        None => InputMessage::text(&text),
+    };
+
+    rpc!("messages.sendMessage", client.send_message(chat, message()))?;
+    Ok(())
+}
+
+// A failed download is an error, so the cursor stays put and the batch is read
+// again next time. Only a complete batch that doesn't parse is skipped.
+async fn read_batch(
+    client: &Client,
+    msg: &Message,
+    tag: &str,
+) -> Result<Option<Vec<Operation>>, AppError> {
+    let text = msg.text();
+    if !text.starts_with(tag) {
+        return Ok(None);
+    }
+
+    let payload = match msg.media() {
+        Some(Media::Document(doc)) => {
+            let mut bytes = Vec::new();
+            let mut download = client.iter_download(&Downloadable::Media(Media::Document(doc)));
+            while let Some(chunk) = rpc!("upload.getFile", download.next())? {
+                bytes.extend(chunk);
+            }
+            if crypto::is_encrypted(&bytes) {
+                crypto::vault()
+                    .ok_or_else(|| AppError::Locked("Metadata is locked".to_string()))?
+                    .decrypt(&bytes)
+                    .map_err(AppError::Storage)?
+            } else {
+                bytes
+            }
+        }
+        _ => text[tag.len()..].trim().as_bytes().to_vec(),
+    };
+
+    match serde_json::from_slice(&payload) {
+        Ok(batch) => Ok(Some(batch)),
+        Err(e) => {
+            eprintln!("Ignoring malformed operation batch {}: {}", msg.id(), e);
+            Ok(None)
+        }
+    }
+}
+
+/// Reads every operation posted after message `after_id`, oldest first.
+/// Returns them with the id of the newest message seen. With `senders`, posts
+/// from anyone else are skipped.
+pub async fn pull_operations(
+    client: &Client,
+    chat: PackedChat,
+    tag: &str,
+    after_id: i32,
+    senders: Option<&HashSet<i64>>,
+) -> Result<(Vec<Operation>, i32), AppError> {
+    // Newest first from Telegram; stop at what we already have
+    let mut fresh = Vec::new();
+    let mut messages = client.iter_messages(chat);
+    while let Some(msg) = rpc!("messages.getHistory", messages.next())? {
+        if msg.id() <= after_id {
+            break;
+        }
+        fresh.push(msg);
+    }
+    fresh.reverse();
+
+    let mut ops = Vec::new();
+    let mut last_id = after_id;
+    for msg in &fresh {
+        last_id = msg.id();
+        if let Some(senders) = senders {
+            let sender = msg.sender().map(|s| s.id());
+            if !sender.is_some_and(|id| senders.contains(&id)) {
+                eprintln!("Ignoring operations {} from a non-admin", msg.id());
+                continue;
+            }
+        }
+        if let Some(batch) = read_batch(client, msg, tag).await? {
+            ops.extend(batch);
+        }
+    }
+    Ok((ops, last_id))
+}
+
+/// Merges `ops` into `db` and fetches thumbnails for files that are new to it.
+/// Returns how many operations changed something.
+pub async fn merge_operations(
+    client: &Client,
+    db: &Database,
+    ops: &[Operation],
+) -> Result<usize, AppError> {
+    let mut merged = 0;
+    let mut new_files = Vec::new();
+
+    for op in ops {
+        let is_new_file = match &op.event {
+            DriveEvent::FileAdded { file } => db.get_file(&file.id).is_none(),
+            _ => false,
+        };
+        if db.merge_operation(op)? {
+            merged += 1;
+            if is_new_file {
+                if let DriveEvent::FileAdded { file } = &op.event {
+                    new_files.push(file.clone());
+                }
+            }
+        }
+    }
+
+    for file in new_files {
+        if let Ok(messages) = storage::fetch_messages(client, &file.peer, &[file.message_id]).await
+        {
+            if let Some(Some(msg)) = messages.first() {
+                let thumbnail = crate::extract_thumbnail_base64(client, msg).await;
+                db.set_thumbnail(&file.id, thumbnail)?;
+            }
+        }
+    }
+    Ok(merged)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceSyncConfig {
+    pub device_id: String,
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub channel: Option<StoragePeer>,
+    #[serde(default)]
+    pub last_op_id: i32,
+    #[serde(default)]
+    pub last_sync: Option<i64>,
+}
+
+impl DeviceSyncConfig {
+    /// Loads the per-install sync state. The device id is created on first load and
+    /// never leaves this machine's account directory.
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(SYNC_FILENAME);
+        let loaded: Option<DeviceSyncConfig> = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok());
+        match loaded {
+            Some(config) => config,
+            None => {
+                let config = DeviceSyncConfig {
+                    device_id: Uuid::new_v4().to_string(),
+                    enabled: false,
+                    channel: None,
+                    last_op_id: 0,
+                    last_sync: None,
+                };
+                // A new id every start until this works, but nothing is lost before sync is on
+                if let Err(e) = config.save(dir) {
+                    eprintln!("Failed to save device sync config: {}", e);
+                }
+                config
+            }
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), AppError> {
+        let file = File::create(dir.join(SYNC_FILENAME)).map_err(AppError::storage)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, self).map_err(AppError::storage)
+    }
+}
+
+// Looks for the sync channel another device of this account already set up
+async fn find_sync_channel(client: &Client) -> Result<Option<StoragePeer>, AppError> {
+    let me = gateway::global().get_me(client).await?;
+    let mut messages = client.search_messages(me).query(SYNC_POINTER_TAG);
+    while let Some(msg) = rpc!("messages.search", messages.next())? {
+        let text = msg.text();
+        if let Some(rest) = text.strip_prefix(SYNC_POINTER_TAG) {
+            let packed = rest.trim();
+            if let Ok(chat) = PackedChat::from_hex(packed) {
+                return Ok(Some(StoragePeer::Channel {
+                    id: chat.id,
+                    packed: packed.to_string(),
+                }));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Pushes this device's personal-drive operations and merges everyone else's.
+pub async fn sync_devices(
+    client: &Client,
+    account: &crate::accounts::Account,
+    app_handle: &tauri::AppHandle,
+) -> Result<usize, AppError> {
+    if account.personal_db.is_locked() {
+        return Err(AppError::Locked("Metadata is locked".to_string()));
+    }
+    let _guard = account.device_sync_lock.lock().await;
+    let config = account.device_sync.lock().unwrap().clone();
+    if !config.enabled {
+        return Err("Device sync is not enabled".into());
+    }
+    let channel = config.channel.ok_or("Device sync has no channel")?;
+    let chat = storage::resolve_peer(client, &channel).await?;
+    let db = &account.personal_db;
+
+    let published = publish_outbox(client, chat, SYNC_TAG, db).await?;
+    if published > 0 {
+        println!("Published {} operations to sync channel", published);
+    }
+
+    let (ops, last_id) = pull_operations(client, chat, SYNC_TAG, config.last_op_id, None).await?;
+    let merged = merge_operations(client, db, &ops).await?;
+
+    let mut config = account.device_sync.lock().unwrap();
+    config.last_op_id = last_id;
+    config.last_sync = Some(chrono::Utc::now().timestamp());
+    // Merging is idempotent, so a cursor that didn't save only means a re-read
+    config.save(&account.dir)?;
+    drop(config);
+
+    if merged > 0 {
+        let _ = app_handle.emit("device-sync-updated", merged);
+    }
+    Ok(merged)
+}
+
+pub fn spawn_sync_loop(app_handle: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(SYNC_INTERVAL_SECS)).await;
+
+            let state = app_handle.state::<AppState>();
+            if state.encryption.is_locked() {
+                continue;
+            }
+            let account = state.accounts.active();
+            if !account.device_sync.lock().unwrap().enabled {
+                continue;
+            }
+            let client = match account.client.lock().await.as_ref() {
+                Some(client) => client.clone(),
+                None => continue,
+            };
+
+            if let Err(e) = sync_devices(&client, &account, &app_handle).await {
+                eprintln!("Device sync failed: {}", e);
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct DeviceSyncStatus {
+    pub device_id: String,
+    pub enabled: bool,
+    pub channel: Option<StoragePeer>,
+    pub last_sync: Option<i64>,
+    pub pending: usize,
+}
+
+#[tauri::command]
+pub(crate) async fn get_device_sync_status(
+    state: State<'_, AppState>,
+) -> Result<DeviceSyncStatus, AppError> {
+    let account = state.accounts.active();
+    let config = account.device_sync.lock().unwrap().clone();
+    let pending = if config.enabled {
+        account.personal_db.pending_operations().len()
+    } else {
+        0
+    };
+    Ok(DeviceSyncStatus {
+        device_id: config.device_id,
+        enabled: config.enabled,
+        channel: config.channel,
+        last_sync: config.last_sync,
+        pending,
+    })
+}
+
+/// Turns on operation logging for the personal drive. Joins the sync channel set
+/// up by another device of the same account, or creates one if this is the first.
+#[tauri::command]
+pub(crate) async fn enable_device_sync(state: State<'_, AppState>) -> Result<usize, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let mut config = account.device_sync.lock().unwrap().clone();
+    if config.enabled {
+        return sync_devices(&client, &account, &state.app_handle).await;
+    }
+
+    if config.channel.is_none() {
+        config.channel = match find_sync_channel(&client).await? {
+            Some(channel) => Some(channel),
+            None => {
+                println!("Creating device sync channel");
+                let channel = storage::create_storage_channel(&client, SYNC_CHANNEL_TITLE).await?;
+                if let StoragePeer::Channel { packed, .. } = &channel {
+                    let me = gateway::global().get_me(&client).await?.pack();
+                    let pointer = format!("{} {}", SYNC_POINTER_TAG, packed);
+                    rpc!(
+                        "messages.sendMessage",
+                        client.send_message(me, InputMessage::text(&pointer))
+                    )?;
+                }
+                Some(channel)
+            }
+        };
+    }
+
+    config.enabled = true;
+    config.save(&account.dir)?;
+    *account.device_sync.lock().unwrap() = config;
+
+    // Everything made before sync was on has to reach the other devices as well
+    account.personal_db.set_journaling(true);
+    account.personal_db.queue_snapshot()?;
+
+    sync_devices(&client, &account, &state.app_handle).await
+}
+
+#[tauri::command]
+pub(crate) async fn disable_device_sync(state: State<'_, AppState>) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    let mut config = account.device_sync.lock().unwrap().clone();
+    config.enabled = false;
+    config.save(&account.dir)?;
+    *account.device_sync.lock().unwrap() = config;
+    account.personal_db.set_journaling(false);
+    // Whatever was not published yet goes out again in the snapshot on re-enable
+    account.personal_db.ack_operations(usize::MAX)
+}
+
+#[tauri::command]
+pub(crate) async fn sync_now(state: State<'_, AppState>) -> Result<usize, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+    sync_devices(&client, &account, &state.app_handle).await
+}
@@ -0,0 +1,382 @@
+use grammers_client::{Client, Config, InitParams};
+use grammers_session::Session;
+use grammers_tl_types as tl;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::State;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::connection;
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::AppState;
+
+const PROXY_FILENAME: &str = "proxy.json";
+const PROBE_TIMEOUT_SECS: u64 = 10;
+const TEST_TIMEOUT_SECS: u64 = 30;
+
+/// How every Telegram connection leaves this machine. App-wide, not per account.
+/// grammers only speaks SOCKS5, so that is the only kind of proxy.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProxyConfig {
+    #[default]
+    None,
+    Socks5 {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+}
+
+impl ProxyConfig {
+    pub fn validate(&self) -> Result<(), AppError> {
+        let (host, port) = match self {
+            ProxyConfig::None => return Ok(()),
+            ProxyConfig::Socks5 {
+                host,
+                port,
+                username,
+                password,
+            } => {
+                if username.as_deref().is_some_and(|u| u.len() > 255)
+                    || password.as_deref().is_some_and(|p| p.len() > 255)
+                {
+                    return Err("SOCKS5 username and password are limited to 255 bytes".into());
+                }
+                (host, port)
+            }
+        };
+        if host.trim().is_empty() {
+            return Err("Proxy host cannot be empty".into());
+        }
+        if *port == 0 {
+            return Err("Proxy port cannot be 0".into());
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(not(feature = "proxy"), allow(dead_code))]
+    fn socks5_url(
+        host: &str,
+        port: u16,
+        username: &Option<String>,
+        password: &Option<String>,
+    ) -> String {
+        let host = if host.contains(':') {
+            format!("[{}]", host) // IPv6
+        } else {
+            host.to_string()
+        };
+        match username.as_deref().filter(|u| !u.is_empty()) {
+            Some(user) => format!(
+                "socks5://{}:{}@{}:{}",
+                percent_encode(user),
+                percent_encode(password.as_deref().unwrap_or("")),
+                host,
+                port
+            ),
+            None => format!("socks5://{}:{}", host, port),
+        }
+    }
+
+    /// Points a client at the proxy.
+    #[cfg_attr(not(feature = "proxy"), allow(unused_variables))]
+    pub fn apply(&self, params: &mut InitParams) -> Result<(), AppError> {
+        match self {
+            ProxyConfig::None => Ok(()),
+            ProxyConfig::Socks5 {
+                host,
+                port,
+                username,
+                password,
+            } => {
+                #[cfg(feature = "proxy")]
+                {
+                    params.proxy_url = Some(Self::socks5_url(host, *port, username, password));
+                    Ok(())
+                }
+                #[cfg(not(feature = "proxy"))]
+                Err("This build has no SOCKS5 support".into())
+            }
+        }
+    }
+}
+
+// Userinfo in a URL may not contain these unescaped
+#[cfg_attr(not(feature = "proxy"), allow(dead_code))]
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub struct ProxySettings {
+    path: PathBuf,
+    config: Mutex<ProxyConfig>,
+}
+
+impl ProxySettings {
+    pub fn load(app_dir: &Path) -> Self {
+        let path = app_dir.join(PROXY_FILENAME);
+        let config = match File::open(&path) {
+            Ok(f) => serde_json::from_reader(BufReader::new(f)).unwrap_or_else(|e| {
+                // e.g. an MTProxy setting from an older build
+                eprintln!(
+                    "Ignoring unreadable proxy settings, connecting directly: {}",
+                    e
+                );
+                ProxyConfig::None
+            }),
+            Err(_) => ProxyConfig::None,
+        };
+        ProxySettings {
+            path,
+            config: Mutex::new(config),
+        }
+    }
+
+    pub fn get(&self) -> ProxyConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    fn set(&self, config: ProxyConfig) -> Result<(), AppError> {
+        let file = File::create(&self.path).map_err(AppError::storage)?;
+        serde_json::to_writer(BufWriter::new(file), &config).map_err(AppError::storage)?;
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+}
+
+// Greets the proxy and logs in if asked to; enough to tell a dead proxy, a wrong
+// port and wrong credentials apart
+async fn probe_socks5(
+    host: &str,
+    port: u16,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<(), String> {
+    let mut stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| format!("Cannot reach proxy: {}", e))?;
+    let io_err = |e: std::io::Error| format!("Proxy closed the connection: {}", e);
+
+    let username = username.filter(|u| !u.is_empty());
+    let method = if username.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await.map_err(io_err)?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.map_err(io_err)?;
+    if reply[0] != 5 {
+        return Err("Not a SOCKS5 proxy".to_string());
+    }
+    match (reply[1], username) {
+        (0, None) => Ok(()),
+        (2, Some(user)) => {
+            let pass = password.unwrap_or("");
+            let mut login = vec![1, user.len() as u8];
+            login.extend_from_slice(user.as_bytes());
+            login.push(pass.len() as u8);
+            login.extend_from_slice(pass.as_bytes());
+            stream.write_all(&login).await.map_err(io_err)?;
+            stream.read_exact(&mut reply).await.map_err(io_err)?;
+            if reply[1] != 0 {
+                return Err("Proxy rejected the username or password".to_string());
+            }
+            Ok(())
+        }
+        (0xFF, None) => Err("Proxy requires a username and password".to_string()),
+        (0xFF, Some(_)) => Err("Proxy does not accept username and password login".to_string()),
+        (m, _) => Err(format!("Proxy asked for unsupported auth method {}", m)),
+    }
+}
+
+async fn probe(config: &ProxyConfig) -> Result<(), String> {
+    match config {
+        ProxyConfig::None => Ok(()),
+        ProxyConfig::Socks5 {
+            host,
+            port,
+            username,
+            password,
+        } => probe_socks5(host, *port, username.as_deref(), password.as_deref()).await,
+    }
+}
+
+// A throwaway client on an empty session, so testing never touches a login
+async fn reach_telegram(config: &ProxyConfig) -> Result<(), AppError> {
+    let (api_id, api_hash) =
+        connection::api_credentials().ok_or("API Credentials missing in .env")?;
+    let mut params = InitParams::default();
+    config.apply(&mut params)?;
+    let client = Client::connect(Config {
+        session: Session::new(),
+        api_id,
+        api_hash,
+        params,
+    })
+    .await?;
+    rpc!(
+        "help.getNearestDc",
+        client.invoke(&tl::functions::help::GetNearestDc {})
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct ProxyTestResult {
+    pub ok: bool,
+    // Handshake with the proxy itself; None when going direct or it failed
+    pub proxy_latency_ms: Option<u64>,
+    // Connecting to Telegram and one request, through the proxy if any
+    pub telegram_latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[tauri::command]
+pub(crate) fn get_proxy_settings(state: State<AppState>) -> Result<ProxyConfig, AppError> {
+    Ok(state.proxy.get())
+}
+
+/// Saves the proxy and reconnects the active account through it.
+#[tauri::command]
+pub(crate) fn set_proxy_settings(
+    config: ProxyConfig,
+    state: State<AppState>,
+) -> Result<(), AppError> {
+    config.validate()?;
+    println!("Proxy settings changed, reconnecting");
+    state.proxy.set(config)?;
+    state.connection.reconnect();
+    Ok(())
+}
+
+/// Tries `config` without saving it: first the proxy alone, then Telegram through it.
+#[tauri::command]
+pub(crate) async fn test_proxy(config: ProxyConfig) -> Result<ProxyTestResult, AppError> {
+    config.validate()?;
+    let mut result = ProxyTestResult {
+        ok: false,
+        proxy_latency_ms: None,
+        telegram_latency_ms: None,
+        error: None,
+    };
+
+    if config != ProxyConfig::None {
+        let started = Instant::now();
+        let probed =
+            tokio::time::timeout(Duration::from_secs(PROBE_TIMEOUT_SECS), probe(&config)).await;
+        match probed {
+            Ok(Ok(())) => result.proxy_latency_ms = Some(started.elapsed().as_millis() as u64),
+            Ok(Err(e)) => {
+                result.error = Some(e);
+                return Ok(result);
+            }
+            Err(_) => {
+                result.error = Some("Proxy did not answer".to_string());
+                return Ok(result);
+            }
+        }
+    }
+
+    let started = Instant::now();
+    let reached = tokio::time::timeout(
+        Duration::from_secs(TEST_TIMEOUT_SECS),
+        reach_telegram(&config),
+    )
+    .await;
+    match reached {
+        Ok(Ok(())) => {
+            result.ok = true;
+            result.telegram_latency_ms = Some(started.elapsed().as_millis() as u64);
+        }
+        Ok(Err(e)) => result.error = Some(e.to_string()),
+        Err(_) => result.error = Some("Telegram did not answer in time".to_string()),
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // One-connection SOCKS5 server that stops after the auth step. `login` is
+    // the username and password it wants, or None for no auth.
+    async fn socks5_server(login: Option<(&'static str, &'static str)>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = [0u8; 2];
+            stream.read_exact(&mut head).await.unwrap();
+            let mut methods = vec![0u8; head[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            let wanted = if login.is_some() { 2 } else { 0 };
+            if !methods.contains(&wanted) {
+                stream.write_all(&[5, 0xFF]).await.unwrap();
+                return;
+            }
+            stream.write_all(&[5, wanted]).await.unwrap();
+            let Some((user, pass)) = login else { return };
+
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut got_user = vec![0u8; len[1] as usize];
+            stream.read_exact(&mut got_user).await.unwrap();
+            stream.read_exact(&mut len[..1]).await.unwrap();
+            let mut got_pass = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut got_pass).await.unwrap();
+            let ok = got_user == user.as_bytes() && got_pass == pass.as_bytes();
+            stream
+                .write_all(&[1, if ok { 0 } else { 1 }])
+                .await
+                .unwrap();
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn probe_without_auth() {
+        let port = socks5_server(None).await;
+        assert_eq!(probe_socks5("127.0.0.1", port, None, None).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn probe_with_username_and_password() {
+        let port = socks5_server(Some(("alice", "secret"))).await;
+        let probed = probe_socks5("127.0.0.1", port, Some("alice"), Some("secret")).await;
+        assert_eq!(probed, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn probe_with_wrong_password() {
+        let port = socks5_server(Some(("alice", "secret"))).await;
+        let probed = probe_socks5("127.0.0.1", port, Some("alice"), Some("nope")).await;
+        assert_eq!(
+            probed,
+            Err("Proxy rejected the username or password".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_without_credentials_when_required() {
+        let port = socks5_server(Some(("alice", "secret"))).await;
+        assert_eq!(
+            probe_socks5("127.0.0.1", port, None, None).await,
+            Err("Proxy requires a username and password".to_string())
+        );
+    }
+}
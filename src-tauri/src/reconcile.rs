@@ -0,0 +1,330 @@
+use grammers_client::types::{Media, Message};
+use grammers_client::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tauri::{Emitter, State, Window};
+
+use crate::db::{FileMetadata, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::recovery::{FileCaption, CAPTION_TAG};
+use crate::{importer, storage, AppState, BACKUP_TAG};
+
+#[derive(Serialize, Clone)]
+pub struct MissingEntry {
+    pub file_id: String,
+    pub name: String,
+    pub peer: StoragePeer,
+    pub message_id: i32,
+    pub has_message: bool, // false: message gone, true: message left without media
+}
+
+#[derive(Serialize, Clone)]
+pub struct MismatchEntry {
+    pub file_id: String,
+    pub name: String,
+    pub size: i64,
+    pub message_name: String,
+    pub message_size: i64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct UnreferencedMedia {
+    pub peer: StoragePeer,
+    pub message_id: i32,
+    pub name: String,
+    pub size: i64,
+    pub date: i64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct DuplicateGroup {
+    pub peer: StoragePeer,
+    pub message_id: i32,
+    pub file_ids: Vec<String>,
+}
+
+/// Everything that disagrees between the index and Telegram. Nothing is changed
+/// while building it; see `apply_reconciliation`.
+#[derive(Serialize, Default)]
+pub struct ReconcileReport {
+    pub checked: usize,
+    pub missing: Vec<MissingEntry>,
+    pub mismatched: Vec<MismatchEntry>,
+    pub unreferenced: Vec<UnreferencedMedia>,
+    pub duplicates: Vec<DuplicateGroup>,
+}
+
+/// One fix picked by the user from a report.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ReconcileAction {
+    // Moves the entry to trash; it can still be restored from there
+    Trash {
+        file_id: String,
+    },
+    // Takes name and/or size from the message
+    Update {
+        file_id: String,
+        name: Option<String>,
+        size: Option<i64>,
+    },
+    // Creates an entry in the inbox folder for media nobody references
+    Adopt {
+        peer: StoragePeer,
+        message_id: i32,
+    },
+}
+
+// Our own bookkeeping messages: backups and operation batches. Uploads are captioned
+// with CAPTION_TAG and count as real files.
+fn is_bookkeeping(msg: &Message) -> bool {
+    let text = msg.text();
+    let tagged = text.starts_with("#paperfold_") && !text.starts_with(CAPTION_TAG);
+    tagged || text.contains(BACKUP_TAG)
+}
+
+// Document name and size as Telegram has them now
+fn message_details(msg: &Message) -> Option<(String, i64)> {
+    match msg.media()? {
+        Media::Document(doc) => Some((doc.name().to_string(), doc.size())),
+        Media::Photo(photo) => Some((String::new(), photo.size())),
+        _ => None,
+    }
+}
+
+fn check_file(file: &FileMetadata, msg: Option<&Message>, report: &mut ReconcileReport) {
+    let msg = match msg {
+        Some(msg) if msg.media().is_some() => msg,
+        other => {
+            report.missing.push(MissingEntry {
+                file_id: file.id.clone(),
+                name: file.name.clone(),
+                peer: file.peer.clone(),
+                message_id: file.message_id,
+                has_message: other.is_some(),
+            });
+            return;
+        }
+    };
+    let (message_name, message_size) = match message_details(msg) {
+        Some(details) => details,
+        None => return,
+    };
+
+    // A rename inside the app is not a mismatch: the caption still has the name
+    // the document was uploaded under
+    let uploaded_as = FileCaption::decode(msg.text())
+        .map(|c| c.name)
+        .unwrap_or_else(|| file.name.clone());
+    let name_differs = !message_name.is_empty() && message_name != uploaded_as;
+    if name_differs || message_size != file.size {
+        report.mismatched.push(MismatchEntry {
+            file_id: file.id.clone(),
+            name: file.name.clone(),
+            size: file.size,
+            message_name,
+            message_size,
+        });
+    }
+}
+
+/// Checks each entry's message against Telegram: gone, left without media, or a
+/// different file. Also groups entries sharing one message. `on_progress` gets
+/// the number checked so far after each batch.
+pub async fn check_index(
+    client: &Client,
+    files: &[FileMetadata],
+    batch_size: usize,
+    mut on_progress: impl FnMut(usize),
+) -> Result<ReconcileReport, AppError> {
+    let mut report = ReconcileReport {
+        checked: files.len(),
+        ..Default::default()
+    };
+
+    let mut by_message: HashMap<(StoragePeer, i32), Vec<String>> = HashMap::new();
+    for f in files {
+        by_message
+            .entry((f.peer.clone(), f.message_id))
+            .or_default()
+            .push(f.id.clone());
+    }
+    for ((peer, message_id), file_ids) in &by_message {
+        if file_ids.len() > 1 {
+            report.duplicates.push(DuplicateGroup {
+                peer: peer.clone(),
+                message_id: *message_id,
+                file_ids: file_ids.clone(),
+            });
+        }
+    }
+
+    let mut by_peer: HashMap<StoragePeer, Vec<&FileMetadata>> = HashMap::new();
+    for f in files {
+        by_peer.entry(f.peer.clone()).or_default().push(f);
+    }
+    let mut checked = 0;
+    for (peer, peer_files) in &by_peer {
+        for chunk in peer_files.chunks(batch_size) {
+            let ids: Vec<i32> = chunk.iter().map(|f| f.message_id).collect();
+            let messages = storage::fetch_messages(client, peer, &ids).await?;
+            for (file, msg) in chunk.iter().zip(messages.iter()) {
+                check_file(file, msg.as_ref(), &mut report);
+            }
+            checked += chunk.len();
+            on_progress(checked);
+        }
+    }
+    Ok(report)
+}
+
+#[tauri::command]
+pub(crate) async fn reconcile_files(
+    state: State<'_, AppState>,
+    window: Window,
+) -> Result<ReconcileReport, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let files = account.db().get_all_files();
+    println!("Reconciling {} files...", files.len());
+    let batch_size = state.settings.get().message_batch_size;
+    let mut report = check_index(&client, &files, batch_size, |checked| {
+        let _ = window.emit(
+            "reconcile-progress",
+            serde_json::json!({ "checked": checked, "total": files.len() }),
+        );
+    })
+    .await?;
+    // Replicas are referenced too, not strays
+    let referenced: HashSet<(StoragePeer, i32)> = files
+        .iter()
+        .flat_map(|f| f.locations())
+        .map(|l| (l.peer, l.message_id))
+        .collect();
+
+    // Media in the storage peers that no entry points at
+    let mut scan_peers: HashSet<StoragePeer> = HashSet::new();
+    scan_peers.insert(account.storage_target());
+    if account.shared.active().is_none() {
+        scan_peers.insert(StoragePeer::SavedMessages);
+    }
+    for peer in scan_peers {
+        let chat = storage::resolve_peer(&client, &peer).await?;
+        let mut messages = client.iter_messages(chat);
+        while let Some(msg) = rpc!("messages.getHistory", messages.next())? {
+            if is_bookkeeping(&msg) || referenced.contains(&(peer.clone(), msg.id())) {
+                continue;
+            }
+            if let Some((name, size, _)) = importer::describe_media(&msg) {
+                report.unreferenced.push(UnreferencedMedia {
+                    peer: peer.clone(),
+                    message_id: msg.id(),
+                    name,
+                    size,
+                    date: msg.date().timestamp(),
+                });
+            }
+        }
+    }
+
+    println!(
+        "Reconciliation: {} missing, {} mismatched, {} unreferenced, {} duplicates",
+        report.missing.len(),
+        report.mismatched.len(),
+        report.unreferenced.len(),
+        report.duplicates.len()
+    );
+    Ok(report)
+}
+
+#[tauri::command]
+pub(crate) async fn apply_reconciliation(
+    actions: Vec<ReconcileAction>,
+    state: State<'_, AppState>,
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    let db = account.db();
+    let mut applied = 0;
+    let mut failed = 0;
+
+    for action in actions {
+        println!("Reconcile: {:?}", action);
+        let ok = match action {
+            ReconcileAction::Trash { file_id } => {
+                if db.get_file(&file_id).is_some() {
+                    db.trash_item(&file_id, false)?;
+                    true
+                } else {
+                    false
+                }
+            }
+            ReconcileAction::Update {
+                file_id,
+                name,
+                size,
+            } => {
+                let renamed = match name {
+                    Some(n) => db.rename_file(&file_id, &n)?,
+                    None => true,
+                };
+                let resized = match size {
+                    Some(s) => db.set_file_size(&file_id, s)?,
+                    None => true,
+                };
+                renamed && resized
+            }
+            ReconcileAction::Adopt { peer, message_id } => {
+                let client_guard = account.client.lock().await;
+                let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+                drop(client_guard);
+
+                let messages = storage::fetch_messages(&client, &peer, &[message_id]).await?;
+                match messages.into_iter().next().flatten() {
+                    Some(msg) => match importer::describe_media(&msg) {
+                        Some((name, size, mime_type)) => {
+                            let inbox = account.storage.lock().unwrap().inbox_folder.clone();
+                            let folder_id = db.ensure_folder_path(&[inbox])?;
+                            let thumbnail = crate::extract_thumbnail_base64(&client, &msg).await;
+                            db.insert_file(FileMetadata {
+                                id: uuid::Uuid::new_v4().to_string(),
+                                folder_id,
+                                name,
+                                size,
+                                mime_type,
+                                message_id,
+                                created_at: msg.date().timestamp(),
+                                trashed: false,
+                                trashed_at: None,
+                                is_starred: false,
+                                thumbnail,
+                                peer,
+                                hash: None,
+                                missing: false,
+                                last_modified: 0,
+                                replicas: Vec::new(),
+                            })?;
+                            true
+                        }
+                        None => false,
+                    },
+                    None => false,
+                }
+            }
+        };
+        if ok {
+            applied += 1;
+        } else {
+            failed += 1;
+        }
+    }
+
+    Ok(format!(
+        "Applied {} changes, {} could not be applied.",
+        applied, failed
+    ))
+}
@@ -0,0 +1,329 @@
+use base64::{engine::general_purpose, Engine as _};
+use grammers_client::types::{Media, Message};
+use grammers_client::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tauri::{Emitter, State, Window};
+
+use crate::crypto::{self, Vault};
+use crate::db::{Database, FileMetadata, Replica, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::{storage, AppState};
+
+pub const CAPTION_TAG: &str = "#paperfold_file";
+// Telegram's caption limit for regular accounts
+const MAX_CAPTION_LEN: usize = 1024;
+
+/// What every upload writes into its message caption, so the tree can be rebuilt
+/// from Telegram alone. Keys are short to leave room for deep folder paths.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileCaption {
+    #[serde(rename = "v")]
+    pub version: u8,
+    pub id: String,
+    #[serde(rename = "p", default)]
+    pub path: Vec<String>,
+    #[serde(rename = "n")]
+    pub name: String,
+    #[serde(rename = "s")]
+    pub size: i64,
+    #[serde(rename = "h", default)]
+    pub hash: Option<String>,
+}
+
+impl FileCaption {
+    /// The caption text. With a `vault` the JSON is encrypted, so names and folder
+    /// paths don't sit on Telegram in the clear (see `Account::caption_vault`).
+    pub fn encode(&self, vault: Option<&Vault>) -> String {
+        let mut caption = self.clone();
+        loop {
+            let json = serde_json::to_string(&caption).unwrap();
+            let payload = match vault {
+                Some(vault) => general_purpose::STANDARD.encode(vault.encrypt(json.as_bytes())),
+                None => json,
+            };
+            let text = format!("{}\n{}", CAPTION_TAG, payload);
+            // Drop outer folders first; the file itself and its nearest parents matter most
+            if text.chars().count() <= MAX_CAPTION_LEN || caption.path.is_empty() {
+                return text;
+            }
+            caption.path.remove(0);
+        }
+    }
+
+    /// Reads a caption written by `encode`. Encrypted ones need the vault.
+    pub fn decode(text: &str) -> Option<FileCaption> {
+        Self::decode_with(text, crypto::vault().as_deref())
+    }
+
+    fn decode_with(text: &str, vault: Option<&Vault>) -> Option<FileCaption> {
+        let payload = text.strip_prefix(CAPTION_TAG)?.trim();
+        if payload.starts_with('{') {
+            return serde_json::from_str(payload).ok();
+        }
+        let blob = general_purpose::STANDARD.decode(payload).ok()?;
+        let json = vault?.decrypt(&blob).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+fn file_from_caption(
+    caption: FileCaption,
+    msg: &Message,
+    peer: &StoragePeer,
+    folder_id: Option<String>,
+) -> FileMetadata {
+    let mime_type = match msg.media() {
+        Some(Media::Document(doc)) => doc.mime_type().unwrap_or("").to_string(),
+        Some(Media::Photo(_)) => "image/jpeg".to_string(),
+        _ => String::new(),
+    };
+    let mime_type = if mime_type.is_empty() {
+        mime_guess::from_path(&caption.name)
+            .first_or_octet_stream()
+            .to_string()
+    } else {
+        mime_type
+    };
+
+    FileMetadata {
+        id: caption.id,
+        folder_id,
+        name: caption.name,
+        size: caption.size,
+        mime_type,
+        message_id: msg.id(),
+        created_at: msg.date().timestamp(),
+        trashed: false,
+        trashed_at: None,
+        is_starred: false,
+        thumbnail: None,
+        peer: peer.clone(),
+        hash: caption.hash,
+        missing: false,
+        last_modified: 0,
+        replicas: Vec::new(),
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct RebuildProgress {
+    scanned: usize,
+    recovered: usize,
+}
+
+/// Scans the full history of every storage chat for captioned uploads and recreates
+/// their folders and file entries. A second copy of a known file (same caption id,
+/// another chat) is attached as a replica. Messages already tracked are left alone,
+/// so it is safe to run on a partial store.
+#[tauri::command]
+pub(crate) async fn rebuild_from_telegram(
+    state: State<'_, AppState>,
+    window: Window,
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    account.ensure_writable()?;
+    let client_guard = account.client.lock().await;
+    let client: Client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let db = account.db();
+    let peers = match account.shared.active() {
+        Some(drive) => vec![drive.info().peer],
+        None => account.personal_peers(),
+    };
+
+    let existing = db.get_all_files();
+    let mut known_ids: HashSet<String> = existing.iter().map(|f| f.id.clone()).collect();
+    let mut known_messages: HashSet<(StoragePeer, i32)> = existing
+        .iter()
+        .flat_map(|f| f.locations())
+        .map(|l| (l.peer, l.message_id))
+        .collect();
+
+    println!("Rebuilding metadata from Telegram...");
+    let mut scanned: usize = 0;
+    let mut recovered = 0;
+    let mut replicas = 0;
+
+    for peer in peers {
+        let chat = storage::resolve_peer(&client, &peer).await?;
+        let mut messages = client.iter_messages(chat);
+
+        while let Some(msg) = rpc!("messages.getHistory", messages.next())? {
+            scanned += 1;
+            if scanned.is_multiple_of(200) {
+                let _ = window.emit("rebuild-progress", RebuildProgress { scanned, recovered });
+            }
+
+            if msg.media().is_none() {
+                continue;
+            }
+            let caption = match FileCaption::decode(msg.text()) {
+                Some(caption) => caption,
+                None => continue,
+            };
+            if !known_messages.insert((peer.clone(), msg.id())) {
+                continue;
+            }
+            if known_ids.contains(&caption.id) {
+                if attach_replica(&db, &caption.id, &peer, msg.id())? {
+                    replicas += 1;
+                }
+                continue;
+            }
+
+            known_ids.insert(caption.id.clone());
+            let folder_id = db.ensure_folder_path(&caption.path)?;
+            let mut file = file_from_caption(caption, &msg, &peer, folder_id);
+            file.thumbnail = crate::extract_thumbnail_base64(&client, &msg).await;
+            db.insert_file(file)?;
+            recovered += 1;
+        }
+    }
+
+    let _ = window.emit("rebuild-progress", RebuildProgress { scanned, recovered });
+    Ok(format!(
+        "Rebuild complete. Scanned {} messages, recovered {} files and {} replicas.",
+        scanned, recovered, replicas
+    ))
+}
+
+// One copy per chat: a duplicate upload in a chat that already holds the file
+// is left for GC rather than tracked twice
+fn attach_replica(
+    db: &Database,
+    id: &str,
+    peer: &StoragePeer,
+    message_id: i32,
+) -> Result<bool, AppError> {
+    let Some(file) = db.get_file(id) else {
+        return Ok(false);
+    };
+    if file.locations().iter().any(|l| l.peer == *peer) {
+        return Ok(false);
+    }
+    let mut replicas = file.replicas;
+    replicas.push(Replica {
+        peer: peer.clone(),
+        message_id,
+    });
+    db.set_replicas(id, replicas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caption(path: Vec<String>) -> FileCaption {
+        FileCaption {
+            version: 1,
+            id: "abc".to_string(),
+            path,
+            name: "report.pdf".to_string(),
+            size: 1234,
+            hash: Some("ff".repeat(32)),
+        }
+    }
+
+    #[test]
+    fn caption_round_trips() {
+        let text = caption(vec!["Work".to_string(), "2024".to_string()]).encode(None);
+        assert!(text.starts_with(CAPTION_TAG));
+        let decoded = FileCaption::decode(&text).unwrap();
+        assert_eq!(decoded.id, "abc");
+        assert_eq!(decoded.path, ["Work", "2024"]);
+        assert_eq!(decoded.name, "report.pdf");
+        assert_eq!(decoded.size, 1234);
+        assert_eq!(decoded.hash, Some("ff".repeat(32)));
+    }
+
+    #[test]
+    fn decode_ignores_other_messages() {
+        assert!(FileCaption::decode("just a photo").is_none());
+        assert!(FileCaption::decode(&format!("{}\nnot json", CAPTION_TAG)).is_none());
+    }
+
+    #[test]
+    fn decode_reads_captions_without_path_or_hash() {
+        let text = format!(
+            "{}\n{{\"v\":1,\"id\":\"x\",\"n\":\"a.txt\",\"s\":3}}",
+            CAPTION_TAG
+        );
+        let decoded = FileCaption::decode(&text).unwrap();
+        assert!(decoded.path.is_empty());
+        assert_eq!(decoded.hash, None);
+    }
+
+    #[test]
+    fn encrypted_caption_hides_names() {
+        let dir = std::env::temp_dir().join(format!("paperfold-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let vault = crypto::Encryption::new(&dir)
+            .create("correct horse")
+            .unwrap();
+
+        let text = caption(vec!["Secret folder".to_string()]).encode(Some(&vault));
+        assert!(text.starts_with(CAPTION_TAG));
+        assert!(!text.contains("Secret folder") && !text.contains("report.pdf"));
+        assert!(FileCaption::decode_with(&text, None).is_none());
+        let decoded = FileCaption::decode_with(&text, Some(&vault)).unwrap();
+        assert_eq!(decoded.name, "report.pdf");
+        assert_eq!(decoded.path, ["Secret folder"]);
+
+        let path: Vec<String> = (0..100).map(|i| format!("folder-{:03}", i)).collect();
+        let text = caption(path).encode(Some(&vault));
+        assert!(text.chars().count() <= MAX_CAPTION_LEN);
+    }
+
+    #[test]
+    fn long_paths_lose_outer_folders_first() {
+        let path: Vec<String> = (0..100).map(|i| format!("folder-{:03}", i)).collect();
+        let text = caption(path.clone()).encode(None);
+        assert!(text.chars().count() <= MAX_CAPTION_LEN);
+        let kept = FileCaption::decode(&text).unwrap().path;
+        assert!(!kept.is_empty() && kept.len() < path.len());
+        assert_eq!(kept[..], path[path.len() - kept.len()..]);
+    }
+
+    #[test]
+    fn length_counts_characters_not_bytes() {
+        // 2 bytes per char, so a byte count would drop folders that fit
+        let path = vec!["ü".repeat(450)];
+        let decoded = FileCaption::decode(&caption(path.clone()).encode(None)).unwrap();
+        assert_eq!(decoded.path, path);
+    }
+
+    #[test]
+    fn duplicates_in_other_chats_become_replicas() {
+        let dir = std::env::temp_dir().join(format!("paperfold-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(dir.to_str().unwrap());
+        let file = db
+            .add_file(
+                None,
+                "a.txt".into(),
+                1,
+                "text/plain".into(),
+                10,
+                None,
+                StoragePeer::SavedMessages,
+            )
+            .unwrap();
+        let channel = StoragePeer::Channel {
+            id: 1,
+            packed: String::new(),
+        };
+
+        assert!(attach_replica(&db, &file.id, &channel, 20).unwrap());
+        // Same chat twice, or the primary's own chat, adds nothing
+        assert!(!attach_replica(&db, &file.id, &channel, 21).unwrap());
+        assert!(!attach_replica(&db, &file.id, &StoragePeer::SavedMessages, 22).unwrap());
+        assert!(!attach_replica(&db, "unknown", &channel, 23).unwrap());
+
+        let replicas = db.get_file(&file.id).unwrap().replicas;
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].message_id, 20);
+    }
+}
@@ -0,0 +1,280 @@
+use grammers_client::types::Message;
+use grammers_client::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tauri::State;
+
+use crate::accounts::Account;
+use crate::db::{FileMetadata, Replica, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::storage::{self, StorageConfig};
+use crate::AppState;
+
+const REPLICA_CHANNEL_TITLE: &str = "Paperfold Replica";
+const MAX_REPLICATION_FACTOR: u8 = 2;
+
+/// What one repair pass changed.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RepairRun {
+    // Primary was gone, a replica took its place
+    pub promoted: usize,
+    // Copies made again from a healthy one
+    pub recreated: usize,
+    // Replicas whose message was gone, removed from the entry
+    pub dropped: usize,
+    // Copies that could not be made this time
+    pub failed: usize,
+    // Files with no copy left anywhere
+    pub lost: usize,
+    // Files left alone because a peer they are in could not be checked
+    #[serde(default)]
+    pub skipped: usize,
+    // Why those peers could not be checked
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+// Peers new copies go to, the storage target first. A file only gets copies
+// until it has `replication_factor` of them, wherever they already are, so
+// changing the target never moves existing files.
+fn wanted_peers(config: &StorageConfig) -> Vec<StoragePeer> {
+    let mut peers = vec![config.target.clone()];
+    if config.replication_factor >= 2 {
+        if let Some(replica) = config.replica_target.as_ref() {
+            if *replica != config.target {
+                peers.push(replica.clone());
+            }
+        }
+    }
+    peers
+}
+
+// Forwarding makes an independent copy without uploading the bytes again
+async fn copy_to(client: &Client, from: &Replica, to: &StoragePeer) -> Result<i32, AppError> {
+    let source = storage::resolve_peer(client, &from.peer).await?;
+    let dest = storage::resolve_peer(client, to).await?;
+    let forwarded = rpc!(
+        "messages.forwardMessages",
+        client.forward_messages(dest, &[from.message_id], source)
+    )?;
+    forwarded
+        .into_iter()
+        .next()
+        .flatten()
+        .map(|msg| msg.id())
+        .ok_or_else(|| AppError::Telegram("Telegram did not return the copy".to_string()))
+}
+
+/// Copies a fresh personal upload into other wanted peers until it has
+/// `replication_factor` copies. Failures only log; the next repair catches up.
+pub async fn replicate_upload(
+    client: &Client,
+    account: &Account,
+    file: &FileMetadata,
+) -> Result<(), AppError> {
+    let config = account.storage.lock().unwrap().clone();
+    let primary = file.locations().remove(0);
+    let mut replicas = file.replicas.clone();
+    for peer in wanted_peers(&config) {
+        if replicas.len() + 1 >= config.replication_factor as usize {
+            break;
+        }
+        if primary.peer == peer || replicas.iter().any(|r| r.peer == peer) {
+            continue;
+        }
+        match copy_to(client, &primary, &peer).await {
+            Ok(message_id) => replicas.push(Replica { peer, message_id }),
+            Err(e) => eprintln!("Failed to replicate {}: {}", file.name, e),
+        }
+    }
+    account.personal_db.set_replicas(&file.id, replicas)?;
+    Ok(())
+}
+
+/// The first of `locations` whose message still has its media, so downloads
+/// keep working while the primary copy is gone. None when no copy is left.
+pub async fn find_copy(
+    client: &Client,
+    locations: &[Replica],
+) -> Result<Option<Message>, AppError> {
+    let mut last_error = None;
+    for (i, location) in locations.iter().enumerate() {
+        match storage::fetch_messages(client, &location.peer, &[location.message_id]).await {
+            Ok(messages) => {
+                if let Some(msg) = messages.into_iter().next().flatten() {
+                    if msg.media().is_some() {
+                        if i > 0 {
+                            println!("Primary copy is gone, using replica {}", i);
+                        }
+                        return Ok(Some(msg));
+                    }
+                }
+            }
+            // An unreachable peer should not hide a copy in the next one
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+/// Brings every personal file back to `replication_factor` copies. Dead
+/// replicas are dropped, a dead primary is replaced by a live replica, and
+/// missing copies are forwarded from a healthy one. Files with no copy left are only counted.
+/// A peer that can't be read is recorded and its files are left for next time.
+pub async fn repair(
+    client: &Client,
+    account: &Account,
+    batch_size: usize,
+) -> Result<RepairRun, AppError> {
+    let config = account.storage.lock().unwrap().clone();
+    let wanted = wanted_peers(&config);
+    let db = &account.personal_db;
+    // Copies made now for a file being deleted would never be cleaned up
+    let files: Vec<_> = db
+        .get_all_files()
+        .into_iter()
+        .filter(|f| !db.is_pending_deletion(&f.id))
+        .collect();
+
+    let mut by_peer: HashMap<StoragePeer, Vec<i32>> = HashMap::new();
+    for f in &files {
+        for location in f.locations() {
+            by_peer
+                .entry(location.peer)
+                .or_default()
+                .push(location.message_id);
+        }
+    }
+    let mut run = RepairRun::default();
+    let mut alive: HashSet<Replica> = HashSet::new();
+    let mut unchecked: HashSet<Replica> = HashSet::new();
+    for (peer, ids) in &by_peer {
+        for chunk in ids.chunks(batch_size) {
+            let messages = match storage::fetch_messages(client, peer, chunk).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    eprintln!("Replica repair could not check {:?}: {}", peer, e);
+                    run.errors.push(e.to_string());
+                    unchecked.extend(chunk.iter().map(|message_id| Replica {
+                        peer: peer.clone(),
+                        message_id: *message_id,
+                    }));
+                    continue;
+                }
+            };
+            for (message_id, msg) in chunk.iter().zip(messages.iter()) {
+                if msg.as_ref().is_some_and(|m| m.media().is_some()) {
+                    alive.insert(Replica {
+                        peer: peer.clone(),
+                        message_id: *message_id,
+                    });
+                }
+            }
+        }
+    }
+
+    for file in &files {
+        // Half a picture could drop a copy that is fine
+        if file.locations().iter().any(|l| unchecked.contains(l)) {
+            run.skipped += 1;
+            continue;
+        }
+        let mut live = file.locations();
+        live.retain(|l| alive.contains(l));
+        if live.is_empty() {
+            run.lost += 1;
+            continue;
+        }
+        run.dropped += file.replicas.iter().filter(|r| !alive.contains(r)).count();
+
+        // Live locations, primary first; the first live one becomes the primary
+        let primary = live.remove(0);
+        if primary.peer != file.peer || primary.message_id != file.message_id {
+            println!("Promoting a replica of {} to primary", file.name);
+            db.relocate_file(&file.id, primary.peer.clone(), primary.message_id)?;
+            run.promoted += 1;
+        }
+        let mut replicas = live;
+        for peer in &wanted {
+            if replicas.len() + 1 >= config.replication_factor as usize {
+                break;
+            }
+            if primary.peer == *peer || replicas.iter().any(|r| r.peer == *peer) {
+                continue;
+            }
+            match copy_to(client, &primary, peer).await {
+                Ok(message_id) => {
+                    replicas.push(Replica {
+                        peer: peer.clone(),
+                        message_id,
+                    });
+                    run.recreated += 1;
+                }
+                Err(e) => {
+                    eprintln!("Failed to recreate a copy of {}: {}", file.name, e);
+                    run.failed += 1;
+                }
+            }
+        }
+        db.set_replicas(&file.id, replicas)?;
+    }
+
+    if run.promoted + run.recreated + run.dropped + run.failed + run.lost + run.skipped > 0 {
+        println!(
+            "Replica repair: {} promoted, {} recreated, {} dropped, {} failed, {} lost, {} skipped",
+            run.promoted, run.recreated, run.dropped, run.failed, run.lost, run.skipped
+        );
+    }
+    Ok(run)
+}
+
+/// 1 keeps a single copy; 2 also stores personal uploads in a second peer:
+/// a private replica channel, or Saved Messages when the storage target is
+/// already a channel. Existing files get their copy on the next repair.
+#[tauri::command]
+pub(crate) async fn set_replication(
+    factor: u8,
+    state: State<'_, AppState>,
+) -> Result<StorageConfig, AppError> {
+    if !(1..=MAX_REPLICATION_FACTOR).contains(&factor) {
+        return Err(format!("Replication factor must be 1 or {}", MAX_REPLICATION_FACTOR).into());
+    }
+    let account = state.accounts.active();
+    let mut config = account.storage.lock().unwrap().clone();
+    config.replication_factor = factor;
+
+    let needs_peer = config
+        .replica_target
+        .as_ref()
+        .is_none_or(|peer| *peer == config.target);
+    if factor >= 2 && needs_peer {
+        config.replica_target = Some(match config.target {
+            StoragePeer::Channel { .. } => StoragePeer::SavedMessages,
+            StoragePeer::SavedMessages => {
+                let client_guard = account.client.lock().await;
+                let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+                drop(client_guard);
+                storage::create_storage_channel(&client, REPLICA_CHANNEL_TITLE).await?
+            }
+        });
+    }
+
+    println!("Replication factor set to {}", factor);
+    config.save(&account.dir)?;
+    *account.storage.lock().unwrap() = config.clone();
+    Ok(config)
+}
+
+#[tauri::command]
+pub(crate) async fn repair_replicas(state: State<'_, AppState>) -> Result<RepairRun, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+    let batch_size = state.settings.get().message_batch_size;
+    repair(&client, &account, batch_size).await
+}
@@ -0,0 +1,253 @@
+use grammers_client::types::{Downloadable, Media, Message};
+use grammers_client::Client;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use tauri::{AppHandle, Emitter, State};
+
+use crate::accounts::Account;
+use crate::db::{FileMetadata, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::replication::{self, RepairRun};
+use crate::settings::Settings;
+use crate::{storage, AppState};
+
+const HEALTH_REPORT_FILENAME: &str = "health_report.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileHealth {
+    Healthy,
+    // The message, or its media, is gone
+    Missing,
+    SizeMismatch,
+    MimeMismatch,
+    // Downloaded content does not match the hash taken at upload
+    HashMismatch,
+    // The sample download itself failed
+    Unreadable,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileCheck {
+    pub file_id: String,
+    pub name: String,
+    pub peer: StoragePeer,
+    pub message_id: i32,
+    pub status: FileHealth,
+    pub detail: Option<String>,
+    // Content was downloaded and matched the stored hash
+    pub hash_verified: bool,
+}
+
+/// Result of the last scrub of the personal drive, in health_report.json.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HealthReport {
+    pub started_at: i64,
+    pub finished_at: i64,
+    pub checked: usize,
+    pub healthy: usize,
+    pub failed: usize,
+    pub hash_verified: usize,
+    // Replica repair done before the checks
+    #[serde(default)]
+    pub repair: RepairRun,
+    pub files: Vec<FileCheck>,
+}
+
+impl HealthReport {
+    pub fn load(dir: &Path) -> Option<Self> {
+        File::open(dir.join(HEALTH_REPORT_FILENAME))
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), AppError> {
+        let file = File::create(dir.join(HEALTH_REPORT_FILENAME)).map_err(AppError::storage)?;
+        serde_json::to_writer(BufWriter::new(file), self).map_err(AppError::storage)
+    }
+}
+
+// Status and detail from the message alone, without downloading anything
+fn inspect(file: &FileMetadata, msg: Option<&Message>) -> (FileHealth, Option<String>) {
+    let msg = match msg {
+        Some(msg) => msg,
+        None => return (FileHealth::Missing, Some("Message was deleted".to_string())),
+    };
+    let (size, mime) = match msg.media() {
+        Some(Media::Document(doc)) => (doc.size(), doc.mime_type().map(str::to_string)),
+        Some(Media::Photo(photo)) => (photo.size(), None),
+        _ => return (FileHealth::Missing, Some("Message has no file".to_string())),
+    };
+    if size != file.size {
+        return (
+            FileHealth::SizeMismatch,
+            Some(format!(
+                "Expected {} bytes, Telegram has {}",
+                file.size, size
+            )),
+        );
+    }
+    // Photos are re-encoded by Telegram and have no MIME type of their own
+    if let Some(mime) = mime {
+        if !file.mime_type.is_empty() && !mime.eq_ignore_ascii_case(&file.mime_type) {
+            return (
+                FileHealth::MimeMismatch,
+                Some(format!(
+                    "Expected {}, Telegram has {}",
+                    file.mime_type, mime
+                )),
+            );
+        }
+    }
+    (FileHealth::Healthy, None)
+}
+
+async fn content_hash(client: &Client, msg: &Message) -> Result<String, AppError> {
+    let media = msg.media().ok_or("Message has no file")?;
+    let mut hasher = Sha256::new();
+    let mut download = client.iter_download(&Downloadable::Media(media));
+    while let Some(chunk) = rpc!("upload.getFile", download.next())? {
+        hasher.update(&chunk);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Repairs replicas first, so a lost primary is replaced before it is checked;
+/// a repair that fails is recorded in the report rather than stopping the scrub.
+/// Then checks every file of the personal drive against its message in batches:
+/// still there, same size, same MIME type. A random sample of files with a
+/// known hash is downloaded and hashed too. Entries whose message is gone are
+/// flagged missing. The report is saved and emitted as "health-report".
+pub async fn run(
+    app_handle: &AppHandle,
+    client: &Client,
+    account: &Account,
+    settings: &Settings,
+) -> Result<HealthReport, AppError> {
+    let started_at = chrono::Utc::now().timestamp();
+    // A failed repair still leaves files worth checking
+    let repair = match replication::repair(client, account, settings.message_batch_size).await {
+        Ok(run) => run,
+        Err(e) => {
+            eprintln!("Replica repair failed: {}", e);
+            RepairRun {
+                errors: vec![e.to_string()],
+                ..Default::default()
+            }
+        }
+    };
+    let db = &account.personal_db;
+    let files = db.get_all_files();
+    let total = files.len();
+    println!("Scrubbing {} files...", total);
+
+    // Picked up front; large files are left out so a scrub stays cheap
+    let max_bytes = (settings.scrub_hash_max_mb * 1_000_000) as i64;
+    let sample: HashSet<String> = {
+        let candidates: Vec<&FileMetadata> = files
+            .iter()
+            .filter(|f| f.hash.is_some() && f.size <= max_bytes)
+            .collect();
+        candidates
+            .choose_multiple(&mut rand::thread_rng(), settings.scrub_hash_sample)
+            .map(|f| f.id.clone())
+            .collect()
+    };
+
+    let mut by_peer: HashMap<StoragePeer, Vec<&FileMetadata>> = HashMap::new();
+    for f in &files {
+        by_peer.entry(f.peer.clone()).or_default().push(f);
+    }
+
+    let mut checks = Vec::with_capacity(total);
+    for (peer, peer_files) in &by_peer {
+        for chunk in peer_files.chunks(settings.message_batch_size) {
+            let ids: Vec<i32> = chunk.iter().map(|f| f.message_id).collect();
+            let messages = storage::fetch_messages(client, peer, &ids).await?;
+            for (file, msg) in chunk.iter().zip(messages.iter()) {
+                let (mut status, mut detail) = inspect(file, msg.as_ref());
+                let mut hash_verified = false;
+                if status == FileHealth::Healthy && sample.contains(&file.id) {
+                    let expected = file.hash.as_deref().unwrap_or_default();
+                    // inspect() already saw the message
+                    match content_hash(client, msg.as_ref().unwrap()).await {
+                        Ok(actual) if actual.eq_ignore_ascii_case(expected) => hash_verified = true,
+                        Ok(_) => {
+                            status = FileHealth::HashMismatch;
+                            detail = Some("Content does not match the hash from upload".into());
+                        }
+                        Err(e) => {
+                            status = FileHealth::Unreadable;
+                            detail = Some(format!("Download failed: {}", e));
+                        }
+                    }
+                }
+                checks.push(FileCheck {
+                    file_id: file.id.clone(),
+                    name: file.name.clone(),
+                    peer: peer.clone(),
+                    message_id: file.message_id,
+                    status,
+                    detail,
+                    hash_verified,
+                });
+            }
+            let _ = app_handle.emit(
+                "scrub-progress",
+                serde_json::json!({ "checked": checks.len(), "total": total }),
+            );
+        }
+    }
+
+    // Same flag the deletion listener sets, so the missing-files view picks them up
+    let known_missing: HashSet<&str> = files
+        .iter()
+        .filter(|f| f.missing)
+        .map(|f| f.id.as_str())
+        .collect();
+    let newly_missing: Vec<String> = checks
+        .iter()
+        .filter(|c| c.status == FileHealth::Missing)
+        .filter(|c| !known_missing.contains(c.file_id.as_str()))
+        .map(|c| c.file_id.clone())
+        .collect();
+    if !newly_missing.is_empty() {
+        if let Err(e) = db.mark_missing(&newly_missing) {
+            eprintln!("Failed to flag missing files: {}", e);
+        }
+    }
+
+    let healthy = checks
+        .iter()
+        .filter(|c| c.status == FileHealth::Healthy)
+        .count();
+    let report = HealthReport {
+        started_at,
+        finished_at: chrono::Utc::now().timestamp(),
+        checked: checks.len(),
+        healthy,
+        failed: checks.len() - healthy,
+        hash_verified: checks.iter().filter(|c| c.hash_verified).count(),
+        repair,
+        files: checks,
+    };
+    println!(
+        "Scrub: {} checked, {} failed, {} hashes verified",
+        report.checked, report.failed, report.hash_verified
+    );
+    report.save(&account.dir)?;
+    let _ = app_handle.emit("health-report", &report);
+    Ok(report)
+}
+
+#[tauri::command]
+pub(crate) fn get_health_report(state: State<AppState>) -> Result<Option<HealthReport>, AppError> {
+    let account = state.accounts.active();
+    Ok(HealthReport::load(&account.dir))
+}
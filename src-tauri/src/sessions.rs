@@ -0,0 +1,116 @@
+use grammers_tl_types as tl;
+use serde::Serialize;
+use tauri::State;
+
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::AppState;
+
+/// One place the account is logged in, as Telegram reports it.
+#[derive(Serialize)]
+pub struct ActiveSession {
+    // i64 does not survive JSON numbers in JS, so it travels as a string
+    pub hash: String,
+    pub current: bool,
+    pub official_app: bool,
+    pub password_pending: bool,
+    pub device_model: String,
+    pub platform: String,
+    pub system_version: String,
+    pub app_name: String,
+    pub app_version: String,
+    pub date_created: i64,
+    pub date_active: i64,
+    pub ip: String,
+    pub country: String,
+    pub region: String,
+}
+
+impl From<tl::types::Authorization> for ActiveSession {
+    fn from(a: tl::types::Authorization) -> Self {
+        ActiveSession {
+            hash: a.hash.to_string(),
+            current: a.current,
+            official_app: a.official_app,
+            password_pending: a.password_pending,
+            device_model: a.device_model,
+            platform: a.platform,
+            system_version: a.system_version,
+            app_name: a.app_name,
+            app_version: a.app_version,
+            date_created: a.date_created as i64,
+            date_active: a.date_active as i64,
+            ip: a.ip,
+            country: a.country,
+            region: a.region,
+        }
+    }
+}
+
+/// Every session of the active account, this one first, then by last activity.
+#[tauri::command]
+pub(crate) async fn list_sessions(
+    state: State<'_, AppState>,
+) -> Result<Vec<ActiveSession>, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let tl::enums::account::Authorizations::Authorizations(result) = rpc!(
+        "account.getAuthorizations",
+        client.invoke(&tl::functions::account::GetAuthorizations {})
+    )?;
+    let mut sessions: Vec<ActiveSession> = result
+        .authorizations
+        .into_iter()
+        .map(|tl::enums::Authorization::Authorization(a)| a.into())
+        .collect();
+    sessions.sort_by(|a, b| {
+        b.current
+            .cmp(&a.current)
+            .then(b.date_active.cmp(&a.date_active))
+    });
+    Ok(sessions)
+}
+
+/// Logs the given sessions out on Telegram's side. This device is refused here;
+/// use `logout` for it. Returns how many were terminated.
+#[tauri::command]
+pub(crate) async fn terminate_sessions(
+    hashes: Vec<String>,
+    state: State<'_, AppState>,
+) -> Result<usize, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let mut terminated = 0;
+    for hash in hashes {
+        let hash: i64 = hash
+            .parse()
+            .map_err(|_| format!("Invalid session id: {}", hash))?;
+        // Telegram reports the current session with hash 0
+        if hash == 0 {
+            return Err("Use Log out to end the session on this device".into());
+        }
+        println!("Terminating session {}", hash);
+        match rpc!(
+            "account.resetAuthorization",
+            client.invoke(&tl::functions::account::ResetAuthorization { hash })
+        ) {
+            Ok(_) => terminated += 1,
+            Err(grammers_client::InvocationError::Rpc(rpc))
+                if rpc.name == "FRESH_RESET_AUTHORISATION_FORBIDDEN" =>
+            {
+                return Err(AppError::Telegram(
+                    "Telegram only lets a session end others once it is at least a day old"
+                        .to_string(),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(terminated)
+}
@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{Emitter, State};
+
+use crate::error::AppError;
+use crate::AppState;
+
+const SETTINGS_FILENAME: &str = "settings.json";
+// Bump when a field changes meaning; older files are upgraded on load
+const SETTINGS_VERSION: u32 = 1;
+// Telegram's limit on parts in one upload
+const MAX_UPLOAD_PARTS: u64 = 4000;
+const MAX_INTERVAL_MINUTES: u64 = 30 * 24 * 60;
+
+/// App-wide tunables, in settings.json. Missing fields take their defaults, so
+/// adding one never breaks an existing file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    // Trashed files older than this are deleted from Telegram at login
+    pub trash_retention_days: u32,
+    pub upload_parallel_parts: usize,
+    pub upload_chunk_kib: usize,
+    // Above this, uploads use the big-file API
+    pub big_file_threshold_mb: u64,
+    // Size of each zip when downloading everything
+    pub zip_part_limit_mb: u64,
+    // Backup messages read per chat when listing; 0 reads them all
+    pub backup_search_limit: usize,
+    // Newest backups kept when a new one is made
+    pub keep_backups: usize,
+    // Messages asked for per request when checking files still exist
+    pub message_batch_size: usize,
+    // How often each maintenance job runs
+    pub trash_expiry_interval_minutes: u64,
+    pub backup_interval_minutes: u64,
+    pub gc_interval_minutes: u64,
+    pub scrub_interval_minutes: u64,
+    pub preview_cache_interval_minutes: u64,
+    // Downloaded previews are evicted past this age, then oldest first past this size
+    pub preview_cache_max_age_days: u64,
+    pub preview_cache_max_mb: u64,
+    // Files per scrub whose content is downloaded and hashed; 0 checks messages only
+    pub scrub_hash_sample: usize,
+    // Files above this are never picked for the hash sample
+    pub scrub_hash_max_mb: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            version: SETTINGS_VERSION,
+            trash_retention_days: 30,
+            upload_parallel_parts: 16,
+            upload_chunk_kib: 512,
+            big_file_threshold_mb: 10,
+            zip_part_limit_mb: 1900,
+            backup_search_limit: 50,
+            keep_backups: 10,
+            message_batch_size: 50,
+            trash_expiry_interval_minutes: 6 * 60,
+            // Only checks whether a backup is due; see AutoBackupConfig
+            backup_interval_minutes: 5,
+            gc_interval_minutes: 7 * 24 * 60,
+            scrub_interval_minutes: 24 * 60,
+            preview_cache_interval_minutes: 60,
+            preview_cache_max_age_days: 7,
+            preview_cache_max_mb: 500,
+            scrub_hash_sample: 3,
+            scrub_hash_max_mb: 50,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.trash_retention_days == 0 {
+            return Err("Trash retention must be at least one day".into());
+        }
+        if !(1..=32).contains(&self.upload_parallel_parts) {
+            return Err("Parallel upload parts must be between 1 and 32".into());
+        }
+        // Telegram wants a part size that divides 512 KiB evenly
+        if !self.upload_chunk_kib.is_power_of_two() || self.upload_chunk_kib > 512 {
+            return Err("Upload chunk size must be a power of two up to 512 KiB".into());
+        }
+        // Small uploads must stay under 10 MB
+        if !(1..=10).contains(&self.big_file_threshold_mb) {
+            return Err("Big file threshold must be between 1 and 10 MB".into());
+        }
+        if !(50..=4000).contains(&self.zip_part_limit_mb) {
+            return Err("Zip part size must be between 50 and 4000 MB".into());
+        }
+        if !(1..=1000).contains(&self.keep_backups) {
+            return Err("Backups kept must be between 1 and 1000".into());
+        }
+        // getMessages takes at most 100 ids
+        if !(1..=100).contains(&self.message_batch_size) {
+            return Err("Message batch size must be between 1 and 100".into());
+        }
+        let intervals = [
+            self.trash_expiry_interval_minutes,
+            self.backup_interval_minutes,
+            self.gc_interval_minutes,
+            self.scrub_interval_minutes,
+            self.preview_cache_interval_minutes,
+        ];
+        if intervals
+            .iter()
+            .any(|m| !(1..=MAX_INTERVAL_MINUTES).contains(m))
+        {
+            return Err("Maintenance intervals must be between 1 minute and 30 days".into());
+        }
+        if self.preview_cache_max_age_days == 0 || self.preview_cache_max_mb == 0 {
+            return Err("Preview cache limits must be at least 1".into());
+        }
+        if self.scrub_hash_sample > 100 {
+            return Err("Scrub hash sample is limited to 100 files".into());
+        }
+        if self.scrub_hash_max_mb == 0 {
+            return Err("Scrub hash size limit must be at least 1 MB".into());
+        }
+        Ok(())
+    }
+
+    /// `self` with the fields in `patch` replaced. Unknown fields, the version and
+    /// values that fail validation are rejected.
+    pub fn apply_patch(&self, patch: serde_json::Value) -> Result<Settings, AppError> {
+        let patch = match patch {
+            serde_json::Value::Object(patch) => patch,
+            _ => return Err("Settings update must be an object".into()),
+        };
+        let mut merged = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(map)) => map,
+            _ => return Err(AppError::Other("Settings did not serialize".to_string())),
+        };
+        for (key, value) in patch {
+            if key == "version" {
+                return Err("The settings version cannot be changed".into());
+            }
+            if !merged.contains_key(&key) {
+                return Err(format!("Unknown setting: {}", key).into());
+            }
+            merged.insert(key, value);
+        }
+        let updated: Settings = serde_json::from_value(serde_json::Value::Object(merged))
+            .map_err(|e| format!("Invalid setting value: {}", e))?;
+        updated.validate()?;
+        Ok(updated)
+    }
+
+    pub fn upload_chunk_bytes(&self) -> usize {
+        self.upload_chunk_kib * 1024
+    }
+
+    pub fn big_file_threshold_bytes(&self) -> u64 {
+        self.big_file_threshold_mb * 1024 * 1024
+    }
+
+    pub fn zip_part_limit_bytes(&self) -> u64 {
+        self.zip_part_limit_mb * 1_000_000
+    }
+
+    /// The largest file one upload can hold with the current chunk size.
+    pub fn max_upload_bytes(&self) -> u64 {
+        self.upload_chunk_bytes() as u64 * MAX_UPLOAD_PARTS
+    }
+}
+
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: Mutex<Settings>,
+}
+
+impl SettingsStore {
+    pub fn load(app_dir: &Path) -> Self {
+        let path = app_dir.join(SETTINGS_FILENAME);
+        let loaded: Option<Settings> =
+            File::open(&path)
+                .ok()
+                .and_then(|f| match serde_json::from_reader(BufReader::new(f)) {
+                    Ok(s) => Some(s),
+                    Err(e) => {
+                        eprintln!("Unreadable settings, using defaults: {}", e);
+                        None
+                    }
+                });
+        let settings = match loaded {
+            Some(s) => match s.validate() {
+                Ok(()) => s,
+                Err(e) => {
+                    eprintln!("Invalid settings, using defaults: {}", e);
+                    Settings::default()
+                }
+            },
+            None => Settings::default(),
+        };
+        let store = SettingsStore {
+            path,
+            settings: Mutex::new(settings.clone()),
+        };
+        if settings.version != SETTINGS_VERSION {
+            println!(
+                "Upgrading settings from version {} to {}",
+                settings.version, SETTINGS_VERSION
+            );
+            let upgraded = Settings {
+                version: SETTINGS_VERSION,
+                ..settings
+            };
+            if let Err(e) = store.save(upgraded) {
+                eprintln!("Failed to save upgraded settings: {}", e);
+            }
+        }
+        store
+    }
+
+    pub fn get(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    fn save(&self, settings: Settings) -> Result<(), AppError> {
+        let file = File::create(&self.path).map_err(AppError::storage)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &settings).map_err(AppError::storage)?;
+        *self.settings.lock().unwrap() = settings;
+        Ok(())
+    }
+}
+
+#[tauri::command]
+pub(crate) fn get_settings(state: State<AppState>) -> Result<Settings, AppError> {
+    Ok(state.settings.get())
+}
+
+/// Applies the fields in `patch` over the current settings. Unknown fields and
+/// invalid values are rejected and nothing is saved. Emits "settings-changed".
+#[tauri::command]
+pub(crate) fn update_settings(
+    patch: serde_json::Value,
+    state: State<AppState>,
+) -> Result<Settings, AppError> {
+    let current = state.settings.get();
+    let updated = current.apply_patch(patch)?;
+    if updated == current {
+        return Ok(updated);
+    }
+
+    println!("Settings updated");
+    state.settings.save(updated.clone())?;
+    let _ = state.app_handle.emit("settings-changed", &updated);
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rejects(patch: serde_json::Value, message: &str) {
+        match Settings::default().apply_patch(patch) {
+            Err(AppError::Other(e)) => assert!(e.contains(message), "{}", e),
+            other => panic!("expected \"{}\", got {:?}", message, other),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Settings::default().validate().is_ok());
+    }
+
+    #[test]
+    fn patch_changes_only_given_fields() {
+        let updated = Settings::default()
+            .apply_patch(json!({ "keep_backups": 3, "upload_chunk_kib": 128 }))
+            .unwrap();
+        assert_eq!(
+            updated,
+            Settings {
+                keep_backups: 3,
+                upload_chunk_kib: 128,
+                ..Settings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn empty_patch_changes_nothing() {
+        let current = Settings::default();
+        assert_eq!(current.apply_patch(json!({})).unwrap(), current);
+    }
+
+    #[test]
+    fn patch_must_be_a_known_object() {
+        rejects(json!([1, 2]), "must be an object");
+        rejects(json!({ "nope": 1 }), "Unknown setting: nope");
+        rejects(json!({ "version": 2 }), "version cannot be changed");
+        rejects(json!({ "keep_backups": "ten" }), "Invalid setting value");
+    }
+
+    #[test]
+    fn patch_is_validated() {
+        rejects(json!({ "trash_retention_days": 0 }), "Trash retention");
+        rejects(
+            json!({ "upload_parallel_parts": 33 }),
+            "Parallel upload parts",
+        );
+        rejects(json!({ "upload_chunk_kib": 384 }), "power of two");
+        rejects(json!({ "upload_chunk_kib": 1024 }), "power of two");
+        rejects(json!({ "big_file_threshold_mb": 11 }), "Big file threshold");
+        rejects(json!({ "zip_part_limit_mb": 49 }), "Zip part size");
+        rejects(json!({ "keep_backups": 0 }), "Backups kept");
+        rejects(json!({ "message_batch_size": 101 }), "Message batch size");
+        rejects(json!({ "gc_interval_minutes": 0 }), "Maintenance intervals");
+        rejects(
+            json!({ "scrub_interval_minutes": MAX_INTERVAL_MINUTES + 1 }),
+            "Maintenance intervals",
+        );
+        rejects(json!({ "preview_cache_max_mb": 0 }), "Preview cache");
+        rejects(json!({ "scrub_hash_sample": 101 }), "Scrub hash sample");
+        rejects(json!({ "scrub_hash_max_mb": 0 }), "Scrub hash size");
+    }
+
+    #[test]
+    fn limits_are_inclusive() {
+        let updated = Settings::default().apply_patch(json!({
+            "upload_parallel_parts": 32,
+            "upload_chunk_kib": 1,
+            "big_file_threshold_mb": 1,
+            "zip_part_limit_mb": 4000,
+            "message_batch_size": 100,
+            "scrub_hash_sample": 0,
+            "backup_interval_minutes": MAX_INTERVAL_MINUTES,
+        }));
+        assert!(updated.is_ok(), "{:?}", updated);
+    }
+}
@@ -0,0 +1,532 @@
+use grammers_client::types::PackedChat;
+use grammers_client::Client;
+use grammers_session::PackedType;
+use grammers_tl_types as tl;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{Emitter, Manager, State};
+
+use crate::accounts::Account;
+use crate::db::{Database, DriveEvent, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::rpc;
+use crate::{oplog, storage, AppState};
+
+const EVENT_TAG: &str = "#paperfold_event";
+const SHARED_DIR: &str = "shared";
+const DRIVE_FILENAME: &str = "drive.json";
+const SYNC_INTERVAL_SECS: u64 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriveRole {
+    Editor,
+    ReadOnly,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedDriveInfo {
+    pub id: String,
+    pub title: String,
+    pub peer: StoragePeer,
+    pub role: DriveRole,
+    pub last_event_id: i32,
+    pub joined_at: i64,
+}
+
+/// A drive whose files and folder tree live in a channel shared by several accounts.
+pub struct SharedDrive {
+    dir: PathBuf,
+    pub info: Mutex<SharedDriveInfo>,
+    pub db: Arc<Database>,
+    sync_lock: tokio::sync::Mutex<()>,
+}
+
+impl SharedDrive {
+    fn open(dir: PathBuf, info: SharedDriveInfo, device_id: &str) -> Result<Self, AppError> {
+        std::fs::create_dir_all(&dir).map_err(AppError::storage)?;
+        let db = Arc::new(Database::new(dir.to_str().unwrap()));
+        // Every local change has to reach the channel
+        db.set_device_id(device_id);
+        db.set_journaling(true);
+        Ok(SharedDrive {
+            dir,
+            info: Mutex::new(info),
+            db,
+            sync_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn save_info(&self) -> Result<(), AppError> {
+        let file = File::create(self.dir.join(DRIVE_FILENAME)).map_err(AppError::storage)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &*self.info.lock().unwrap()).map_err(AppError::storage)
+    }
+
+    pub fn info(&self) -> SharedDriveInfo {
+        self.info.lock().unwrap().clone()
+    }
+}
+
+pub struct SharedDrives {
+    root: PathBuf,
+    device_id: String,
+    drives: Mutex<HashMap<String, Arc<SharedDrive>>>,
+    active: Mutex<Option<String>>,
+}
+
+impl SharedDrives {
+    pub fn load(account_dir: &Path, device_id: &str) -> Self {
+        let root = account_dir.join(SHARED_DIR);
+        let mut drives = HashMap::new();
+
+        if let Ok(entries) = std::fs::read_dir(&root) {
+            for entry in entries.flatten() {
+                let info_path = entry.path().join(DRIVE_FILENAME);
+                let info: Option<SharedDriveInfo> = File::open(&info_path)
+                    .ok()
+                    .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok());
+                let Some(info) = info else {
+                    eprintln!("Skipping unreadable shared drive at {:?}", entry.path());
+                    continue;
+                };
+                match SharedDrive::open(entry.path(), info.clone(), device_id) {
+                    Ok(drive) => {
+                        drives.insert(info.id, Arc::new(drive));
+                    }
+                    Err(e) => eprintln!("Skipping shared drive at {:?}: {}", entry.path(), e),
+                }
+            }
+        }
+
+        SharedDrives {
+            root,
+            device_id: device_id.to_string(),
+            drives: Mutex::new(drives),
+            active: Mutex::new(None),
+        }
+    }
+
+    pub fn list(&self) -> Vec<SharedDriveInfo> {
+        let drives = self.drives.lock().unwrap();
+        let mut list: Vec<SharedDriveInfo> = drives.values().map(|d| d.info()).collect();
+        list.sort_by_key(|d| d.joined_at);
+        list
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<SharedDrive>> {
+        self.drives.lock().unwrap().get(id).cloned()
+    }
+
+    /// The drive currently shown in the dashboard, or `None` for the personal drive.
+    pub fn active(&self) -> Option<Arc<SharedDrive>> {
+        let active = self.active.lock().unwrap().clone()?;
+        self.get(&active)
+    }
+
+    pub fn set_active(&self, id: Option<String>) -> Result<(), AppError> {
+        if let Some(id) = &id {
+            if self.get(id).is_none() {
+                return Err("Shared drive not found".into());
+            }
+        }
+        *self.active.lock().unwrap() = id;
+        Ok(())
+    }
+
+    fn register(&self, info: SharedDriveInfo) -> Result<Arc<SharedDrive>, AppError> {
+        if let Some(existing) = self.get(&info.id) {
+            return Ok(existing);
+        }
+        let drive = Arc::new(SharedDrive::open(
+            self.root.join(&info.id),
+            info.clone(),
+            &self.device_id,
+        )?);
+        // Without drive.json the drive would be gone after a restart
+        drive.save_info()?;
+        self.drives
+            .lock()
+            .unwrap()
+            .insert(info.id.clone(), drive.clone());
+        Ok(drive)
+    }
+
+    fn remove(&self, id: &str) -> Option<Arc<SharedDrive>> {
+        let drive = self.drives.lock().unwrap().remove(id);
+        let mut active = self.active.lock().unwrap();
+        if active.as_deref() == Some(id) {
+            *active = None;
+        }
+        drop(active);
+
+        let dir = self.root.join(id);
+        if dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                eprintln!("Failed to remove shared drive dir {:?}: {}", dir, e);
+            }
+        }
+        drive
+    }
+}
+
+/// Editors are the channel creator and its admins. Everyone else can browse and
+/// download but not change anything; Telegram would refuse their posts anyway.
+async fn fetch_role(client: &Client, chat: PackedChat) -> Result<DriveRole, AppError> {
+    let channel = chat
+        .try_to_input_channel()
+        .ok_or("Shared drive peer is not a channel")?;
+    let tl::enums::channels::ChannelParticipant::Participant(result) = rpc!(
+        "channels.getParticipant",
+        client.invoke(&tl::functions::channels::GetParticipant {
+            channel: channel.clone(),
+            participant: tl::enums::InputPeer::PeerSelf,
+        })
+    )?;
+
+    Ok(match result.participant {
+        tl::enums::ChannelParticipant::Creator(_) | tl::enums::ChannelParticipant::Admin(_) => {
+            DriveRole::Editor
+        }
+        _ => DriveRole::ReadOnly,
+    })
+}
+
+// Megagroups let every member post, so there only events from the creator and
+// admins count. Anonymous admins post as the group itself.
+async fn fetch_admins(client: &Client, chat: PackedChat) -> Result<HashSet<i64>, AppError> {
+    let channel = chat
+        .try_to_input_channel()
+        .ok_or("Shared drive peer is not a channel")?;
+    let result = rpc!(
+        "channels.getParticipants",
+        client.invoke(&tl::functions::channels::GetParticipants {
+            channel: channel.clone(),
+            filter: tl::enums::ChannelParticipantsFilter::ChannelParticipantsAdmins,
+            offset: 0,
+            limit: 200,
+            hash: 0,
+        })
+    )?;
+    let mut admins = HashSet::from([chat.id]);
+    if let tl::enums::channels::ChannelParticipants::Participants(result) = result {
+        admins.extend(result.participants.iter().filter_map(|p| match p {
+            tl::enums::ChannelParticipant::Creator(c) => Some(c.user_id),
+            tl::enums::ChannelParticipant::Admin(a) => Some(a.user_id),
+            _ => None,
+        }));
+    }
+    Ok(admins)
+}
+
+// Access hashes differ per account, so files always point at this member's own
+// view of the channel rather than whatever the author recorded.
+fn localize_event(event: &mut DriveEvent, peer: &StoragePeer) {
+    match event {
+        DriveEvent::FileAdded { file } => {
+            file.peer = peer.clone();
+            file.thumbnail = None;
+        }
+        DriveEvent::FileRelocated { peer: p, .. } => *p = peer.clone(),
+        _ => {}
+    }
+}
+
+/// Publishes local changes, then merges every operation posted since the last
+/// sync. Own operations come back too and are simply skipped by the merge.
+pub async fn sync_drive(
+    client: &Client,
+    drive: &SharedDrive,
+    app_handle: &tauri::AppHandle,
+) -> Result<usize, AppError> {
+    // Pulled operations would be merged into nothing and never saved
+    if drive.db.is_locked() {
+        return Err(AppError::Locked("Metadata is locked".to_string()));
+    }
+    let _guard = drive.sync_lock.lock().await;
+    let info = drive.info();
+    let chat = storage::resolve_peer(client, &info.peer).await?;
+
+    let role = fetch_role(client, chat).await?;
+    if role != info.role {
+        println!("Shared drive {} role is now {:?}", info.title, role);
+        drive.info.lock().unwrap().role = role;
+        drive.save_info()?;
+    }
+
+    let pending = drive.db.pending_operations().len();
+    if pending > 0 {
+        if role == DriveRole::Editor {
+            oplog::publish_outbox(client, chat, EVENT_TAG, &drive.db).await?;
+        } else {
+            eprintln!(
+                "Dropping {} local changes to read-only drive {}",
+                pending, info.title
+            );
+            drive.db.ack_operations(pending)?;
+        }
+    }
+
+    let admins = match chat.ty {
+        PackedType::Megagroup => Some(fetch_admins(client, chat).await?),
+        _ => None,
+    };
+    let (mut ops, last_event_id) =
+        oplog::pull_operations(client, chat, EVENT_TAG, info.last_event_id, admins.as_ref())
+            .await?;
+    for op in &mut ops {
+        localize_event(&mut op.event, &info.peer);
+    }
+    let merged = oplog::merge_operations(client, &drive.db, &ops).await?;
+
+    if last_event_id != info.last_event_id {
+        drive.info.lock().unwrap().last_event_id = last_event_id;
+        drive.save_info()?;
+    }
+
+    if merged > 0 {
+        let _ = app_handle.emit("shared-drive-updated", &info.id);
+    }
+    Ok(merged)
+}
+
+/// Keeps the active shared drive in step with its channel while the app runs.
+pub fn spawn_sync_loop(app_handle: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(SYNC_INTERVAL_SECS)).await;
+
+            let state = app_handle.state::<AppState>();
+            if state.encryption.is_locked() {
+                continue;
+            }
+            let account = state.accounts.active();
+            let drive = match account.shared.active() {
+                Some(drive) => drive,
+                None => continue,
+            };
+            let client = match account.client.lock().await.as_ref() {
+                Some(client) => client.clone(),
+                None => continue,
+            };
+
+            if let Err(e) = sync_drive(&client, &drive, &app_handle).await {
+                eprintln!("Shared drive sync failed: {}", e);
+            }
+        }
+    });
+}
+
+fn parse_invite_hash(link: &str) -> Option<String> {
+    let link = link.trim().trim_end_matches('/');
+    let hash = if let Some(pos) = link.find("joinchat/") {
+        &link[pos + "joinchat/".len()..]
+    } else if let Some(pos) = link.rfind('+') {
+        &link[pos + 1..]
+    } else {
+        return None;
+    };
+    if hash.is_empty() {
+        None
+    } else {
+        Some(hash.to_string())
+    }
+}
+
+fn channel_from_updates(updates: tl::enums::Updates) -> Option<(StoragePeer, String)> {
+    let chats = match updates {
+        tl::enums::Updates::Updates(u) => u.chats,
+        tl::enums::Updates::Combined(u) => u.chats,
+        _ => Vec::new(),
+    };
+    chats.into_iter().find_map(|c| match c {
+        tl::enums::Chat::Channel(channel) => {
+            Some((storage::channel_peer(&channel), channel.title.clone()))
+        }
+        _ => None,
+    })
+}
+
+async fn client_for(account: &Account) -> Result<Client, AppError> {
+    let client_guard = account.client.lock().await;
+    Ok(client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone())
+}
+
+#[derive(Serialize)]
+pub struct SharedDriveList {
+    pub drives: Vec<SharedDriveInfo>,
+    pub active_id: Option<String>,
+}
+
+#[tauri::command]
+pub(crate) async fn list_shared_drives(
+    state: State<'_, AppState>,
+) -> Result<SharedDriveList, AppError> {
+    let account = state.accounts.active();
+    Ok(SharedDriveList {
+        drives: account.shared.list(),
+        active_id: account.shared.active().map(|d| d.info().id),
+    })
+}
+
+#[tauri::command]
+pub(crate) async fn create_shared_drive(
+    title: String,
+    state: State<'_, AppState>,
+) -> Result<SharedDriveInfo, AppError> {
+    let account = state.accounts.active();
+    let client = client_for(&account).await?;
+    println!("Creating shared drive: {}", title);
+
+    let peer = storage::create_storage_channel(&client, &title).await?;
+    let id = match &peer {
+        StoragePeer::Channel { id, .. } => id.to_string(),
+        StoragePeer::SavedMessages => unreachable!(),
+    };
+
+    let drive = account.shared.register(SharedDriveInfo {
+        id,
+        title,
+        peer,
+        role: DriveRole::Editor,
+        last_event_id: 0,
+        joined_at: chrono::Utc::now().timestamp(),
+    })?;
+    Ok(drive.info())
+}
+
+/// Joins through an invite link (t.me/+hash or t.me/joinchat/hash) and replays the
+/// channel's whole event log into a fresh local store.
+#[tauri::command]
+pub(crate) async fn join_shared_drive(
+    invite_link: String,
+    state: State<'_, AppState>,
+) -> Result<SharedDriveInfo, AppError> {
+    let account = state.accounts.active();
+    let client = client_for(&account).await?;
+    let hash = parse_invite_hash(&invite_link).ok_or("Invalid invite link")?;
+
+    let joined = match rpc!(
+        "messages.importChatInvite",
+        client.invoke(&tl::functions::messages::ImportChatInvite { hash: hash.clone() })
+    ) {
+        Ok(updates) => channel_from_updates(updates),
+        Err(e) if e.to_string().contains("USER_ALREADY_PARTICIPANT") => {
+            match rpc!(
+                "messages.checkChatInvite",
+                client.invoke(&tl::functions::messages::CheckChatInvite { hash: hash.clone() })
+            )? {
+                tl::enums::ChatInvite::Already(already) => match already.chat {
+                    tl::enums::Chat::Channel(channel) => {
+                        Some((storage::channel_peer(&channel), channel.title.clone()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        Err(e) => return Err(format!("Failed to join: {}", e).into()),
+    };
+    let (peer, title) = joined.ok_or("Invite does not point to a channel or group")?;
+
+    let chat = storage::resolve_peer(&client, &peer).await?;
+    let role = fetch_role(&client, chat).await?;
+    let id = chat.id.to_string();
+
+    let drive = account.shared.register(SharedDriveInfo {
+        id,
+        title,
+        peer,
+        role,
+        last_event_id: 0,
+        joined_at: chrono::Utc::now().timestamp(),
+    })?;
+    sync_drive(&client, &drive, &state.app_handle).await?;
+    Ok(drive.info())
+}
+
+#[tauri::command]
+pub(crate) async fn export_shared_drive_invite(
+    id: String,
+    state: State<'_, AppState>,
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let client = client_for(&account).await?;
+    let drive = account.shared.get(&id).ok_or("Shared drive not found")?;
+    let chat = storage::resolve_peer(&client, &drive.info().peer).await?;
+
+    let invite = rpc!(
+        "messages.exportChatInvite",
+        client.invoke(&tl::functions::messages::ExportChatInvite {
+            legacy_revoke_permanent: false,
+            request_needed: false,
+            peer: chat.to_input_peer(),
+            expire_date: None,
+            usage_limit: None,
+            title: None,
+            subscription_pricing: None,
+        })
+    )?;
+
+    match invite {
+        tl::enums::ExportedChatInvite::ChatInviteExported(invite) => Ok(invite.link),
+        _ => Err("Telegram did not return an invite link".into()),
+    }
+}
+
+/// Switches the dashboard between the personal drive (`None`) and a shared drive.
+#[tauri::command]
+pub(crate) async fn open_shared_drive(
+    id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    account.shared.set_active(id.clone())?;
+    let _ = state.app_handle.emit("drive-switched", &id);
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) async fn sync_shared_drive(
+    id: String,
+    state: State<'_, AppState>,
+) -> Result<usize, AppError> {
+    let account = state.accounts.active();
+    let client = client_for(&account).await?;
+    let drive = account.shared.get(&id).ok_or("Shared drive not found")?;
+    sync_drive(&client, &drive, &state.app_handle).await
+}
+
+/// Forgets the drive locally and leaves the channel. Files stay in the channel
+/// for the remaining members.
+#[tauri::command]
+pub(crate) async fn leave_shared_drive(
+    id: String,
+    state: State<'_, AppState>,
+) -> Result<(), AppError> {
+    let account = state.accounts.active();
+    let drive = account.shared.get(&id).ok_or("Shared drive not found")?;
+
+    if let Ok(client) = client_for(&account).await {
+        let chat = storage::resolve_peer(&client, &drive.info().peer).await?;
+        if let Some(channel) = chat.try_to_input_channel() {
+            if let Err(e) = rpc!(
+                "channels.leaveChannel",
+                client.invoke(&tl::functions::channels::LeaveChannel {
+                    channel: channel.clone()
+                })
+            ) {
+                eprintln!("Failed to leave channel: {}", e);
+            }
+        }
+    }
+
+    account.shared.remove(&id);
+    println!("Left shared drive {}", id);
+    Ok(())
+}
@@ -0,0 +1,361 @@
+use grammers_client::types::{Message, PackedChat};
+use grammers_client::Client;
+use grammers_session::PackedType;
+use grammers_tl_types as tl;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use tauri::{Emitter, State, Window};
+
+use crate::db::{FileMetadata, Replica, StoragePeer};
+use crate::error::AppError;
+use crate::gateway::{self, rpc};
+use crate::AppState;
+
+const STORAGE_FILENAME: &str = "storage.json";
+const STORAGE_CHANNEL_TITLE: &str = "Paperfold Storage";
+
+const DEFAULT_INBOX_FOLDER: &str = "Inbox";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageConfig {
+    pub target: StoragePeer,
+    #[serde(default)]
+    pub channel_title: Option<String>,
+    // The private storage channel once created, kept while Saved Messages is the target
+    #[serde(default)]
+    pub channel: Option<StoragePeer>,
+    // Files that show up in the storage peer from other clients land here
+    #[serde(default = "default_true")]
+    pub auto_ingest: bool,
+    #[serde(default = "default_inbox_folder")]
+    pub inbox_folder: String,
+    // 2 keeps a second copy of every personal upload in `replica_target`
+    #[serde(default = "default_replication_factor")]
+    pub replication_factor: u8,
+    #[serde(default)]
+    pub replica_target: Option<StoragePeer>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_inbox_folder() -> String {
+    DEFAULT_INBOX_FOLDER.to_string()
+}
+
+fn default_replication_factor() -> u8 {
+    1
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            target: StoragePeer::SavedMessages,
+            channel_title: None,
+            channel: None,
+            auto_ingest: true,
+            inbox_folder: default_inbox_folder(),
+            replication_factor: default_replication_factor(),
+            replica_target: None,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(STORAGE_FILENAME);
+        if !path.exists() {
+            return StorageConfig::default();
+        }
+        let mut config: StorageConfig = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_default(),
+            Err(_) => StorageConfig::default(),
+        };
+        // Files from before the channel had its own field
+        if config.channel.is_none() && matches!(config.target, StoragePeer::Channel { .. }) {
+            config.channel = Some(config.target.clone());
+        }
+        config
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), AppError> {
+        let file = File::create(dir.join(STORAGE_FILENAME)).map_err(AppError::storage)?;
+        serde_json::to_writer(BufWriter::new(file), self).map_err(AppError::storage)
+    }
+}
+
+/// Turns a stored peer into something grammers can address.
+pub async fn resolve_peer(client: &Client, peer: &StoragePeer) -> Result<PackedChat, AppError> {
+    match peer {
+        StoragePeer::SavedMessages => Ok(gateway::global().get_me(client).await?.pack()),
+        StoragePeer::Channel { packed, .. } => {
+            PackedChat::from_hex(packed).map_err(AppError::storage)
+        }
+    }
+}
+
+pub async fn fetch_messages(
+    client: &Client,
+    peer: &StoragePeer,
+    message_ids: &[i32],
+) -> Result<Vec<Option<Message>>, AppError> {
+    let chat = resolve_peer(client, peer).await?;
+    Ok(rpc!(
+        "messages.getMessages",
+        client.get_messages_by_id(chat, message_ids)
+    )?)
+}
+
+/// Packs a channel or supergroup the current account can see into a storage peer.
+pub fn channel_peer(channel: &tl::types::Channel) -> StoragePeer {
+    let ty = if channel.megagroup {
+        PackedType::Megagroup
+    } else {
+        PackedType::Broadcast
+    };
+    StoragePeer::Channel {
+        id: channel.id,
+        packed: PackedChat {
+            ty,
+            id: channel.id,
+            access_hash: channel.access_hash,
+        }
+        .to_hex(),
+    }
+}
+
+pub(crate) async fn create_storage_channel(
+    client: &Client,
+    title: &str,
+) -> Result<StoragePeer, AppError> {
+    let updates = rpc!(
+        "channels.createChannel",
+        client.invoke(&tl::functions::channels::CreateChannel {
+            broadcast: true,
+            megagroup: false,
+            for_import: false,
+            forum: false,
+            title: title.to_string(),
+            about: "Files stored by Paperfold. Do not post here manually.".to_string(),
+            geo_point: None,
+            address: None,
+            ttl_period: None,
+        })
+    )?;
+
+    let chats = match updates {
+        tl::enums::Updates::Updates(u) => u.chats,
+        tl::enums::Updates::Combined(u) => u.chats,
+        _ => Vec::new(),
+    };
+
+    chats
+        .into_iter()
+        .find_map(|c| match c {
+            tl::enums::Chat::Channel(channel) => Some(channel_peer(&channel)),
+            _ => None,
+        })
+        .ok_or_else(|| AppError::Telegram("Channel was created but not returned".to_string()))
+}
+
+#[tauri::command]
+pub(crate) async fn get_storage_target(
+    state: State<'_, AppState>,
+) -> Result<StorageConfig, AppError> {
+    let account = state.accounts.active();
+    let config = account.storage.lock().unwrap().clone();
+    Ok(config)
+}
+
+/// `kind` is either "saved_messages" or "channel". Switching to a channel
+/// reuses the one created earlier, or creates a private one on first use.
+#[tauri::command]
+pub(crate) async fn set_storage_target(
+    kind: String,
+    state: State<'_, AppState>,
+) -> Result<StorageConfig, AppError> {
+    let account = state.accounts.active();
+    println!("Setting storage target: {}", kind);
+
+    let mut config = account.storage.lock().unwrap().clone();
+    match kind.as_str() {
+        "saved_messages" => config.target = StoragePeer::SavedMessages,
+        "channel" => {
+            let channel = match config.channel.clone() {
+                Some(channel) => channel,
+                None => {
+                    let client_guard = account.client.lock().await;
+                    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+                    drop(client_guard);
+
+                    config.channel_title = Some(STORAGE_CHANNEL_TITLE.to_string());
+                    create_storage_channel(&client, STORAGE_CHANNEL_TITLE).await?
+                }
+            };
+            config.channel = Some(channel.clone());
+            config.target = channel;
+        }
+        _ => return Err(format!("Unknown storage target: {}", kind).into()),
+    }
+
+    config.save(&account.dir)?;
+    *account.storage.lock().unwrap() = config.clone();
+    Ok(config)
+}
+
+#[tauri::command]
+pub(crate) async fn set_inbox_settings(
+    auto_ingest: bool,
+    inbox_folder: String,
+    state: State<'_, AppState>,
+) -> Result<StorageConfig, AppError> {
+    let account = state.accounts.active();
+    let inbox_folder = inbox_folder.trim();
+    if inbox_folder.is_empty() {
+        return Err("Inbox folder name cannot be empty".into());
+    }
+
+    let mut config = account.storage.lock().unwrap();
+    let mut updated = config.clone();
+    updated.auto_ingest = auto_ingest;
+    updated.inbox_folder = inbox_folder.to_string();
+    updated.save(&account.dir)?;
+    *config = updated.clone();
+    Ok(updated)
+}
+
+/// Forwards every file that does not live in the current storage target into it
+/// and repoints the metadata. Originals are only deleted once their copy exists.
+#[tauri::command]
+pub(crate) async fn migrate_to_storage_target(
+    delete_originals: bool,
+    state: State<'_, AppState>,
+    window: Window,
+) -> Result<String, AppError> {
+    let account = state.accounts.active();
+    let client_guard = account.client.lock().await;
+    let client = client_guard.as_ref().ok_or(AppError::NotLoggedIn)?.clone();
+    drop(client_guard);
+
+    let config = account.storage.lock().unwrap().clone();
+    let target = config.target.clone();
+    let target_chat = resolve_peer(&client, &target).await?;
+    // An original in the replica peer stays as the file's copy there
+    let replica_peer = config
+        .replica_target
+        .clone()
+        .filter(|_| config.replication_factor > 1);
+
+    let db = &account.personal_db;
+    let mut pending = Vec::new();
+    let mut promoted = 0;
+    for file in db.get_all_files() {
+        if file.peer == target || file.message_id == 0 || db.is_pending_deletion(&file.id) {
+            continue;
+        }
+        // A replica already in the target just swaps places with the primary
+        match file.replicas.iter().position(|r| r.peer == target) {
+            Some(i) => {
+                let mut replicas = file.replicas.clone();
+                let copy = std::mem::replace(
+                    &mut replicas[i],
+                    Replica {
+                        peer: file.peer.clone(),
+                        message_id: file.message_id,
+                    },
+                );
+                db.relocate_file(&file.id, copy.peer, copy.message_id)?;
+                db.set_replicas(&file.id, replicas)?;
+                promoted += 1;
+            }
+            None => pending.push(file),
+        }
+    }
+
+    if pending.is_empty() {
+        return Ok(match promoted {
+            0 => "All files are already in the storage target.".to_string(),
+            n => format!("Migration complete. {} files already had a copy there.", n),
+        });
+    }
+
+    println!("Migrating {} files to {:?}", pending.len(), target);
+    let total = pending.len();
+    let mut migrated = 0;
+    let mut failed = 0;
+
+    let mut by_peer: HashMap<StoragePeer, Vec<FileMetadata>> = HashMap::new();
+    for f in pending {
+        by_peer.entry(f.peer.clone()).or_default().push(f);
+    }
+
+    for (source, files) in by_peer {
+        let source_chat = resolve_peer(&client, &source).await?;
+
+        // forward_messages accepts up to 100 ids per request
+        for chunk in files.chunks(100) {
+            let ids: Vec<i32> = chunk.iter().map(|f| f.message_id).collect();
+            let forwarded = rpc!(
+                "messages.forwardMessages",
+                client.forward_messages(target_chat, &ids, source_chat)
+            )?;
+
+            let mut moved_ids = Vec::new();
+            for (file, new_msg) in chunk.iter().zip(forwarded.iter()) {
+                match new_msg {
+                    Some(msg) => {
+                        db.relocate_file(&file.id, target.clone(), msg.id())?;
+                        if replica_peer.as_ref() == Some(&source)
+                            && !file.replicas.iter().any(|r| r.peer == source)
+                        {
+                            let mut replicas = file.replicas.clone();
+                            replicas.push(Replica {
+                                peer: source.clone(),
+                                message_id: file.message_id,
+                            });
+                            db.set_replicas(&file.id, replicas)?;
+                        } else {
+                            moved_ids.push(file.message_id);
+                        }
+                        migrated += 1;
+                    }
+                    None => {
+                        eprintln!(
+                            "Could not forward message {} ({})",
+                            file.message_id, file.name
+                        );
+                        failed += 1;
+                    }
+                }
+            }
+
+            if delete_originals && !moved_ids.is_empty() {
+                if let Err(e) = rpc!(
+                    "messages.deleteMessages",
+                    client.delete_messages(source_chat, &moved_ids)
+                ) {
+                    eprintln!("Failed to delete migrated originals: {}", e);
+                }
+            }
+
+            let _ = window.emit(
+                "migration-progress",
+                serde_json::json!({
+                    "migrated": migrated,
+                    "failed": failed,
+                    "total": total,
+                }),
+            );
+        }
+    }
+
+    Ok(format!(
+        "Migration complete. Moved {} files, {} failed.",
+        migrated + promoted,
+        failed
+    ))
+}
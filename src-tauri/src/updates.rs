@@ -0,0 +1,171 @@
+use grammers_client::types::Message;
+use grammers_client::{Client, Update};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::accounts::Account;
+use crate::db::{FileMetadata, StoragePeer};
+use crate::error::AppError;
+use crate::gateway;
+use crate::recovery::CAPTION_TAG;
+use crate::{importer, missing, AppState, BACKUP_TAG};
+
+// How long to wait before treating a new message as foreign. Our own uploads and
+// migrations register their message ids within this window.
+const INGEST_DELAY_SECS: u64 = 5;
+// The listener wakes up this often to notice account switches and logins
+const POLL_SECS: u64 = 5;
+
+/// Single consumer of the grammers update stream for the active account.
+pub fn spawn_update_listener(app_handle: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut own_ids: HashMap<String, i64> = HashMap::new();
+
+        loop {
+            let state = app_handle.state::<AppState>();
+            let account = state.accounts.active();
+            let client = match account.client.lock().await.as_ref() {
+                // Updates wait in grammers until the metadata can be written
+                Some(_) if state.encryption.is_locked() => {
+                    tokio::time::sleep(Duration::from_secs(POLL_SECS)).await;
+                    continue;
+                }
+                Some(client) => client.clone(),
+                None => {
+                    tokio::time::sleep(Duration::from_secs(POLL_SECS)).await;
+                    continue;
+                }
+            };
+
+            let update =
+                match tokio::time::timeout(Duration::from_secs(POLL_SECS), client.next_update())
+                    .await
+                {
+                    Err(_) => continue,
+                    Ok(Err(e)) => {
+                        eprintln!("Update stream error: {}", e);
+                        // Likely a dead connection; let the supervisor look now
+                        state.connection.wake();
+                        tokio::time::sleep(Duration::from_secs(POLL_SECS)).await;
+                        continue;
+                    }
+                    Ok(Ok(update)) => update,
+                };
+
+            match update {
+                Update::NewMessage(msg) => {
+                    let me_id = match own_ids.get(&account.id) {
+                        Some(id) => *id,
+                        None => match gateway::global().get_me(&client).await {
+                            Ok(me) => {
+                                own_ids.insert(account.id.clone(), me.id());
+                                me.id()
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to get own user: {}", e);
+                                continue;
+                            }
+                        },
+                    };
+                    handle_new_message(&app_handle, &account, &client, msg, me_id);
+                }
+                Update::MessageDeleted(deletion) => {
+                    missing::handle_deletion(&app_handle, &account, &deletion);
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+fn handle_new_message(
+    app_handle: &AppHandle,
+    account: &Arc<Account>,
+    client: &Client,
+    msg: Message,
+    me_id: i64,
+) {
+    let config = account.storage.lock().unwrap().clone();
+    if !config.auto_ingest {
+        return;
+    }
+    let in_storage = match &config.target {
+        StoragePeer::SavedMessages => msg.chat().id() == me_id,
+        StoragePeer::Channel { id, .. } => msg.chat().id() == *id,
+    };
+    if !in_storage || importer::describe_media(&msg).is_none() {
+        return;
+    }
+    // Captioned uploads and metadata backups come from Paperfold itself
+    if msg.text().starts_with(CAPTION_TAG) || msg.text().contains(BACKUP_TAG) {
+        return;
+    }
+
+    let app_handle = app_handle.clone();
+    let account = account.clone();
+    let client = client.clone();
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(INGEST_DELAY_SECS)).await;
+        if let Err(e) = ingest(
+            &app_handle,
+            &account,
+            &client,
+            &msg,
+            config.target,
+            &config.inbox_folder,
+        )
+        .await
+        {
+            eprintln!("Inbox ingest failed: {}", e);
+        }
+    });
+}
+
+async fn ingest(
+    app_handle: &AppHandle,
+    account: &Account,
+    client: &Client,
+    msg: &Message,
+    peer: StoragePeer,
+    inbox_folder: &str,
+) -> Result<(), AppError> {
+    let db = &account.personal_db;
+    let tracked = db.get_all_files().iter().any(|f| {
+        f.locations()
+            .iter()
+            .any(|l| l.peer == peer && l.message_id == msg.id())
+    });
+    if tracked {
+        return Ok(());
+    }
+    let (name, size, mime_type) = match importer::describe_media(msg) {
+        Some(described) => described,
+        None => return Ok(()),
+    };
+
+    println!("Ingesting {} into {}", name, inbox_folder);
+    let folder_id = db.ensure_folder_path(&[inbox_folder.to_string()])?;
+    let thumbnail = crate::extract_thumbnail_base64(client, msg).await;
+    let file = db.insert_file(FileMetadata {
+        id: uuid::Uuid::new_v4().to_string(),
+        folder_id,
+        name,
+        size,
+        mime_type,
+        message_id: msg.id(),
+        created_at: msg.date().timestamp(),
+        trashed: false,
+        trashed_at: None,
+        is_starred: false,
+        thumbnail,
+        peer,
+        hash: None,
+        missing: false,
+        last_modified: 0,
+        replicas: Vec::new(),
+    })?;
+    let _ = app_handle.emit("inbox-file-added", &file);
+    Ok(())
+}
@@ -1,37 +1,59 @@
-import { useState, useEffect } from 'react';
+import { useState, useEffect, useCallback } from 'react';
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import './index.css';
 import Auth from './components/Auth';
 import Dashboard from './components/Dashboard';
+import LockScreen, { LockKind } from './components/LockScreen';
 
 function App() {
   const [isAuthenticated, setIsAuthenticated] = useState(false);
+  const [lock, setLock] = useState<LockKind | null>(null);
   const [loading, setLoading] = useState(true);
 
-  useEffect(() => {
-    async function checkAuth() {
-      try {
-        const isAuth = await invoke<boolean>('check_auth');
-        if (isAuth) {
-          setIsAuthenticated(true);
-        }
-      } catch (e) {
-        console.error("Auth check failed", e);
-      } finally {
-        setLoading(false);
+  // The passcode comes first: nothing else answers while the app is locked
+  const checkAccess = useCallback(async () => {
+    try {
+      const appLock = await invoke<{ locked: boolean }>('get_app_lock_status');
+      if (appLock.locked) {
+        setLock('app');
+        return;
+      }
+      const encryption = await invoke<{ enabled: boolean, unlocked: boolean }>('get_encryption_status');
+      if (encryption.enabled && !encryption.unlocked) {
+        setLock('metadata');
+        return;
       }
+      setLock(null);
+      const isAuth = await invoke<boolean>('check_auth');
+      setIsAuthenticated(isAuth);
+    } catch (e) {
+      console.error("Auth check failed", e);
+    } finally {
+      setLoading(false);
     }
-    checkAuth();
   }, []);
 
+  useEffect(() => {
+    checkAccess();
+    const unlisten = listen('app-locked', () => setLock('app'));
+    return () => {
+      unlisten.then(f => f());
+    };
+  }, [checkAccess]);
+
   if (loading) {
     return <div className="min-h-screen flex items-center justify-center bg-background text-foreground">Loading...</div>;
   }
 
+  if (lock) {
+    return <LockScreen kind={lock} onUnlock={checkAccess} />;
+  }
+
   return (
     <div className="min-h-screen bg-background text-foreground font-sans">
       {isAuthenticated ? (
-        <Dashboard onLogout={() => setIsAuthenticated(false)} />
+        <Dashboard onLogout={() => setIsAuthenticated(false)} onLocked={checkAccess} />
       ) : (
         <Auth onLogin={() => setIsAuthenticated(true)} />
       )}
@@ -2,6 +2,7 @@ import { useState, useMemo, useEffect } from 'react';
 import { motion, AnimatePresence, useMotionValue, useTransform } from 'framer-motion';
 import { invoke } from '@tauri-apps/api/core';
 import { ChevronDown, ArrowRight, Loader2, Search } from 'lucide-react';
+import { errorMessage, isAppError } from '../types';
 
 interface AuthProps {
     onLogin: () => void;
@@ -277,7 +278,7 @@ export default function Auth({ onLogin }: AuthProps) {
             await invoke('login_start', { phone: fullPhone });
             setStep('code');
         } catch (err: any) {
-            setError(typeof err === 'string' ? err : "Failed to send code");
+            setError(errorMessage(err, "Failed to send code"));
         } finally {
             setLoading(false);
         }
@@ -291,11 +292,10 @@ export default function Auth({ onLogin }: AuthProps) {
             await invoke('login_complete', { code, password: null });
             onLogin();
         } catch (err: any) {
-            const msg = typeof err === 'string' ? err : "Login failed";
-            if (msg.includes("PASSWORD_REQUIRED")) {
+            if (isAppError(err) && err.code === 'PASSWORD_REQUIRED') {
                 setStep('password');
             } else {
-                setError(msg);
+                setError(errorMessage(err, "Login failed"));
             }
         } finally {
             setLoading(false);
@@ -310,7 +310,7 @@ export default function Auth({ onLogin }: AuthProps) {
             await invoke('login_complete', { code, password });
             onLogin();
         } catch (err: any) {
-            setError(typeof err === 'string' ? err : "Password incorrect");
+            setError(errorMessage(err, "Password incorrect"));
         } finally {
             setLoading(false);
         }
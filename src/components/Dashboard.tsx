@@ -36,7 +36,7 @@ import {
     Grid
 } from 'lucide-react';
 import FileCard, { FileItem } from './FileCard';
-import { invoke } from '@tauri-apps/api/core';
+import { invoke as invokeCommand } from '@tauri-apps/api/core';
 import { convertFileSrc } from '@tauri-apps/api/core';
 import { listen } from '@tauri-apps/api/event';
 import { open, save } from '@tauri-apps/plugin-dialog';
@@ -45,7 +45,7 @@ import { check } from '@tauri-apps/plugin-updater';
 import { relaunch } from '@tauri-apps/plugin-process';
 // We will add opener import after we verify package.json
 // For now, let's just wait.
-import { FileMetadata, Folder } from '../types';
+import { FileMetadata, Folder, ConnectionState, HealthReport, errorMessage, isAppError } from '../types';
 import { motion, AnimatePresence } from 'framer-motion';
 
 interface UserProfile {
@@ -56,7 +56,19 @@ interface UserProfile {
     phone?: string;
 }
 
-export default function Dashboard({ onLogout }: { onLogout: () => void }) {
+export default function Dashboard({ onLogout, onLocked }: { onLogout: () => void, onLocked: () => void }) {
+    // Any command can come back LOCKED after an auto-lock; send the user to the lock screen
+    const invoke = async <T,>(cmd: string, args?: Record<string, unknown>): Promise<T> => {
+        try {
+            return await invokeCommand<T>(cmd, args);
+        } catch (e) {
+            if (isAppError(e) && e.code === 'LOCKED') {
+                onLocked();
+            }
+            throw e;
+        }
+    };
+
     const [view, setView] = useState<'grid' | 'list'>('grid');
     const [currentFolder, setCurrentFolder] = useState<string | null>(null);
     const currentFolderRef = useRef<string | null>(null); // Ref to track currentFolder for listeners
@@ -95,6 +107,21 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             unlistenPromise.then(unlisten => unlisten());
         };
     }, []);
+
+    // Long jobs pause instead of failing when Telegram asks us to slow down
+    useEffect(() => {
+        let timer: ReturnType<typeof setTimeout> | undefined;
+        const unlistenPromise = listen<{ method: string, retry_after_secs: number }>('rate-limited', (event) => {
+            const secs = event.payload.retry_after_secs;
+            setUpdateStatus(`Telegram rate limit, resuming in ${secs}s...`);
+            clearTimeout(timer);
+            timer = setTimeout(() => setUpdateStatus(null), (secs + 1) * 1000);
+        });
+        return () => {
+            clearTimeout(timer);
+            unlistenPromise.then(unlisten => unlisten());
+        };
+    }, []);
     const [newFolderName, setNewFolderName] = useState("");
     const [itemToDelete, setItemToDelete] = useState<{ id: string, isFolder: boolean, name: string, deleteType: 'soft' | 'hard', batchIds?: string[] } | null>(null);
     const [isEmptyTrashOpen, setIsEmptyTrashOpen] = useState(false);
@@ -200,7 +227,51 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
         { name: 'Waves', value: 'repeating-radial-gradient(circle at 0 0, transparent 0, rgba(255,255,255,0.1) 10px), repeating-linear-gradient(rgba(255,255,255,0.1), rgba(255,255,255,0.1))' }
     ];
 
-    const [uploadQueue, setUploadQueue] = useState<{ path: string, name: string, status: 'pending' | 'uploading' | 'completed' | 'error', progress: number, targetFolderId?: string | null }[]>([]);
+    const [uploadQueue, setUploadQueue] = useState<{ path: string, name: string, status: 'pending' | 'uploading' | 'queued' | 'completed' | 'error', progress: number, targetFolderId?: string | null }[]>([]);
+    const [connection, setConnection] = useState<ConnectionState>({ state: 'online' });
+
+    // Offline the drive stays browsable; uploads wait in the backend until reconnect
+    useEffect(() => {
+        invoke<ConnectionState>('get_connection_state').then(setConnection).catch(console.error);
+        const unlistenState = listen<ConnectionState>('connection-state', (event) => {
+            setConnection(event.payload);
+        });
+        const unlistenQueued = listen<{ id: string, path: string, error: string | null }>('queued-upload-done', (event) => {
+            const { path, error } = event.payload;
+            setUploadQueue(prev => prev.map(q => q.path === path && q.status === 'queued'
+                ? { ...q, status: error ? 'error' : 'completed', progress: error ? 0 : 100 }
+                : q));
+            if (error) alert(`Failed to upload ${path}: ${error}`);
+            setRefresh(prev => prev + 1);
+        });
+        return () => {
+            unlistenState.then(unlisten => unlisten());
+            unlistenQueued.then(unlisten => unlisten());
+        };
+    }, []);
+
+    // Files the last integrity scrub could not vouch for, by id
+    const [healthIssues, setHealthIssues] = useState<Record<string, string>>({});
+    const [healthBannerDismissed, setHealthBannerDismissed] = useState(false);
+
+    useEffect(() => {
+        const applyReport = (report: HealthReport | null) => {
+            const issues: Record<string, string> = {};
+            report?.files.forEach(f => {
+                if (f.status !== 'healthy') issues[f.file_id] = f.detail || f.status;
+            });
+            setHealthIssues(issues);
+            setHealthBannerDismissed(false);
+        };
+        invoke<HealthReport | null>('get_health_report').then(applyReport).catch(console.error);
+        const unlistenReport = listen<HealthReport>('health-report', (event) => {
+            applyReport(event.payload);
+            setRefresh(prev => prev + 1);
+        });
+        return () => {
+            unlistenReport.then(unlisten => unlisten());
+        };
+    }, []);
 
     const [folderStats, setFolderStats] = useState<Record<string, { size: number, count: number }>>({});
     const [isRestoreConfirmOpen, setIsRestoreConfirmOpen] = useState(false);
@@ -260,9 +331,13 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                 setUploadQueue(prev => prev.map((q, i) => i === pendingItemIndex ? { ...q, status: 'completed', progress: 100 } : q));
                 setRefresh(prev => prev + 1);
             } catch (e) {
+                if (isAppError(e) && e.code === 'OFFLINE') {
+                    setUploadQueue(prev => prev.map((q, i) => i === pendingItemIndex ? { ...q, status: 'queued', progress: 0 } : q));
+                    return;
+                }
                 console.error("Upload failed for", item.path, e);
                 setUploadQueue(prev => prev.map((q, i) => i === pendingItemIndex ? { ...q, status: 'error', progress: 0 } : q));
-                alert(`Failed to upload ${item.name}: ${e}`);
+                alert(`Failed to upload ${item.name}: ${errorMessage(e)}`);
             }
         };
 
@@ -419,12 +494,12 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
     const getSortedItems = () => {
         // Upload queue items are always first and unsorted (or sorted by add time implicit)
         const uploadItems: FileItem[] = uploadQueue
-            .filter(q => (q.status === 'pending' || q.status === 'uploading') && q.targetFolderId === currentFolder) // Filter by current folder
+            .filter(q => (q.status === 'pending' || q.status === 'uploading' || q.status === 'queued') && q.targetFolderId === currentFolder) // Filter by current folder
             .map((q, i) => ({
                 id: `upload-${i}`,
                 name: q.name,
                 type: 'file' as const,
-                size: q.status === 'uploading' ? 'Uploading...' : 'Queued',
+                size: q.status === 'uploading' ? 'Uploading...' : q.status === 'queued' ? 'Waiting for connection' : 'Queued',
                 mimeType: 'application/octet-stream' // placeholder
             }));
 
@@ -729,7 +804,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             setRefresh(prev => prev + 1);
         } catch (err) {
             console.error("Create folder failed", err);
-            alert("Failed to create folder: " + err);
+            alert("Failed to create folder: " + errorMessage(err));
         } finally {
             setIsLoading(false);
         }
@@ -760,7 +835,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             setSelectedItemIds(new Set());
         } catch (e) {
             console.error("Restore failed", e);
-            alert("Restore failed: " + e);
+            alert("Restore failed: " + errorMessage(e));
         } finally {
             setIsLoading(false);
         }
@@ -774,7 +849,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             setRefresh(prev => prev + 1);
         } catch (e) {
             console.error("Empty trash failed", e);
-            alert("Empty trash failed: " + e);
+            alert("Empty trash failed: " + errorMessage(e));
         } finally {
             setIsLoading(false);
         }
@@ -815,7 +890,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             }
         } catch (e) {
             console.error("Download failed", e);
-            alert("Download failed: " + e);
+            alert("Download failed: " + errorMessage(e));
         }
     };
 
@@ -855,7 +930,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             setSelectedItemIds(new Set()); // Clear selection after delete
         } catch (e) {
             console.error("Delete failed", e);
-            alert("Delete failed: " + e);
+            alert("Delete failed: " + errorMessage(e));
         } finally {
             setIsLoading(false);
         }
@@ -866,8 +941,9 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             await invoke('logout');
             onLogout();
         } catch (e) {
+            // Still logged in on Telegram's side, so stay logged in here too
             console.error("Logout failed", e);
-            onLogout();
+            alert("Logout failed: " + errorMessage(e));
         }
     };
 
@@ -929,7 +1005,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             const res = await invoke<string>('backup_metadata');
             alert(res);
         } catch (e) {
-            alert("Backup failed: " + e);
+            alert("Backup failed: " + errorMessage(e));
         }
     };
 
@@ -944,7 +1020,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             alert(res);
             setRefresh(prev => prev + 1); // Refresh UI to show restored data
         } catch (e) {
-            alert("Restore failed: " + e);
+            alert("Restore failed: " + errorMessage(e));
         }
     };
 
@@ -1011,6 +1087,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                                 item={file}
                                 isSelected={isSelected}
                                 downloadProgress={downloadProgress[file.id]}
+                                healthIssue={healthIssues[file.id]}
                                 onClick={(e) => handleFileClick(e, file)}
                                 onContextMenu={(e, item) => {
                                     e.preventDefault();
@@ -1230,7 +1307,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             setIsCustomizeOpen(false);
         } catch (e) {
             console.error("Customize failed", e);
-            alert("Failed to update folder: " + e);
+            alert("Failed to update folder: " + errorMessage(e));
         } finally {
             setIsLoading(false);
         }
@@ -1354,6 +1431,34 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                 )}
             </AnimatePresence>
 
+            {/* Offline banner */}
+            {connection.state !== 'online' && (
+                <div className="fixed top-4 left-1/2 -translate-x-1/2 z-[60] bg-[#0A0A0A] border border-amber-500/30 rounded-full py-2 px-5 shadow-2xl flex items-center gap-3 text-sm text-amber-100">
+                    <span>
+                        {connection.state === 'connecting'
+                            ? 'Reconnecting to Telegram...'
+                            : `Offline. Changes are saved locally${connection.retry_in_secs ? `, retrying in ${connection.retry_in_secs}s` : ''}.`}
+                    </span>
+                    {connection.state === 'offline' && (
+                        <button onClick={() => invoke('reconnect_now')} className="text-amber-400 hover:text-amber-300 font-medium">
+                            Retry now
+                        </button>
+                    )}
+                </div>
+            )}
+
+            {/* Integrity banner */}
+            {!healthBannerDismissed && Object.keys(healthIssues).length > 0 && (
+                <div className="fixed top-16 left-1/2 -translate-x-1/2 z-[60] bg-[#0A0A0A] border border-red-500/30 rounded-full py-2 px-5 shadow-2xl flex items-center gap-3 text-sm text-red-100">
+                    <span>
+                        {Object.keys(healthIssues).length} file{Object.keys(healthIssues).length === 1 ? '' : 's'} failed the last integrity check.
+                    </span>
+                    <button onClick={() => setHealthBannerDismissed(true)} className="text-red-400 hover:text-red-300 font-medium">
+                        Dismiss
+                    </button>
+                </div>
+            )}
+
             {/* Global Status Toast (Downloads, etc.) */}
             <AnimatePresence>
                 {updateStatus && (
@@ -2542,6 +2647,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                                             ) : (
                                                 <p className="text-[10px] text-gray-500 font-mono uppercase">
                                                     {item.status === 'pending' && "Queued"}
+                                                    {item.status === 'queued' && <span className="text-amber-400">Waiting for connection</span>}
                                                     {item.status === 'completed' && <span className="text-green-400">Success</span>}
                                                     {item.status === 'error' && <span className="text-red-400">Failed</span>}
                                                 </p>
@@ -2549,6 +2655,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                                         </div>
                                         <div>
                                             {item.status === 'pending' && <div className="w-3 h-3 rounded-full border-2 border-gray-600" />}
+                                            {item.status === 'queued' && <div className="w-3 h-3 rounded-full border-2 border-amber-500" />}
                                             {item.status === 'uploading' && <Loader2 className="w-3 h-3 text-cyan-400 animate-spin" />}
                                             {item.status === 'completed' && <CheckCircle className="w-3 h-3 text-green-400" />}
                                             {item.status === 'error' && <AlertCircle className="w-3 h-3 text-red-400" />}
@@ -2658,7 +2765,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                                                         setRefresh(r => r + 1);
                                                         setIsRenameOpen(false);
                                                     } catch (err) {
-                                                        alert("Failed to rename: " + err);
+                                                        alert("Failed to rename: " + errorMessage(err));
                                                     }
                                                 }
                                             }
@@ -2691,7 +2798,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                                                 setRefresh(r => r + 1);
                                                 setIsRenameOpen(false);
                                             } catch (err) {
-                                                alert("Failed to rename: " + err);
+                                                alert("Failed to rename: " + errorMessage(err));
                                             }
                                         }
                                     }}
@@ -1,7 +1,7 @@
 import {
     Folder, File as FileIcon, Star, FileText, Music, Video, Image as ImageIcon, Play,
     Briefcase, Heart, Code, Globe, User, Users, Shield, Lock, Settings, Archive,
-    Cloud, Database, Smartphone, Monitor, Book, Coffee, Gift, Tag, Flag
+    Cloud, Database, Smartphone, Monitor, Book, Coffee, Gift, Tag, Flag, AlertTriangle
 } from 'lucide-react';
 import { convertFileSrc } from '@tauri-apps/api/core';
 import { motion } from 'framer-motion';
@@ -36,6 +36,7 @@ interface FileCardProps {
     onContextMenu?: (e: React.MouseEvent, item: FileItem) => void;
     onClick?: (e: React.MouseEvent, item: FileItem) => void;
     downloadProgress?: number;
+    healthIssue?: string; // Set when the last integrity scrub flagged this file
 }
 
 export const FOLDER_ICONS: Record<string, any> = {
@@ -61,7 +62,7 @@ export const FOLDER_ICONS: Record<string, any> = {
     flag: Flag
 };
 
-export default function FileCard({ item, isSelected, onNavigate, onPreview, onContextMenu, onClick, downloadProgress }: FileCardProps) {
+export default function FileCard({ item, isSelected, onNavigate, onPreview, onContextMenu, onClick, downloadProgress, healthIssue }: FileCardProps) {
     const getFileIcon = () => {
         const name = item.name.toLowerCase();
         const mime = (item.mimeType || '').toLowerCase();
@@ -209,6 +210,13 @@ export default function FileCard({ item, isSelected, onNavigate, onPreview, onCo
                     </div>
                 )}
 
+                {/* Integrity Warning */}
+                {healthIssue && (
+                    <div className="absolute top-2 left-2 text-red-400" title={healthIssue}>
+                        <AlertTriangle className="w-4 h-4 drop-shadow-md" />
+                    </div>
+                )}
+
 
                 {/* Download Progress Overlay */}
                 {downloadProgress !== undefined && (
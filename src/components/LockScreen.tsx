@@ -0,0 +1,83 @@
+import { useState } from 'react';
+import { motion } from 'framer-motion';
+import { invoke } from '@tauri-apps/api/core';
+import { Lock, Loader2 } from 'lucide-react';
+import { errorMessage } from '../types';
+
+// 'app' is the passcode lock, 'metadata' the passphrase for encrypted metadata
+export type LockKind = 'app' | 'metadata';
+
+interface LockScreenProps {
+    kind: LockKind;
+    onUnlock: () => void;
+}
+
+export default function LockScreen({ kind, onUnlock }: LockScreenProps) {
+    const [secret, setSecret] = useState('');
+    const [loading, setLoading] = useState(false);
+    const [error, setError] = useState<string | null>(null);
+
+    const handleSubmit = async (e: React.FormEvent) => {
+        e.preventDefault();
+        setLoading(true);
+        setError(null);
+        try {
+            if (kind === 'app') {
+                await invoke('unlock_app', { passcode: secret });
+            } else {
+                await invoke('unlock', { passphrase: secret });
+            }
+            setSecret('');
+            onUnlock();
+        } catch (err) {
+            setError(errorMessage(err, kind === 'app' ? "Wrong passcode" : "Wrong passphrase"));
+        } finally {
+            setLoading(false);
+        }
+    };
+
+    return (
+        <div className="min-h-screen flex items-center justify-center bg-background text-foreground">
+            <motion.form
+                initial={{ opacity: 0, y: 10 }}
+                animate={{ opacity: 1, y: 0 }}
+                onSubmit={handleSubmit}
+                className="w-full max-w-sm p-8 space-y-6 bg-[#0A0A0A] border border-white/10 rounded-2xl shadow-2xl"
+            >
+                <div className="flex flex-col items-center gap-3 text-center">
+                    <div className="p-3 rounded-xl bg-cyan-500/10 text-cyan-400">
+                        <Lock className="w-6 h-6" />
+                    </div>
+                    <h2 className="font-bold text-lg text-white">
+                        {kind === 'app' ? "Paperfold is locked" : "Metadata is encrypted"}
+                    </h2>
+                    <p className="text-xs text-gray-500">
+                        {kind === 'app'
+                            ? "Enter your passcode to continue."
+                            : "Enter your passphrase to read your files."}
+                    </p>
+                </div>
+
+                <input
+                    type="password"
+                    value={secret}
+                    onChange={(e) => setSecret(e.target.value)}
+                    placeholder={kind === 'app' ? "Passcode" : "Passphrase"}
+                    className="w-full h-14 bg-white/5 border border-white/10 rounded-xl px-4 text-lg focus:outline-none focus:border-blue-500/50 focus:bg-white/10 transition-all placeholder:text-gray-600"
+                    autoFocus
+                />
+
+                <motion.button whileHover={{ scale: 1.02 }} whileTap={{ scale: 0.98 }} type="submit" disabled={loading || !secret} className="w-full h-14 bg-gradient-to-r from-blue-600 to-blue-500 text-white font-semibold rounded-xl transition-all flex items-center justify-center gap-2 disabled:opacity-50 disabled:cursor-not-allowed shadow-lg">
+                    {loading ? <Loader2 className="animate-spin w-5 h-5" /> : "Unlock"}
+                </motion.button>
+
+                {error && (
+                    <div className="p-4 bg-red-500/10 border border-red-500/20 rounded-xl flex items-center gap-3 text-red-200 text-sm">
+                        <div className="w-1.5 h-1.5 rounded-full bg-red-500 shadow-[0_0_10px_red]" />
+                        {error}
+                    </div>
+                )}
+            </motion.form>
+        </div>
+    );
+}
@@ -1,5 +1,8 @@
+import { useState, useEffect } from 'react';
 import { motion, AnimatePresence } from 'framer-motion';
-import { X, LogOut, Moon, Sun, User, Settings, Cloud } from 'lucide-react';
+import { invoke } from '@tauri-apps/api/core';
+import { X, LogOut, Moon, Sun, User, Settings, Cloud, Lock, KeyRound } from 'lucide-react';
+import { errorMessage } from '../types';
 
 
 interface SettingsModalProps {
@@ -12,6 +15,51 @@ interface SettingsModalProps {
 }
 
 export default function SettingsModal({ isOpen, onClose, onLogout, onDownloadAll, theme, toggleTheme }: SettingsModalProps) {
+    const [passcodeSet, setPasscodeSet] = useState(false);
+    const [encrypted, setEncrypted] = useState(false);
+    const [securityForm, setSecurityForm] = useState<'passcode' | 'encryption' | null>(null);
+    const [currentSecret, setCurrentSecret] = useState('');
+    const [newSecret, setNewSecret] = useState('');
+    const [securityError, setSecurityError] = useState<string | null>(null);
+
+    useEffect(() => {
+        if (!isOpen) return;
+        invoke<{ enabled: boolean }>('get_app_lock_status').then(s => setPasscodeSet(s.enabled)).catch(console.error);
+        invoke<{ enabled: boolean }>('get_encryption_status').then(s => setEncrypted(s.enabled)).catch(console.error);
+    }, [isOpen]);
+
+    const openSecurityForm = (form: 'passcode' | 'encryption') => {
+        setSecurityForm(securityForm === form ? null : form);
+        setCurrentSecret('');
+        setNewSecret('');
+        setSecurityError(null);
+    };
+
+    // Passcode: set, or change when one exists. Encryption: enable, or change the passphrase.
+    const handleSecuritySubmit = async (e: React.FormEvent) => {
+        e.preventDefault();
+        setSecurityError(null);
+        try {
+            if (securityForm === 'passcode') {
+                await invoke('set_passcode', {
+                    currentPasscode: passcodeSet ? currentSecret : null,
+                    newPasscode: newSecret,
+                });
+                setPasscodeSet(true);
+            } else if (encrypted) {
+                await invoke('change_passphrase', { currentPassphrase: currentSecret, newPassphrase: newSecret });
+            } else {
+                await invoke('enable_encryption', { passphrase: newSecret });
+                setEncrypted(true);
+            }
+            setSecurityForm(null);
+        } catch (err) {
+            setSecurityError(errorMessage(err));
+        }
+    };
+
+    const needsCurrent = securityForm === 'passcode' ? passcodeSet : encrypted;
+
     const handleLogout = async () => {
         // In future: call backend to clear session file
         // For now, just clear frontend state
@@ -98,6 +146,65 @@ export default function SettingsModal({ isOpen, onClose, onLogout, onDownloadAll
                                     <span className="text-sm font-medium text-gray-200 group-hover:text-white">Download Backup</span>
                                 </button>
 
+                                <button
+                                    onClick={() => openSecurityForm('passcode')}
+                                    className="w-full flex items-center justify-between p-3.5 hover:bg-white/5 rounded-xl transition-all border border-transparent hover:border-white/10 group"
+                                >
+                                    <div className="flex items-center gap-3">
+                                        <div className="p-2 rounded-lg bg-cyan-500/10 text-cyan-400">
+                                            <Lock className="w-4 h-4" />
+                                        </div>
+                                        <span className="text-sm font-medium text-gray-200 group-hover:text-white">App Passcode</span>
+                                    </div>
+                                    <span className="text-xs font-mono bg-white/5 px-2 py-1 rounded text-gray-400 border border-white/5">
+                                        {passcodeSet ? 'on' : 'off'}
+                                    </span>
+                                </button>
+
+                                <button
+                                    onClick={() => openSecurityForm('encryption')}
+                                    className="w-full flex items-center justify-between p-3.5 hover:bg-white/5 rounded-xl transition-all border border-transparent hover:border-white/10 group"
+                                >
+                                    <div className="flex items-center gap-3">
+                                        <div className="p-2 rounded-lg bg-yellow-500/10 text-yellow-400">
+                                            <KeyRound className="w-4 h-4" />
+                                        </div>
+                                        <span className="text-sm font-medium text-gray-200 group-hover:text-white">Encrypt Metadata</span>
+                                    </div>
+                                    <span className="text-xs font-mono bg-white/5 px-2 py-1 rounded text-gray-400 border border-white/5">
+                                        {encrypted ? 'on' : 'off'}
+                                    </span>
+                                </button>
+
+                                {securityForm && (
+                                    <form onSubmit={handleSecuritySubmit} className="space-y-2 p-3 bg-white/5 border border-white/5 rounded-xl">
+                                        {needsCurrent && (
+                                            <input
+                                                type="password"
+                                                value={currentSecret}
+                                                onChange={(e) => setCurrentSecret(e.target.value)}
+                                                placeholder={securityForm === 'passcode' ? "Current passcode" : "Current passphrase"}
+                                                className="w-full bg-white/5 border border-white/10 rounded-lg px-3 py-2 text-sm text-gray-200 placeholder:text-gray-600 focus:outline-none focus:bg-white/10"
+                                            />
+                                        )}
+                                        <input
+                                            type="password"
+                                            value={newSecret}
+                                            onChange={(e) => setNewSecret(e.target.value)}
+                                            placeholder={securityForm === 'passcode' ? "New passcode" : "New passphrase"}
+                                            className="w-full bg-white/5 border border-white/10 rounded-lg px-3 py-2 text-sm text-gray-200 placeholder:text-gray-600 focus:outline-none focus:bg-white/10"
+                                        />
+                                        {securityError && <p className="text-xs text-red-400">{securityError}</p>}
+                                        <button
+                                            type="submit"
+                                            disabled={!newSecret || (needsCurrent && !currentSecret)}
+                                            className="w-full py-2 bg-cyan-500/20 hover:bg-cyan-500/30 text-cyan-300 text-sm font-medium rounded-lg transition-colors disabled:opacity-50"
+                                        >
+                                            Save
+                                        </button>
+                                    </form>
+                                )}
+
                                 <button
                                     onClick={handleLogout}
                                     className="w-full flex items-center gap-3 p-3.5 hover:bg-red-500/10 text-red-400 hover:text-red-300 rounded-xl transition-colors border border-transparent hover:border-red-500/20 group"
@@ -30,3 +30,86 @@ export interface Folder {
     view_mode?: 'grid' | 'list';
     last_modified?: number;
 }
+
+// Every command rejects with this shape
+export interface AppError {
+    code: 'NOT_LOGGED_IN' | 'PASSWORD_REQUIRED' | 'FLOOD_WAIT' | 'TELEGRAM' | 'OFFLINE' | 'LOCKED'
+        | 'NOT_FOUND' | 'TRANSFER_FAILED' | 'STORAGE' | 'OTHER';
+    message: string;
+    retry_after_secs?: number;
+    file_id?: string;
+    part?: number;
+}
+
+export type ConnectionState =
+    | { state: 'online' }
+    | { state: 'connecting', attempt: number }
+    | { state: 'offline', attempt: number, retry_in_secs: number };
+
+// Mirrors settings.json; update_settings takes any subset of these
+export interface Settings {
+    version: number;
+    trash_retention_days: number;
+    upload_parallel_parts: number;
+    upload_chunk_kib: number;
+    big_file_threshold_mb: number;
+    zip_part_limit_mb: number;
+    backup_search_limit: number;
+    keep_backups: number;
+    message_batch_size: number;
+    trash_expiry_interval_minutes: number;
+    backup_interval_minutes: number;
+    gc_interval_minutes: number;
+    scrub_interval_minutes: number;
+    preview_cache_interval_minutes: number;
+    preview_cache_max_age_days: number;
+    preview_cache_max_mb: number;
+    scrub_hash_sample: number;
+    scrub_hash_max_mb: number;
+}
+
+export type MaintenanceJob = 'trash_expiry' | 'backup' | 'orphan_gc' | 'scrub' | 'preview_cache';
+
+export interface JobStatus {
+    job: MaintenanceJob;
+    interval_minutes: number;
+    running: boolean;
+    next_run: number;
+    last_run: number | null;
+    last_duration_ms: number | null;
+    last_result: string | null;
+    last_error: string | null;
+    errors: { at: number, message: string }[];
+}
+
+export type FileHealth = 'healthy' | 'missing' | 'size_mismatch' | 'mime_mismatch' | 'hash_mismatch' | 'unreadable';
+
+export interface FileCheck {
+    file_id: string;
+    name: string;
+    message_id: number;
+    status: FileHealth;
+    detail: string | null;
+    hash_verified: boolean;
+}
+
+export interface HealthReport {
+    started_at: number;
+    finished_at: number;
+    checked: number;
+    healthy: number;
+    failed: number;
+    hash_verified: number;
+    repair: { promoted: number, recreated: number, dropped: number, failed: number, lost: number, skipped: number, errors: string[] };
+    files: FileCheck[];
+}
+
+export function isAppError(err: unknown): err is AppError {
+    return typeof err === 'object' && err !== null && 'code' in err && 'message' in err;
+}
+
+export function errorMessage(err: unknown, fallback = 'Something went wrong'): string {
+    if (isAppError(err)) return err.message;
+    if (typeof err === 'string') return err;
+    return fallback;
+}